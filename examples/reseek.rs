@@ -1,16 +1,15 @@
+use std::io::{Seek, SeekFrom};
+
 use anyhow::Context;
 use tracing::info;
 use webm_iterable::{
     matroska_spec::{Master, MatroskaSpec},
     WebmIterator,
 };
-use webm_streamer::jrec::{
-    streaming::blocking::StdStreamingFile,
-    webm::stream_parser::{Read, StreamParser},
-};
+use webm_streamer::jrec::streaming::blocking::StdStreamingFile;
 
 pub fn main() -> anyhow::Result<()> {
-    let file = StdStreamingFile::open_read(".\\recordings\\21_11_41_54.webm".try_into()?)?;
+    let file = StdStreamingFile::open_read(".\\recordings\\21_11_41_54.webm".into())?;
 
     let mut tag_iterator = WebmIterator::new(
         file,
@@ -46,7 +45,10 @@ pub fn main() -> anyhow::Result<()> {
 
     let last_read_position = tag_iterator.last_emitted_tag_offset();
 
-    let mut tag_iterator = StreamParser::reseek(last_read_position, tag_iterator, Read)?;
+    let mut file = tag_iterator.into_inner();
+    file.reopen_read()?;
+    file.seek(SeekFrom::Start(last_read_position as u64))?;
+    let mut tag_iterator = WebmIterator::new(file, &[]);
     let next = tag_iterator.next();
 
     println!("After reseeking, next: {:?}", next);
@@ -1,13 +1,12 @@
+use std::io::{Seek, SeekFrom};
+
 use anyhow::Context;
 use tracing::info;
 use webm_iterable::{
     matroska_spec::{Master, MatroskaSpec},
     WebmIterator, WebmWriter,
 };
-use webm_streamer::jrec::{
-    streaming::{blocking::StdStreamingFile, std_stream::StdStream},
-    webm::stream_parser::{Read, StreamParser},
-};
+use webm_streamer::jrec::streaming::{blocking::StdStreamingFile, std_stream::StdStream};
 
 pub struct InMemoryReader {
     buffer: Vec<u8>,
@@ -70,7 +69,10 @@ pub fn main() -> anyhow::Result<()> {
 
     let last_read_position = tag_iterator.last_emitted_tag_offset();
 
-    let mut tag_iterator = StreamParser::reseek(last_read_position, tag_iterator, Read)?;
+    let mut file = tag_iterator.into_inner();
+    file.reopen_read()?;
+    file.seek(SeekFrom::Start(last_read_position as u64))?;
+    let mut tag_iterator = WebmIterator::new(file, &[]);
     let Some(Ok(next)) = tag_iterator.next() else {
         panic!("No next tag found")
     };
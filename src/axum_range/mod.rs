@@ -99,13 +99,24 @@ pub trait RangeBody: AsyncRead + AsyncSeekStart {
 pub struct Ranged<B: RangeBody + Send + 'static> {
     range: Option<Range>,
     body: B,
+    on_response: Option<Box<dyn FnOnce(StatusCode) + Send>>,
 }
 
 impl<B: RangeBody + Send + 'static> Ranged<B> {
     /// Construct a ranged response over any type implementing [`RangeBody`]
     /// and an optional [`Range`] header.
     pub fn new(range: Option<Range>, body: B) -> Self {
-        Ranged { range, body }
+        Ranged {
+            range,
+            body,
+            on_response: None,
+        }
+    }
+
+    /// Calls `hook` with the status of the response once it is built.
+    pub fn on_response(mut self, hook: impl FnOnce(StatusCode) + Send + 'static) -> Self {
+        self.on_response = Some(Box::new(hook));
+        self
     }
 
     /// Responds to the request, returning headers and body as
@@ -214,8 +225,13 @@ impl<B: RangeBody + Send + 'static> Ranged<B> {
 }
 
 impl<B: RangeBody + Send + 'static> IntoResponse for Ranged<B> {
-    fn into_response(self) -> Response {
-        self.try_respond().into_response()
+    fn into_response(mut self) -> Response {
+        let on_response = self.on_response.take();
+        let response = self.try_respond().into_response();
+        if let Some(on_response) = on_response {
+            on_response(response.status());
+        }
+        response
     }
 }
 
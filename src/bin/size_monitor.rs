@@ -1,12 +1,10 @@
 use chrono::{DateTime, Local};
 use clap::Parser;
 use std::fs;
-use std::fs::OpenOptions;
-use std::os::windows::fs::OpenOptionsExt;
 use std::path::PathBuf;
 use std::thread;
 use std::time::Duration;
-use winapi::um::winnt::{FILE_SHARE_READ, FILE_SHARE_WRITE};
+use webm_streamer::utils::file::std_open_read;
 
 #[derive(Parser)]
 #[command(
@@ -24,7 +22,7 @@ fn main() -> anyhow::Result<()> {
 
     // Infinite loop to refresh the listing every 0.1 seconds
     loop {
-        // Clear the console (this is platform-dependent; works on Windows)
+        // Clear the console with ANSI escapes
         print!("\x1B[2J\x1B[1;1H");
 
         // Table header
@@ -67,10 +65,7 @@ fn main() -> anyhow::Result<()> {
             };
 
             // Open the file with read and write share modes
-            let _file = OpenOptions::new()
-                .read(true)
-                .share_mode(FILE_SHARE_WRITE | FILE_SHARE_READ)
-                .open(&path)?;
+            let _file = std_open_read(&path)?;
 
             // Print file details in table format
             println!("{:<30} {:<10} {:<20}", file_name, file_size, formatted_time);
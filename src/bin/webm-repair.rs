@@ -0,0 +1,78 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use webm_streamer::jrec::webm::repair::{self, ValidationReport};
+
+#[derive(Parser)]
+#[command(
+    name = "webm-repair",
+    about = "Validates a recording and rewrites it without its broken tail"
+)]
+struct AppArgs {
+    /// Recording to check
+    recording: PathBuf,
+
+    /// Where to write the repaired recording, the recording is replaced when omitted
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Only report what is wrong, exit with status 1 when the recording is not valid
+    #[arg(long)]
+    check: bool,
+
+    /// Print the report as JSON, or the repair summary holding it when the recording is repaired
+    #[arg(long)]
+    json: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = AppArgs::parse();
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "warn".into()),
+        )
+        .init();
+
+    let report = repair::validate(&args.recording)?;
+    let repairing = !args.check && (!report.is_valid() || args.output.is_some());
+    if !args.json {
+        print_report(&report);
+    } else if !repairing {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    }
+
+    if args.check {
+        std::process::exit(if report.is_valid() { 0 } else { 1 });
+    }
+    if !repairing {
+        return Ok(());
+    }
+
+    let summary = repair::repair(&args.recording, args.output.as_deref())?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&summary)?);
+    } else {
+        println!(
+            "Kept {} of {} bytes: {} clusters, {} ms",
+            summary.kept_bytes,
+            summary.report.file_len,
+            summary.finalized.cluster_count,
+            summary.finalized.duration_ms
+        );
+    }
+
+    Ok(())
+}
+
+fn print_report(report: &ValidationReport) {
+    println!(
+        "{} bytes, {} complete clusters",
+        report.file_len, report.cluster_count
+    );
+    if report.issues.is_empty() {
+        println!("No issues found");
+    }
+    for issue in &report.issues {
+        println!("  {}", issue);
+    }
+}
@@ -0,0 +1,526 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::Context;
+use clap::Parser;
+
+#[derive(Debug, Parser)]
+#[command(
+    name = "webm-streamer",
+    about = "Records WebM push sessions and streams them back"
+)]
+pub struct Args {
+    /// TOML configuration file, values given on the command line or in the environment win over it
+    #[arg(short, long, env = "WEBM_STREAMER_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Address the HTTP server binds to
+    #[arg(short, long, env = "WEBM_STREAMER_LISTEN")]
+    pub listen: Option<SocketAddr>,
+
+    /// Directory recordings are written to and served from
+    #[arg(short, long, env = "WEBM_STREAMER_RECORDING_DIR")]
+    pub recording_dir: Option<PathBuf>,
+
+    /// `tracing_subscriber::EnvFilter` directives, `RUST_LOG` is used when not set anywhere
+    #[arg(long, env = "WEBM_STREAMER_LOG")]
+    pub log_filter: Option<String>,
+
+    /// Origins allowed by CORS, any origin is allowed when empty
+    #[arg(
+        long = "cors-origin",
+        env = "WEBM_STREAMER_CORS_ORIGINS",
+        value_delimiter = ','
+    )]
+    pub cors_origins: Option<Vec<String>>,
+
+    /// Maximum number of push sessions recorded at the same time
+    #[arg(long, env = "WEBM_STREAMER_MAX_ACTIVE_RECORDINGS")]
+    pub max_active_recordings: Option<usize>,
+
+    /// What happens to a live viewer that cannot keep up with the recording
+    #[arg(long, value_enum, env = "WEBM_STREAMER_SLOW_VIEWER_POLICY")]
+    pub slow_viewer_policy: Option<SlowViewerPolicy>,
+
+    /// Seconds given to in-flight recordings and connections to finish once a shutdown is requested
+    #[arg(long, env = "WEBM_STREAMER_SHUTDOWN_DEADLINE")]
+    pub shutdown_deadline_secs: Option<u64>,
+
+    /// Key access tokens are verified with, every route is open when there is none
+    #[arg(long, env = "WEBM_STREAMER_AUTH_KEY_FILE")]
+    pub auth_key_file: Option<PathBuf>,
+
+    /// PEM certificate chain, the server speaks HTTPS and WSS when it is set along with `--tls-key-file`
+    #[arg(long, env = "WEBM_STREAMER_TLS_CERT_FILE")]
+    pub tls_cert_file: Option<PathBuf>,
+
+    /// PEM private key of the certificate
+    #[arg(long, env = "WEBM_STREAMER_TLS_KEY_FILE")]
+    pub tls_key_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: SocketAddr,
+    pub recording_dir: PathBuf,
+    pub log_filter: Option<String>,
+    pub cors: CorsConfig,
+    pub limits: LimitsConfig,
+    pub live: LiveConfig,
+    pub retention: RetentionConfig,
+    pub rotation: RotationConfig,
+    pub auth: AuthConfig,
+    pub tls: TlsConfig,
+    pub events: EventsConfig,
+    pub webhooks: WebhooksConfig,
+    /// How long a push session waits for its client to reconnect and resume it before it is finalized
+    pub resume_window_secs: u64,
+    /// Recordings not finalized by then are recovered on the next start
+    pub shutdown_deadline_secs: u64,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+    pub max_age_secs: u64,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_active_recordings: Option<usize>,
+    /// How many entries `/list-recording` returns when the request does not ask for a page size
+    pub max_listed_recordings: usize,
+    /// The largest page size `/list-recording` accepts
+    pub max_list_page_size: usize,
+    /// Largest element of a push held in memory until it is complete, a Cluster most of the time.
+    /// Past it the rest of the push still goes to the file, but live viewers get nothing more
+    pub max_push_element_bytes: usize,
+    /// Largest group of pictures a live feed keeps for the viewers joining it, an encoder may go minutes
+    /// between keyframes. Past it new viewers wait for the next keyframe
+    pub max_group_of_pictures_bytes: usize,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LiveConfig {
+    /// Clusters the live feed keeps for viewers that are behind, and the chunks queued for the recording writer
+    /// before the push is slowed down
+    pub feed_capacity: usize,
+    /// Clusters buffered for each viewer before `slow_viewer_policy` applies
+    pub viewer_buffer: usize,
+    pub slow_viewer_policy: SlowViewerPolicy,
+}
+
+/// What the retention task prunes, oldest finished recordings first. Every limit is off when not set.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    pub max_age_secs: Option<u64>,
+    /// Size of all the recordings together
+    pub max_total_bytes: Option<u64>,
+    /// Recordings kept per push group, recordings pushed without a group are one group
+    pub max_recordings_per_group: Option<usize>,
+    /// Below this much free space recordings are pruned, and pushes refused if that is not enough
+    pub min_free_bytes: Option<u64>,
+    pub interval_secs: u64,
+    /// A recording fetched this recently, or whose last viewer session ended this recently, is not pruned.
+    /// Recordings with a viewer session open are never pruned
+    pub view_grace_secs: u64,
+}
+
+/// When a push session moves on to a new file, at the next keyframe cluster. Never when both are unset.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RotationConfig {
+    pub max_segment_secs: Option<u64>,
+    pub max_segment_bytes: Option<u64>,
+}
+
+/// How the signed access tokens of the `/jet/jrec` routes are checked.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// The shared secret for HS256, or the PEM public key for EdDSA. Authorization is off when not set
+    pub key_file: Option<PathBuf>,
+    pub algorithm: TokenAlgorithm,
+    /// Checked against the `iss` claim when set
+    pub issuer: Option<String>,
+    /// Checked against the `aud` claim when set
+    pub audience: Option<String>,
+    /// Clock skew tolerated on `exp` and `nbf`
+    pub leeway_secs: u64,
+}
+
+/// The lifecycle events sent to `/events` subscribers.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventsConfig {
+    /// Events kept for subscribers that are behind, slower ones are told they missed some
+    pub capacity: usize,
+    /// A `bytes_received` event is sent every time a push session gets this much more, never when 0
+    pub bytes_milestone: u64,
+}
+
+/// Where recording lifecycle notifications are POSTed, off without endpoints.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhooksConfig {
+    pub endpoints: Vec<WebhookEndpoint>,
+    /// Notifications not delivered yet, kept across restarts. `.webhooks` in the recording directory when not set
+    pub outbox_dir: Option<PathBuf>,
+    /// A notification still failing after this many attempts is given up, and left in the outbox as `.failed`
+    pub max_attempts: u32,
+    /// Wait before the second attempt, doubled on every failure after that
+    pub initial_backoff_secs: u64,
+    pub max_backoff_secs: u64,
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookEndpoint {
+    pub url: String,
+    /// Key of the HMAC-SHA256 of the body sent in `X-Webhook-Signature`, unsigned when not set
+    pub secret_file: Option<PathBuf>,
+}
+
+/// TLS termination, off unless both files are set. Both are reloaded whenever they change on disk.
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain, the end-entity certificate first
+    pub cert_file: Option<PathBuf>,
+    /// PEM private key, PKCS#8, PKCS#1 or SEC1
+    pub key_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize)]
+pub enum TokenAlgorithm {
+    #[default]
+    HS256,
+    EdDSA,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowViewerPolicy {
+    /// Drop clusters until the next keyframe once the viewer's buffer is full
+    #[default]
+    DropToKeyframe,
+    /// Close the viewer's stream once its buffer is full
+    Disconnect,
+    /// Wait for the viewer, it skips to the next keyframe only when it falls out of the live feed
+    Block,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        let recording_dir = dirs::home_dir()
+            .map(|home| home.join("code").join("webm-streamer").join("recordings"))
+            .unwrap_or_else(|| PathBuf::from("recordings"));
+
+        Self {
+            listen: SocketAddr::from(([127, 0, 0, 1], 3000)),
+            recording_dir,
+            log_filter: None,
+            cors: CorsConfig::default(),
+            limits: LimitsConfig::default(),
+            live: LiveConfig::default(),
+            retention: RetentionConfig::default(),
+            rotation: RotationConfig::default(),
+            auth: AuthConfig::default(),
+            tls: TlsConfig::default(),
+            events: EventsConfig::default(),
+            webhooks: WebhooksConfig::default(),
+            resume_window_secs: 30,
+            shutdown_deadline_secs: 30,
+        }
+    }
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: vec![],
+            max_age_secs: 86400,
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            key_file: None,
+            algorithm: TokenAlgorithm::default(),
+            issuer: None,
+            audience: None,
+            leeway_secs: 60,
+        }
+    }
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 256,
+            bytes_milestone: 16 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for WebhooksConfig {
+    fn default() -> Self {
+        Self {
+            endpoints: vec![],
+            outbox_dir: None,
+            max_attempts: 10,
+            initial_backoff_secs: 1,
+            max_backoff_secs: 600,
+            timeout_secs: 10,
+        }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_active_recordings: None,
+            max_listed_recordings: 10,
+            max_list_page_size: 500,
+            max_push_element_bytes: 64 * 1024 * 1024,
+            max_group_of_pictures_bytes: 32 * 1024 * 1024,
+        }
+    }
+}
+
+impl Default for LiveConfig {
+    fn default() -> Self {
+        Self {
+            feed_capacity: 256,
+            viewer_buffer: 16,
+            slow_viewer_policy: SlowViewerPolicy::default(),
+        }
+    }
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            max_age_secs: None,
+            max_total_bytes: None,
+            max_recordings_per_group: None,
+            min_free_bytes: None,
+            interval_secs: 300,
+            view_grace_secs: 600,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Reads the command line and environment, layered on top of the configuration file if one is given.
+    pub fn load() -> anyhow::Result<Self> {
+        Self::from_args(Args::parse())
+    }
+
+    pub fn from_args(args: Args) -> anyhow::Result<Self> {
+        let mut config = match args.config.as_deref() {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+
+        if let Some(listen) = args.listen {
+            config.listen = listen;
+        }
+
+        if let Some(recording_dir) = args.recording_dir {
+            config.recording_dir = recording_dir;
+        }
+
+        if let Some(log_filter) = args.log_filter {
+            config.log_filter = Some(log_filter);
+        }
+
+        if let Some(cors_origins) = args.cors_origins {
+            config.cors.allowed_origins = cors_origins;
+        }
+
+        if let Some(max_active_recordings) = args.max_active_recordings {
+            config.limits.max_active_recordings = Some(max_active_recordings);
+        }
+
+        if let Some(slow_viewer_policy) = args.slow_viewer_policy {
+            config.live.slow_viewer_policy = slow_viewer_policy;
+        }
+
+        if let Some(shutdown_deadline_secs) = args.shutdown_deadline_secs {
+            config.shutdown_deadline_secs = shutdown_deadline_secs;
+        }
+
+        if let Some(auth_key_file) = args.auth_key_file {
+            config.auth.key_file = Some(auth_key_file);
+        }
+
+        if let Some(tls_cert_file) = args.tls_cert_file {
+            config.tls.cert_file = Some(tls_cert_file);
+        }
+
+        if let Some(tls_key_file) = args.tls_key_file {
+            config.tls.key_file = Some(tls_key_file);
+        }
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("reading configuration file {:?}", path))?;
+
+        let config: Self = toml::from_str(&content)
+            .with_context(|| format!("parsing configuration file {:?}", path))?;
+        config
+            .validate()
+            .with_context(|| format!("checking configuration file {:?}", path))?;
+
+        Ok(config)
+    }
+
+    /// Values deserialization lets through but the server cannot run with.
+    fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.live.feed_capacity > 0,
+            "live.feed_capacity must be at least 1"
+        );
+        anyhow::ensure!(
+            self.live.viewer_buffer > 0,
+            "live.viewer_buffer must be at least 1"
+        );
+        anyhow::ensure!(
+            self.events.capacity > 0,
+            "events.capacity must be at least 1"
+        );
+        anyhow::ensure!(
+            self.retention.interval_secs > 0,
+            "retention.interval_secs must be at least 1"
+        );
+
+        Ok(())
+    }
+
+    pub fn cors_max_age(&self) -> Duration {
+        Duration::from_secs(self.cors.max_age_secs)
+    }
+
+    pub fn resume_window(&self) -> Duration {
+        Duration::from_secs(self.resume_window_secs)
+    }
+
+    pub fn shutdown_deadline(&self) -> Duration {
+        Duration::from_secs(self.shutdown_deadline_secs)
+    }
+
+    pub fn webhook_outbox_dir(&self) -> PathBuf {
+        self.webhooks
+            .outbox_dir
+            .clone()
+            .unwrap_or_else(|| self.recording_dir.join(".webhooks"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_line_wins_over_environment_over_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_file = dir.path().join("config.toml");
+        std::fs::write(
+            &config_file,
+            r#"
+listen = "127.0.0.1:1000"
+recording_dir = "/from/file"
+log_filter = "debug"
+
+[live]
+slow_viewer_policy = "disconnect"
+"#,
+        )
+        .unwrap();
+
+        // Only read by this test, the other tests build their configuration by hand
+        std::env::set_var("WEBM_STREAMER_LISTEN", "127.0.0.1:2000");
+        std::env::set_var("WEBM_STREAMER_RECORDING_DIR", "/from/env");
+        let args = Args::try_parse_from([
+            "webm-streamer".as_ref(),
+            "--config".as_ref(),
+            config_file.as_os_str(),
+            "--listen".as_ref(),
+            "127.0.0.1:3000".as_ref(),
+        ]);
+        std::env::remove_var("WEBM_STREAMER_LISTEN");
+        std::env::remove_var("WEBM_STREAMER_RECORDING_DIR");
+        let config = ServerConfig::from_args(args.unwrap()).unwrap();
+
+        assert_eq!(
+            "127.0.0.1:3000".parse::<SocketAddr>().unwrap(),
+            config.listen
+        );
+        assert_eq!(Path::new("/from/env"), config.recording_dir);
+        assert_eq!(Some("debug"), config.log_filter.as_deref());
+        assert_eq!(SlowViewerPolicy::Disconnect, config.live.slow_viewer_policy);
+        // Neither in the file nor given
+        assert_eq!(
+            ServerConfig::default().shutdown_deadline_secs,
+            config.shutdown_deadline_secs
+        );
+    }
+
+    #[test]
+    fn test_unknown_keys_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_file = dir.path().join("config.toml");
+        std::fs::write(&config_file, "[live]\nfeed_capacty = 16\n").unwrap();
+
+        let error = ServerConfig::from_file(&config_file).unwrap_err();
+        assert!(
+            format!("{:#}", error).contains("unknown field `feed_capacty`"),
+            "{:#}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_zero_sizes_and_intervals_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_file = dir.path().join("config.toml");
+
+        for (content, message) in [
+            (
+                "[live]\nfeed_capacity = 0\n",
+                "live.feed_capacity must be at least 1",
+            ),
+            (
+                "[live]\nviewer_buffer = 0\n",
+                "live.viewer_buffer must be at least 1",
+            ),
+            (
+                "[events]\ncapacity = 0\n",
+                "events.capacity must be at least 1",
+            ),
+            (
+                "[retention]\ninterval_secs = 0\n",
+                "retention.interval_secs must be at least 1",
+            ),
+        ] {
+            std::fs::write(&config_file, content).unwrap();
+            let error = ServerConfig::from_file(&config_file).unwrap_err();
+            assert!(format!("{:#}", error).contains(message), "{:#}", error);
+        }
+    }
+}
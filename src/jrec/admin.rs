@@ -0,0 +1,67 @@
+//! Operator endpoints for the push sessions in progress.
+
+use axum::{
+    extract::{Path, State},
+    http::header,
+    response::IntoResponse,
+    Json,
+};
+use hyper::StatusCode;
+use tracing::{error, info};
+
+use crate::utils::{recording_manager::ActiveRecording, state::AppState};
+
+pub async fn active_recordings(State(state): State<AppState>) -> Json<Vec<ActiveRecording>> {
+    Json(state.recording_manager().active_recordings().await)
+}
+
+/// Ends a push session, the recording is finalized as if the client had hung up.
+pub async fn stop_recording(
+    Path(recording): Path<String>,
+    State(state): State<AppState>,
+) -> Result<StatusCode, StatusCode> {
+    // Not looked up on disk, the file of a push session may have been removed or moved meanwhile
+    if std::path::Path::new(&recording).file_name() != Some(recording.as_ref()) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let recording_manager = state.recording_manager();
+    let path = recording_manager.recording_dir().join(&recording);
+
+    match recording_manager.stop_recording(&path).await {
+        Ok(true) => {
+            info!(?path, "Recording stopped by an operator");
+            Ok(StatusCode::ACCEPTED)
+        }
+        Ok(false) if tokio::fs::try_exists(&path).await.unwrap_or(false) => {
+            Err(StatusCode::CONFLICT)
+        }
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!(?path, ?e, "Failed to stop recording");
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// Prometheus scrape endpoint, in the OpenMetrics text format.
+pub async fn prometheus_metrics(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let recording_manager = state.recording_manager();
+    let encoded = recording_manager
+        .metrics()
+        .scrape(&recording_manager)
+        .await
+        .map_err(|e| {
+            error!(?e, "Failed to collect metrics");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )],
+        encoded,
+    ))
+}
@@ -0,0 +1,226 @@
+//! Signed access tokens for the `/jet/jrec` routes: what the holder may do, and on which recording.
+
+use std::{fmt, sync::Arc};
+
+use anyhow::Context;
+use axum::{
+    extract::{Query, RawPathParams, Request, State},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use hyper::StatusCode;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use tracing::{debug, info};
+
+use crate::{
+    config::{AuthConfig, TokenAlgorithm},
+    utils::state::AppState,
+};
+
+/// What a token allows, every route asks for one of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    /// Start a push session, or resume one
+    Push,
+    /// Download a recording, as is or exported
+    Pull,
+    /// Watch a recording, live or finished, list the recordings and follow their events
+    Stream,
+    /// Watch over the push sessions in progress and stop them
+    Admin,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Claims {
+    pub ops: Vec<Operation>,
+    /// File name of the one recording the token is good for, any recording when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recording: Option<String>,
+    /// Expiry, in seconds since the epoch
+    pub exp: u64,
+}
+
+impl Claims {
+    /// Whether the holder may do `operation` on `recording`, `None` being whatever recording the route picks.
+    pub fn permits(&self, operation: Operation, recording: Option<&str>) -> bool {
+        if !self.ops.contains(&operation) {
+            return false;
+        }
+
+        match self.recording.as_deref() {
+            None => true,
+            // The recording a push session writes to is only named once it starts
+            Some(_) if operation == Operation::Push => true,
+            Some(allowed) => recording == Some(allowed),
+        }
+    }
+}
+
+/// Checks the signature and expiry of a token, other schemes than JWT can be plugged in through [`AppState::new`].
+pub trait TokenVerifier: Send + Sync + fmt::Debug {
+    fn verify(&self, token: &str) -> anyhow::Result<Claims>;
+}
+
+pub struct JwtVerifier {
+    key: DecodingKey,
+    validation: Validation,
+}
+
+impl fmt::Debug for JwtVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtVerifier")
+            .field("algorithms", &self.validation.algorithms)
+            .finish_non_exhaustive()
+    }
+}
+
+impl JwtVerifier {
+    /// `key` is the shared secret for HS256, the PEM encoded public key for EdDSA.
+    pub fn new(config: &AuthConfig, key: &[u8]) -> anyhow::Result<Self> {
+        let (key, algorithm) = match config.algorithm {
+            // Secret files usually end with a newline the signer did not use
+            TokenAlgorithm::HS256 => (DecodingKey::from_secret(key.trim_ascii()), Algorithm::HS256),
+            TokenAlgorithm::EdDSA => (
+                DecodingKey::from_ed_pem(key).context("reading the EdDSA public key")?,
+                Algorithm::EdDSA,
+            ),
+        };
+
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = config.leeway_secs;
+        if let Some(issuer) = &config.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &config.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        Ok(Self { key, validation })
+    }
+}
+
+impl TokenVerifier for JwtVerifier {
+    fn verify(&self, token: &str) -> anyhow::Result<Claims> {
+        let data = jsonwebtoken::decode::<Claims>(token, &self.key, &self.validation)?;
+        Ok(data.claims)
+    }
+}
+
+/// The verifier `config` asks for, `None` when authorization is off.
+pub fn load_verifier(config: &AuthConfig) -> anyhow::Result<Option<Arc<dyn TokenVerifier>>> {
+    let Some(key_file) = &config.key_file else {
+        return Ok(None);
+    };
+    let key =
+        std::fs::read(key_file).with_context(|| format!("reading auth key file {:?}", key_file))?;
+    info!(algorithm = ?config.algorithm, "Access tokens required on every route");
+
+    Ok(Some(Arc::new(JwtVerifier::new(config, &key)?)))
+}
+
+#[derive(serde::Deserialize)]
+pub struct AuthQuery {
+    /// For clients that cannot set headers, like browsers opening a WebSocket
+    pub token: Option<String>,
+    pub recording: Option<String>,
+}
+
+/// Route middleware: lets the request through when its token allows `operation` on the recording
+/// it names, in the `recording` query parameter or path segment.
+pub async fn authorize(
+    State((state, operation)): State<(AppState, Operation)>,
+    path_params: Option<RawPathParams>,
+    Query(query): Query<AuthQuery>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let Some(verifier) = state.token_verifier() else {
+        return Ok(next.run(request).await);
+    };
+
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or(query.token.as_deref())
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let claims = verifier.verify(token).map_err(|e| {
+        debug!(?e, "Rejected access token");
+        StatusCode::UNAUTHORIZED
+    })?;
+
+    let recording = path_params
+        .as_ref()
+        .and_then(|params| {
+            params
+                .iter()
+                .find_map(|(key, value)| (key == "recording").then_some(value))
+        })
+        .or(query.recording.as_deref());
+    if !claims.permits(operation, recording) {
+        debug!(
+            ?operation,
+            ?recording,
+            "Access token does not allow the request"
+        );
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use jsonwebtoken::{EncodingKey, Header};
+
+    use super::*;
+
+    fn token(claims: &Claims, secret: &[u8]) -> String {
+        jsonwebtoken::encode(
+            &Header::default(),
+            claims,
+            &EncodingKey::from_secret(secret),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_tokens_only_allow_their_operations_and_recording() {
+        let verifier = JwtVerifier::new(&AuthConfig::default(), b"secret\n").unwrap();
+        let exp = Utc::now().timestamp() as u64 + 60;
+
+        let push = Claims {
+            ops: vec![Operation::Push],
+            recording: None,
+            exp,
+        };
+        let claims = verifier.verify(&token(&push, b"secret")).unwrap();
+        assert!(claims.permits(Operation::Push, None));
+        assert!(!claims.permits(Operation::Stream, Some("01_10_00_00.webm")));
+        assert!(!claims.permits(Operation::Admin, None));
+
+        let stream = Claims {
+            ops: vec![Operation::Stream, Operation::Pull],
+            recording: Some("01_10_00_00.webm".to_string()),
+            exp,
+        };
+        let claims = verifier.verify(&token(&stream, b"secret")).unwrap();
+        assert!(claims.permits(Operation::Stream, Some("01_10_00_00.webm")));
+        assert!(claims.permits(Operation::Pull, Some("01_10_00_00.webm")));
+        assert!(!claims.permits(Operation::Stream, Some("01_11_00_00.webm")));
+        // The latest recording may be somebody else's
+        assert!(!claims.permits(Operation::Stream, None));
+
+        assert!(verifier.verify(&token(&stream, b"other secret")).is_err());
+        let expired = Claims {
+            exp: exp - 3600,
+            ..stream
+        };
+        assert!(verifier.verify(&token(&expired, b"secret")).is_err());
+    }
+}
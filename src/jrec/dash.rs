@@ -0,0 +1,658 @@
+//! DASH (WebM profile) for recordings: an MPD, the recording header as initialization segment,
+//! and media segments cut at keyframe clusters. Active recordings get a dynamic MPD that players
+//! refresh to pick up new segments. Every file of a rotated recording is a Period of its own,
+//! with its own initialization segment.
+
+use std::{fmt::Write, io::SeekFrom, ops::Range, path::PathBuf, sync::Arc};
+
+use anyhow::Context;
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::header,
+    response::Response,
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use chrono::{DateTime, SecondsFormat, Utc};
+use hyper::StatusCode;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use tracing::{error, warn};
+
+use crate::utils::state::AppState;
+
+use super::{
+    auth::AuthQuery,
+    metadata::{read_tracks, RecordingMetadata, TrackInfo, TrackKind},
+    utils::find_recording,
+    webm::{
+        element_id,
+        index::RecordingIndex,
+        splitter::{ElementHeader, UNKNOWN_SIZE},
+    },
+};
+
+/// How often players re-fetch the MPD of an active recording
+const MINIMUM_UPDATE_PERIOD_SECS: u64 = 2;
+/// Used when nothing tells how much data the recording holds per second yet
+const DEFAULT_BANDWIDTH: u64 = 1_000_000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MediaSegment {
+    pub start_ms: u64,
+    pub duration_ms: u64,
+    /// Bytes of the recording holding the segment's clusters
+    pub range: Range<u64>,
+}
+
+/// One file of the recording in the MPD.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Period {
+    /// Where the file starts in the recording, its own timestamps start at zero
+    pub start_ms: u64,
+    pub segments: Vec<MediaSegment>,
+}
+
+/// Segments of a recording, each one starting at a keyframe cluster.
+///
+/// `end` is where the last cluster ends, `None` while the recording is still being written:
+/// the last segment is only complete once the next keyframe cluster has started.
+pub fn media_segments(index: &RecordingIndex, end: Option<u64>) -> Vec<MediaSegment> {
+    let keyframes: Vec<_> = index
+        .clusters
+        .iter()
+        .filter(|cluster| cluster.keyframe)
+        .collect();
+
+    let mut segments: Vec<_> = keyframes
+        .windows(2)
+        .map(|pair| MediaSegment {
+            start_ms: pair[0].timestamp_ms,
+            duration_ms: pair[1].timestamp_ms.saturating_sub(pair[0].timestamp_ms),
+            range: pair[0].offset..pair[1].offset,
+        })
+        .collect();
+
+    if let (Some(end), Some(last)) = (end, keyframes.last()) {
+        let duration_ms = index
+            .duration_ms
+            .and_then(|duration| duration.checked_sub(last.timestamp_ms))
+            .or_else(|| segments.last().map(|segment| segment.duration_ms))
+            .unwrap_or_default();
+
+        segments.push(MediaSegment {
+            start_ms: last.timestamp_ms,
+            duration_ms,
+            range: last.offset..end,
+        });
+    }
+
+    segments
+}
+
+/// The recording header with the Segment turned unknown-sized, media segments are appended to it
+/// one after the other and a finalized Segment size would not describe that stream.
+pub fn init_segment(header: &[u8]) -> anyhow::Result<Bytes> {
+    let ebml = ElementHeader::read(header)?.context("header too short for the EBML element")?;
+    anyhow::ensure!(
+        ebml.id == element_id::EBML,
+        "recording does not start with EBML"
+    );
+    let segment_start = ebml.len + ebml.known_size()?;
+
+    let segment = ElementHeader::read(&header[segment_start..])?
+        .context("header too short for the Segment element")?;
+    anyhow::ensure!(
+        segment.id == element_id::SEGMENT,
+        "EBML header is not followed by a Segment"
+    );
+
+    let mut init = BytesMut::with_capacity(header.len() + UNKNOWN_SIZE.len());
+    init.put_slice(&header[..segment_start]);
+    init.put_u32(element_id::SEGMENT);
+    init.put_slice(&UNKNOWN_SIZE);
+    init.put_slice(&header[segment_start + segment.len..]);
+    Ok(init.freeze())
+}
+
+/// `availability_start` is set for active recordings, which get a dynamic MPD. `token` is the access token
+/// the MPD was fetched with, players fetch the segments at the URLs as they are and need it too.
+pub fn render_manifest(
+    periods: &[Period],
+    tracks: &[TrackInfo],
+    availability_start: Option<DateTime<Utc>>,
+    token: Option<&str>,
+) -> String {
+    let mut mpd = String::new();
+    write_manifest(&mut mpd, periods, tracks, availability_start, token)
+        .expect("writing to a String cannot fail");
+    mpd
+}
+
+fn write_manifest(
+    mpd: &mut String,
+    periods: &[Period],
+    tracks: &[TrackInfo],
+    availability_start: Option<DateTime<Utc>>,
+    token: Option<&str>,
+) -> std::fmt::Result {
+    // Percent-encoded, nothing left to escape in an attribute or to take for a template identifier
+    let query = token.map_or_else(String::new, |token| {
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("token", token)
+            .finish();
+        format!("?{}", query)
+    });
+    let codecs: Vec<_> = tracks
+        .iter()
+        .filter_map(|track| dash_codec(&track.codec))
+        .collect();
+    let video = tracks.iter().find(|track| track.kind == TrackKind::Video);
+    let audio = tracks.iter().find(|track| track.kind == TrackKind::Audio);
+
+    let segments = periods.iter().flat_map(|period| &period.segments);
+    let total_bytes: u64 = segments
+        .clone()
+        .map(|segment| segment.range.end - segment.range.start)
+        .sum();
+    let segments_ms: u64 = segments.map(|segment| segment.duration_ms).sum();
+    let total_ms = periods.last().map_or(0, |period| {
+        period.start_ms
+            + period
+                .segments
+                .iter()
+                .map(|segment| segment.duration_ms)
+                .sum::<u64>()
+    });
+    let bandwidth = match segments_ms {
+        0 => DEFAULT_BANDWIDTH,
+        segments_ms => total_bytes * 8 * 1000 / segments_ms,
+    };
+
+    writeln!(mpd, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    write!(
+        mpd,
+        r#"<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" profiles="urn:mpeg:dash:profile:isoff-live:2011" minBufferTime="PT2S""#
+    )?;
+    match availability_start {
+        Some(availability_start) => {
+            write!(
+                mpd,
+                r#" type="dynamic" availabilityStartTime="{}" publishTime="{}" minimumUpdatePeriod="PT{}S""#,
+                availability_start.to_rfc3339_opts(SecondsFormat::Millis, true),
+                Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                MINIMUM_UPDATE_PERIOD_SECS,
+            )?;
+        }
+        None => {
+            write!(
+                mpd,
+                r#" type="static" mediaPresentationDuration="{}""#,
+                iso_duration(total_ms)
+            )?;
+        }
+    }
+    writeln!(mpd, ">")?;
+
+    for (id, period) in periods.iter().enumerate() {
+        writeln!(
+            mpd,
+            r#"  <Period id="{}" start="{}">"#,
+            id,
+            iso_duration(period.start_ms)
+        )?;
+        writeln!(
+            mpd,
+            r#"    <AdaptationSet mimeType="{}" segmentAlignment="true" startWithSAP="1">"#,
+            if video.is_some() {
+                "video/webm"
+            } else {
+                "audio/webm"
+            }
+        )?;
+        write!(
+            mpd,
+            r#"      <Representation id="0" codecs="{}" bandwidth="{}""#,
+            codecs.join(","),
+            bandwidth
+        )?;
+        if let Some(TrackInfo {
+            width: Some(width),
+            height: Some(height),
+            ..
+        }) = video
+        {
+            write!(mpd, r#" width="{}" height="{}""#, width, height)?;
+        }
+        if let Some(sample_rate) = audio.and_then(|audio| audio.sample_rate) {
+            write!(mpd, r#" audioSamplingRate="{}""#, sample_rate as u64)?;
+        }
+        writeln!(mpd, ">")?;
+
+        writeln!(
+            mpd,
+            r#"        <SegmentTemplate timescale="1000" initialization="{id}/init.webm{query}" media="{id}/$Number$.webm{query}" startNumber="1">"#
+        )?;
+        writeln!(mpd, "          <SegmentTimeline>")?;
+        for segment in &period.segments {
+            writeln!(
+                mpd,
+                r#"            <S t="{}" d="{}"/>"#,
+                segment.start_ms, segment.duration_ms
+            )?;
+        }
+        writeln!(mpd, "          </SegmentTimeline>")?;
+        writeln!(mpd, "        </SegmentTemplate>")?;
+        writeln!(mpd, "      </Representation>")?;
+        writeln!(mpd, "    </AdaptationSet>")?;
+        writeln!(mpd, "  </Period>")?;
+    }
+    writeln!(mpd, "</MPD>")
+}
+
+/// RFC 6381 codec string for a Matroska codec id.
+fn dash_codec(codec_id: &str) -> Option<&'static str> {
+    match codec_id {
+        "V_VP8" => Some("vp8"),
+        "V_VP9" => Some("vp9"),
+        // Main profile, level 4.0, 8 bit: what browsers record
+        "V_AV1" => Some("av01.0.08M.08"),
+        "A_OPUS" => Some("opus"),
+        "A_VORBIS" => Some("vorbis"),
+        _ => None,
+    }
+}
+
+fn iso_duration(duration_ms: u64) -> String {
+    format!("PT{}.{:03}S", duration_ms / 1000, duration_ms % 1000)
+}
+
+/// A recording and the files it was rotated into, a single one unless it was rotated.
+struct DashRecording {
+    path: PathBuf,
+    metadata: Option<RecordingMetadata>,
+    /// Still pushed, its last file keeps growing
+    active: bool,
+}
+
+impl DashRecording {
+    async fn load(state: &AppState, recording: &str) -> Result<Self, StatusCode> {
+        let recording_manager = state.recording_manager();
+        let path = find_recording(recording_manager.recording_dir(), recording).await?;
+        recording_manager.mark_viewed(&path);
+        let metadata = RecordingMetadata::load(&path).await.map_err(|e| {
+            error!(?e, "Failed to read recording sidecar");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        let active = recording_manager.subscribe(&path).await.is_some();
+
+        Ok(Self {
+            path,
+            metadata,
+            active,
+        })
+    }
+
+    fn period_paths(&self) -> Vec<PathBuf> {
+        match &self.metadata {
+            Some(metadata) => metadata.segment_paths(&self.path),
+            None => vec![self.path.clone()],
+        }
+    }
+
+    async fn period(&self, state: &AppState, period: usize) -> Result<DashPeriod, StatusCode> {
+        let period_paths = self.period_paths();
+        let path = period_paths.get(period).ok_or(StatusCode::NOT_FOUND)?;
+        // Only the last file of an active recording is still written
+        let growing = self.active && period + 1 == period_paths.len();
+
+        let indexed = match state.recording_manager().open_indexed(path).await {
+            Ok(indexed) => indexed,
+            Err(e) if growing => {
+                warn!(?path, ?e, "Nothing to segment in the recording yet");
+                return Err(StatusCode::NOT_FOUND);
+            }
+            Err(e) => {
+                error!(?path, ?e, "Failed to index recording");
+                return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        };
+        let end = (!growing).then(|| indexed.index.clusters_end.unwrap_or(indexed.len));
+
+        let start_timestamp = self
+            .metadata
+            .as_ref()
+            .and_then(|metadata| metadata.segments.get(period))
+            .map_or(0, |segment| segment.start_timestamp);
+        let start_ms = start_timestamp * indexed.index.timestamp_scale / 1_000_000;
+
+        Ok(DashPeriod {
+            start_ms,
+            index: indexed.index,
+            file: indexed.file,
+            end,
+        })
+    }
+
+    /// When the first cluster was pushed, in wall clock time.
+    fn availability_start(&self, periods: &[Period]) -> DateTime<Utc> {
+        match &self.metadata {
+            Some(metadata) => metadata.started_at,
+            None => {
+                let last_timestamp_ms = periods
+                    .last()
+                    .and_then(|period| {
+                        let segment = period.segments.last()?;
+                        Some(period.start_ms + segment.start_ms)
+                    })
+                    .unwrap_or_default();
+                Utc::now() - chrono::Duration::milliseconds(last_timestamp_ms as i64)
+            }
+        }
+    }
+}
+
+/// One file of a recording, indexed.
+struct DashPeriod {
+    start_ms: u64,
+    index: Arc<RecordingIndex>,
+    /// The file indexed, segments are read from it even after finalization replaced the recording
+    file: std::fs::File,
+    /// Where the last cluster ends, `None` while the file is written
+    end: Option<u64>,
+}
+
+impl DashPeriod {
+    fn into_file(self) -> tokio::fs::File {
+        tokio::fs::File::from_std(self.file)
+    }
+
+    fn segments(&self) -> Vec<MediaSegment> {
+        media_segments(&self.index, self.end)
+    }
+}
+
+pub async fn manifest(
+    Path(recording): Path<String>,
+    Query(query): Query<AuthQuery>,
+    State(state): State<AppState>,
+) -> Result<Response, StatusCode> {
+    let recording = DashRecording::load(&state, &recording).await?;
+
+    let mut periods = vec![];
+    for period in 0..recording.period_paths().len() {
+        match recording.period(&state, period).await {
+            Ok(dash_period) => periods.push(Period {
+                start_ms: dash_period.start_ms,
+                segments: dash_period.segments(),
+            }),
+            // The file rotated into holds no cluster yet, it is listed on a later refresh
+            Err(StatusCode::NOT_FOUND) if period > 0 => break,
+            Err(status) => return Err(status),
+        }
+    }
+
+    let tracks_path = recording.path.clone();
+    let tracks = tokio::task::spawn_blocking(move || read_tracks(&tracks_path))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|e| {
+            error!(?e, "Failed to read recording tracks");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let availability_start = recording
+        .active
+        .then(|| recording.availability_start(&periods));
+    let mpd = render_manifest(
+        &periods,
+        &tracks,
+        availability_start,
+        query.token.as_deref(),
+    );
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/dash+xml")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Body::from(mpd))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// `period` is the file of the recording, numbered from 0 as in the MPD.
+pub async fn init(
+    Path((recording, period)): Path<(String, usize)>,
+    State(state): State<AppState>,
+) -> Result<Response, StatusCode> {
+    let period = DashRecording::load(&state, &recording)
+        .await?
+        .period(&state, period)
+        .await?;
+
+    let mut header = vec![0; period.index.header_end as usize];
+    let mut file = period.into_file();
+    file.seek(SeekFrom::Start(0))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    file.read_exact(&mut header)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let init = init_segment(&header).map_err(|e| {
+        error!(?e, "Failed to build initialization segment");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    webm_response(Body::from(init))
+}
+
+/// `segment` is `<number>.webm`, numbered from 1 within its period as in the MPD.
+pub async fn segment(
+    Path((recording, period, segment)): Path<(String, usize, String)>,
+    State(state): State<AppState>,
+) -> Result<Response, StatusCode> {
+    let number: usize = segment
+        .strip_suffix(".webm")
+        .and_then(|number| number.parse().ok())
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let period = DashRecording::load(&state, &recording)
+        .await?
+        .period(&state, period)
+        .await?;
+    let segment = number
+        .checked_sub(1)
+        .and_then(|index| period.segments().get(index).cloned())
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let mut file = period.into_file();
+    file.seek(SeekFrom::Start(segment.range.start))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let data = file.take(segment.range.end - segment.range.start);
+
+    webm_response(Body::from_stream(ReaderStream::new(data)))
+}
+
+fn webm_response(body: Body) -> Result<Response, StatusCode> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, "video/webm")
+        .body(body)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use jsonwebtoken::{EncodingKey, Header};
+    use tokio::net::TcpListener;
+    use webm_iterable::{
+        matroska_spec::{Master, MatroskaSpec},
+        WebmIterator,
+    };
+
+    use crate::{
+        config::{AuthConfig, ServerConfig},
+        jrec::{
+            auth::{Claims, JwtVerifier, Operation},
+            make_router,
+            webm::{finalize::finalize_recording, fixture},
+        },
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_segments_of_active_and_finalized_recordings() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("recording.webm");
+        std::fs::write(&path, fixture::recording(4, 10)).unwrap();
+
+        // Still recording, the last keyframe cluster may grow
+        let index = RecordingIndex::build(&path).unwrap();
+        let starts: Vec<_> = media_segments(&index, None)
+            .iter()
+            .map(|segment| segment.start_ms)
+            .collect();
+        assert_eq!(vec![0, 330, 660], starts);
+
+        finalize_recording(&path).unwrap();
+        let index = RecordingIndex::build(&path).unwrap();
+        let segments = media_segments(&index, index.clusters_end);
+        assert_eq!(4, segments.len());
+        assert_eq!(index.header_end, segments[0].range.start);
+        assert!(segments
+            .windows(2)
+            .all(|pair| pair[0].range.end == pair[1].range.start));
+
+        // Header followed by every segment is a playable stream with all the clusters
+        let data = std::fs::read(&path).unwrap();
+        let mut stream = init_segment(&data[..index.header_end as usize])
+            .unwrap()
+            .to_vec();
+        for segment in &segments {
+            stream
+                .extend_from_slice(&data[segment.range.start as usize..segment.range.end as usize]);
+        }
+        let cluster_count = WebmIterator::new(Cursor::new(stream), &[])
+            .map_while(Result::ok)
+            .filter(|tag| matches!(tag, MatroskaSpec::Cluster(Master::Start)))
+            .count();
+        assert_eq!(4, cluster_count);
+
+        let tracks = read_tracks(&path).unwrap();
+        let periods = [Period {
+            start_ms: 0,
+            segments,
+        }];
+        let mpd = render_manifest(&periods, &tracks, None, None);
+        assert!(mpd.contains(r#"codecs="vp9,opus""#));
+        assert!(mpd.contains(r#"type="static""#));
+        assert_eq!(4, mpd.matches("<S ").count());
+    }
+
+    #[test]
+    fn test_rotated_recording_has_a_period_per_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("recording.webm");
+        std::fs::write(&path, fixture::recording(2, 10)).unwrap();
+        finalize_recording(&path).unwrap();
+        let index = RecordingIndex::build(&path).unwrap();
+        let segments = media_segments(&index, index.clusters_end);
+        let duration_ms: u64 = segments.iter().map(|segment| segment.duration_ms).sum();
+
+        let periods = [
+            Period {
+                start_ms: 0,
+                segments: segments.clone(),
+            },
+            Period {
+                start_ms: duration_ms,
+                segments,
+            },
+        ];
+        let mpd = render_manifest(&periods, &read_tracks(&path).unwrap(), None, None);
+        assert!(mpd.contains(r#"<Period id="0" start="PT0.000S">"#));
+        assert!(mpd.contains(&format!(
+            r#"<Period id="1" start="{}">"#,
+            iso_duration(duration_ms)
+        )));
+        assert!(mpd.contains(r#"initialization="1/init.webm" media="1/$Number$.webm""#));
+        assert!(mpd.contains(&format!(
+            r#"mediaPresentationDuration="{}""#,
+            iso_duration(2 * duration_ms)
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_segment_urls_carry_the_token_of_the_manifest() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("01_10_00_00.webm");
+        std::fs::write(&path, fixture::recording(2, 10)).unwrap();
+        finalize_recording(&path).unwrap();
+
+        let verifier = JwtVerifier::new(&AuthConfig::default(), b"secret").unwrap();
+        let config = ServerConfig {
+            recording_dir: directory.path().to_path_buf(),
+            ..Default::default()
+        };
+        let state = AppState::new(config, Some(Arc::new(verifier)));
+        let app = make_router(&state).with_state(state);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let claims = Claims {
+            ops: vec![Operation::Stream],
+            recording: Some("01_10_00_00.webm".to_string()),
+            exp: Utc::now().timestamp() as u64 + 60,
+        };
+        let token = jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        let manifest_url = reqwest::Url::parse(&format!(
+            "http://{}/jet/jrec/dash/01_10_00_00.webm/manifest.mpd?token={}",
+            addr, token
+        ))
+        .unwrap();
+
+        let client = reqwest::Client::new();
+        let mpd = client
+            .get(manifest_url.clone())
+            .send()
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        let init = format!("0/init.webm?token={}", token);
+        let media = format!("0/$Number$.webm?token={}", token);
+        assert!(
+            mpd.contains(&format!(r#"initialization="{}""#, init)),
+            "{}",
+            mpd
+        );
+        assert!(mpd.contains(&format!(r#"media="{}""#, media)), "{}", mpd);
+
+        // Resolved against the MPD URL, as a player does
+        for segment in [init, media.replace("$Number$", "1")] {
+            let response = client
+                .get(manifest_url.join(&segment).unwrap())
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(200, response.status().as_u16(), "{}", segment);
+        }
+        let response = client
+            .get(manifest_url.join("0/init.webm").unwrap())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(401, response.status().as_u16());
+    }
+}
@@ -0,0 +1,168 @@
+//! Lifecycle events of the recordings, pushed to UIs over Server-Sent Events.
+
+use std::convert::Infallible;
+
+use axum::{
+    extract::{Query, State},
+    response::sse::{Event, KeepAlive, Sse},
+};
+use chrono::{DateTime, Utc};
+use futures::Stream;
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc,
+};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::utils::state::AppState;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct RecordingEvent {
+    /// File name of the recording
+    pub recording: String,
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum EventKind {
+    Started {
+        session_id: Option<Uuid>,
+        group: Option<String>,
+    },
+    /// Live viewers can join from now on
+    FirstKeyframe,
+    /// Sent every `events.bytes_milestone` bytes received
+    BytesReceived {
+        bytes: u64,
+    },
+    ViewerJoined {
+        viewers: usize,
+    },
+    ViewerLeft {
+        viewers: usize,
+    },
+    /// The push session ended, the recording is being finalized
+    Stopped,
+    Finalized {
+        duration_ms: Option<u64>,
+        byte_size: u64,
+    },
+    /// Finalization failed, the recording is marked corrupt
+    Failed {
+        error: String,
+    },
+}
+
+/// The subscribers of the recording manager.
+#[derive(Debug, Clone)]
+pub struct EventSenders {
+    /// UIs, which may fall behind and miss events
+    pub broadcast: broadcast::Sender<RecordingEvent>,
+    /// Subscribers that must see every event, such as the webhooks
+    pub lossless: Vec<mpsc::UnboundedSender<RecordingEvent>>,
+}
+
+impl EventSenders {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            broadcast: broadcast::channel(capacity).0,
+            lossless: vec![],
+        }
+    }
+}
+
+/// Where the events of one recording go, the bus of the recording manager.
+#[derive(Debug, Clone)]
+pub struct RecordingEvents {
+    recording: String,
+    senders: EventSenders,
+    bytes_milestone: u64,
+}
+
+impl RecordingEvents {
+    pub fn new(recording: String, senders: EventSenders, bytes_milestone: u64) -> Self {
+        Self {
+            recording,
+            senders,
+            bytes_milestone,
+        }
+    }
+
+    pub fn emit(&self, kind: EventKind) {
+        let event = RecordingEvent {
+            recording: self.recording.clone(),
+            at: Utc::now(),
+            kind,
+        };
+        for lossless in &self.senders.lossless {
+            lossless.send(event.clone()).ok();
+        }
+        // Nobody listening is fine
+        self.senders.broadcast.send(event).ok();
+    }
+
+    /// Emits `BytesReceived` for every milestone crossed going from `before` to `after` bytes.
+    pub fn received(&self, before: u64, after: u64) {
+        if self.bytes_milestone == 0 {
+            return;
+        }
+        let milestones = (before / self.bytes_milestone + 1)..=(after / self.bytes_milestone);
+        for milestone in milestones {
+            self.emit(EventKind::BytesReceived {
+                bytes: milestone * self.bytes_milestone,
+            });
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct EventsQuery {
+    /// Only the events of this recording, every recording when not set
+    pub recording: Option<String>,
+}
+
+/// Every event as a JSON `message`, from the time of the request on. A client too slow to keep up
+/// gets `{"event":"lagged","skipped":<count>}` and should fetch `/list-recording` again, with the same
+/// `recording` when it follows only one.
+pub async fn events(
+    Query(query): Query<EventsQuery>,
+    State(state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let receiver = state.recording_manager().subscribe_events();
+
+    let stream = futures::stream::unfold(
+        (receiver, query.recording),
+        |(mut receiver, recording)| async move {
+            let event = loop {
+                match receiver.recv().await {
+                    Ok(event)
+                        if recording
+                            .as_ref()
+                            .is_none_or(|recording| *recording == event.recording) =>
+                    {
+                        break Event::default().json_data(&event);
+                    }
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "Event subscriber fell behind");
+                        break Event::default().json_data(serde_json::json!({
+                            "event": "lagged",
+                            "skipped": skipped,
+                        }));
+                    }
+                    // The server is shutting down
+                    Err(RecvError::Closed) => return None,
+                }
+            };
+            let event = event.expect("events serialize to JSON");
+
+            Some((Ok(event), (receiver, recording)))
+        },
+    );
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
@@ -0,0 +1,565 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc::{self, error::TrySendError},
+};
+use tracing::{debug, info, warn};
+
+use crate::config::{LiveConfig, SlowViewerPolicy};
+
+use super::{
+    events::{EventKind, RecordingEvents},
+    streaming::std_stream::AsyncBufferReader,
+    webm::splitter::{LiveChunk, LiveCluster},
+};
+
+/// How far back the current bitrate of a push session looks
+const BITRATE_WINDOW: Duration = Duration::from_secs(5);
+
+struct FeedState {
+    header: Option<Bytes>,
+    /// The last keyframe cluster and every cluster since, a viewer needs all of them to decode the current one.
+    /// Empty until the next keyframe once it would go over `max_group_of_pictures_bytes`
+    group_of_pictures: Vec<LiveCluster>,
+    group_of_pictures_bytes: usize,
+    max_group_of_pictures_bytes: usize,
+    keyframe_seen: bool,
+    /// Taken when the push ends, which closes every subscription
+    sender: Option<broadcast::Sender<LiveChunk>>,
+    published_clusters: u64,
+    viewers: Vec<Arc<ViewerStats>>,
+    created_at: Instant,
+    /// When and how many bytes were published during the last `BITRATE_WINDOW`
+    recent_chunks: VecDeque<(Instant, usize)>,
+    published_bytes: u64,
+}
+
+/// The chunks of an active push session, parsed once and shared by every live viewer.
+pub struct LiveFeed {
+    state: Mutex<FeedState>,
+    events: Option<RecordingEvents>,
+}
+
+impl std::fmt::Debug for LiveFeed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LiveFeed").finish_non_exhaustive()
+    }
+}
+
+pub struct LiveSubscription {
+    /// Already published when subscribing, later it arrives through `receiver`
+    pub header: Option<Bytes>,
+    /// Starts with a keyframe cluster, empty until the first one
+    pub group_of_pictures: Vec<LiveCluster>,
+    pub receiver: Option<broadcast::Receiver<LiveChunk>>,
+    /// Clusters published before subscribing
+    pub published_clusters: u64,
+}
+
+/// Counters of one live viewer, updated by its forwarding task.
+#[derive(Debug)]
+pub struct ViewerStats {
+    connected_at: Instant,
+    sent_clusters: AtomicU64,
+    sent_bytes: AtomicU64,
+    /// Clusters the viewer never got, because its buffer was full or it fell out of the feed
+    dropped_clusters: AtomicU64,
+    /// Clusters taken from the feed, sent or dropped
+    received_clusters: AtomicU64,
+    buffer_capacity: usize,
+    buffered_clusters: AtomicU64,
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ViewerSnapshot {
+    pub connected_secs: u64,
+    pub sent_clusters: u64,
+    pub sent_bytes: u64,
+    pub dropped_clusters: u64,
+    /// Clusters published that the viewer has not taken from the feed yet
+    pub lag_clusters: u64,
+    /// Clusters waiting in the viewer's own buffer
+    pub buffered_clusters: u64,
+    pub buffer_capacity: usize,
+}
+
+impl LiveFeed {
+    /// Keeps up to `capacity` chunks for viewers that are behind, and a group of pictures of up to
+    /// `max_group_of_pictures_bytes` for the viewers joining.
+    pub fn new(capacity: usize, max_group_of_pictures_bytes: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+
+        Self {
+            state: Mutex::new(FeedState {
+                header: None,
+                group_of_pictures: vec![],
+                group_of_pictures_bytes: 0,
+                max_group_of_pictures_bytes,
+                keyframe_seen: false,
+                sender: Some(sender),
+                published_clusters: 0,
+                viewers: vec![],
+                created_at: Instant::now(),
+                recent_chunks: VecDeque::new(),
+                published_bytes: 0,
+            }),
+            events: None,
+        }
+    }
+
+    /// Tells `events` about the first keyframe, the bytes received and the viewers coming and going.
+    pub fn with_events(mut self, events: RecordingEvents) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    fn emit(&self, kind: EventKind) {
+        if let Some(events) = &self.events {
+            events.emit(kind);
+        }
+    }
+
+    pub fn publish(&self, chunk: LiveChunk) {
+        // Sent under the lock so a subscriber gets each chunk either in its snapshot or from the channel, never both
+        let mut state = self.state.lock().expect("live feed lock");
+        match &chunk {
+            LiveChunk::Header(header) => state.header = Some(header.clone()),
+            LiveChunk::Cluster(cluster) => {
+                state.published_clusters += 1;
+                if cluster.keyframe {
+                    if !state.keyframe_seen {
+                        state.keyframe_seen = true;
+                        self.emit(EventKind::FirstKeyframe);
+                    }
+                    state.group_of_pictures.clear();
+                    state.group_of_pictures_bytes = 0;
+                }
+                if cluster.keyframe || !state.group_of_pictures.is_empty() {
+                    state.keep_in_group_of_pictures(cluster);
+                }
+            }
+            LiveChunk::Raw(_) => {}
+        }
+        let now = Instant::now();
+        let len = chunk.bytes().len();
+        state.recent_chunks.push_back((now, len));
+        state.forget_old_chunks(now);
+        let published_bytes = state.published_bytes + len as u64;
+        if let Some(events) = &self.events {
+            events.received(state.published_bytes, published_bytes);
+        }
+        state.published_bytes = published_bytes;
+
+        if let Some(sender) = &state.sender {
+            // Nobody listening is fine
+            sender.send(chunk).ok();
+        }
+    }
+
+    pub fn subscribe(&self) -> LiveSubscription {
+        let state = self.state.lock().expect("live feed lock");
+
+        LiveSubscription {
+            header: state.header.clone(),
+            group_of_pictures: state.group_of_pictures.clone(),
+            receiver: state.sender.as_ref().map(broadcast::Sender::subscribe),
+            published_clusters: state.published_clusters,
+        }
+    }
+
+    /// Subscribers get what is still queued, then the end of the stream.
+    pub fn finish(&self) {
+        self.state.lock().expect("live feed lock").sender = None;
+    }
+
+    /// Bits per second published over the last few seconds.
+    pub fn bitrate(&self) -> u64 {
+        let mut state = self.state.lock().expect("live feed lock");
+        let now = Instant::now();
+        state.forget_old_chunks(now);
+
+        let bytes: usize = state.recent_chunks.iter().map(|(_, len)| len).sum();
+        // A session younger than the window is not averaged over time it did not exist
+        let window = BITRATE_WINDOW.min(now - state.created_at).as_secs_f64();
+        if window == 0.0 {
+            return 0;
+        }
+        (bytes as f64 * 8.0 / window) as u64
+    }
+
+    pub fn viewers(&self) -> Vec<ViewerSnapshot> {
+        let state = self.state.lock().expect("live feed lock");
+
+        state
+            .viewers
+            .iter()
+            .map(|stats| stats.snapshot(state.published_clusters))
+            .collect()
+    }
+
+    fn add_viewer(&self, stats: Arc<ViewerStats>) {
+        let mut state = self.state.lock().expect("live feed lock");
+        state.viewers.push(stats);
+        self.emit(EventKind::ViewerJoined {
+            viewers: state.viewers.len(),
+        });
+    }
+
+    fn remove_viewer(&self, stats: &Arc<ViewerStats>) {
+        let mut state = self.state.lock().expect("live feed lock");
+        state.viewers.retain(|viewer| !Arc::ptr_eq(viewer, stats));
+        self.emit(EventKind::ViewerLeft {
+            viewers: state.viewers.len(),
+        });
+    }
+}
+
+impl FeedState {
+    fn keep_in_group_of_pictures(&mut self, cluster: &LiveCluster) {
+        let group_of_pictures_bytes = self.group_of_pictures_bytes + cluster.data.len();
+        if group_of_pictures_bytes > self.max_group_of_pictures_bytes {
+            debug!(
+                group_of_pictures_bytes,
+                "Group of pictures too large to keep, viewers joining wait for the next keyframe"
+            );
+            self.group_of_pictures.clear();
+            self.group_of_pictures_bytes = 0;
+            return;
+        }
+
+        self.group_of_pictures.push(cluster.clone());
+        self.group_of_pictures_bytes = group_of_pictures_bytes;
+    }
+
+    fn forget_old_chunks(&mut self, now: Instant) {
+        while let Some((published_at, _)) = self.recent_chunks.front() {
+            if now - *published_at <= BITRATE_WINDOW {
+                break;
+            }
+            self.recent_chunks.pop_front();
+        }
+    }
+}
+
+impl ViewerStats {
+    fn new(received_clusters: u64, buffer_capacity: usize) -> Self {
+        Self {
+            connected_at: Instant::now(),
+            sent_clusters: AtomicU64::new(0),
+            sent_bytes: AtomicU64::new(0),
+            dropped_clusters: AtomicU64::new(0),
+            received_clusters: AtomicU64::new(received_clusters),
+            buffer_capacity,
+            buffered_clusters: AtomicU64::new(0),
+        }
+    }
+
+    fn snapshot(&self, published_clusters: u64) -> ViewerSnapshot {
+        ViewerSnapshot {
+            connected_secs: self.connected_at.elapsed().as_secs(),
+            sent_clusters: self.sent_clusters.load(Ordering::Relaxed),
+            sent_bytes: self.sent_bytes.load(Ordering::Relaxed),
+            dropped_clusters: self.dropped_clusters.load(Ordering::Relaxed),
+            lag_clusters: published_clusters
+                .saturating_sub(self.received_clusters.load(Ordering::Relaxed)),
+            buffered_clusters: self.buffered_clusters.load(Ordering::Relaxed),
+            buffer_capacity: self.buffer_capacity,
+        }
+    }
+}
+
+/// Starts a viewer at the most recent keyframe, caught up with the clusters since, with timestamps
+/// rebased so its playback starts at zero.
+pub fn spawn_viewer(feed: &Arc<LiveFeed>, config: &LiveConfig) -> AsyncBufferReader {
+    let subscription = feed.subscribe();
+    let (sender, reader) = AsyncBufferReader::channel(config.viewer_buffer);
+    let stats = Arc::new(ViewerStats::new(
+        subscription.published_clusters,
+        config.viewer_buffer,
+    ));
+    feed.add_viewer(stats.clone());
+
+    let viewer = Viewer {
+        sender,
+        policy: config.slow_viewer_policy,
+        stats: stats.clone(),
+        awaiting_keyframe: true,
+        time_offset: None,
+    };
+    let feed = feed.clone();
+    tokio::spawn(async move {
+        match viewer.forward(subscription).await {
+            Ok(()) => info!("Live stream ended"),
+            Err(e) => debug!(?e, "Viewer went away"),
+        }
+        feed.remove_viewer(&stats);
+    });
+
+    reader
+}
+
+struct Viewer {
+    sender: mpsc::Sender<Bytes>,
+    policy: SlowViewerPolicy,
+    stats: Arc<ViewerStats>,
+    /// Frames are missing or the viewer just joined, the decoder can only start at a keyframe
+    awaiting_keyframe: bool,
+    time_offset: Option<u64>,
+}
+
+enum Delivery {
+    Sent,
+    Dropped,
+    Disconnect,
+}
+
+impl Viewer {
+    async fn forward(mut self, subscription: LiveSubscription) -> anyhow::Result<()> {
+        let LiveSubscription {
+            header,
+            group_of_pictures,
+            receiver,
+            ..
+        } = subscription;
+
+        // The buffer is empty at this point, there is nothing to apply the policy to
+        let mut header_sent = header.is_some();
+        if let Some(header) = header {
+            self.sender.send(header).await?;
+        }
+        if let Some(keyframe_cluster) = group_of_pictures.first().filter(|_| header_sent) {
+            self.awaiting_keyframe = false;
+            let time_offset = *self.time_offset.insert(keyframe_cluster.timestamp);
+            for cluster in &group_of_pictures {
+                self.sender.send(cluster.rebased(time_offset)).await?;
+            }
+        }
+
+        let Some(mut receiver) = receiver else {
+            return Ok(());
+        };
+
+        loop {
+            let chunk = match receiver.recv().await {
+                Ok(chunk) => chunk,
+                Err(RecvError::Closed) => return Ok(()),
+                Err(RecvError::Lagged(skipped)) => {
+                    self.stats
+                        .received_clusters
+                        .fetch_add(skipped, Ordering::Relaxed);
+                    self.stats
+                        .dropped_clusters
+                        .fetch_add(skipped, Ordering::Relaxed);
+                    if self.policy == SlowViewerPolicy::Disconnect {
+                        warn!(skipped, "Viewer fell out of the live feed, disconnecting");
+                        return Ok(());
+                    }
+
+                    warn!(
+                        skipped,
+                        "Viewer fell out of the live feed, skipping to the next keyframe"
+                    );
+                    self.awaiting_keyframe = true;
+                    continue;
+                }
+            };
+
+            match chunk {
+                LiveChunk::Header(header) if !header_sent => {
+                    self.sender.send(header).await?;
+                    header_sent = true;
+                }
+                LiveChunk::Cluster(cluster) if header_sent => {
+                    self.stats.received_clusters.fetch_add(1, Ordering::Relaxed);
+                    if let Delivery::Disconnect = self.deliver(&cluster).await? {
+                        warn!("Viewer buffer full, disconnecting");
+                        return Ok(());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    async fn deliver(&mut self, cluster: &LiveCluster) -> anyhow::Result<Delivery> {
+        if self.awaiting_keyframe && !cluster.keyframe {
+            self.stats.dropped_clusters.fetch_add(1, Ordering::Relaxed);
+            return Ok(Delivery::Dropped);
+        }
+
+        let time_offset = *self.time_offset.get_or_insert(cluster.timestamp);
+        let data = cluster.rebased(time_offset);
+        let len = data.len() as u64;
+
+        let delivery = match self.policy {
+            SlowViewerPolicy::Block => {
+                self.sender.send(data).await?;
+                Delivery::Sent
+            }
+            SlowViewerPolicy::DropToKeyframe | SlowViewerPolicy::Disconnect => {
+                match self.sender.try_send(data) {
+                    Ok(()) => Delivery::Sent,
+                    Err(TrySendError::Closed(_)) => anyhow::bail!("viewer closed its stream"),
+                    Err(TrySendError::Full(_)) if self.policy == SlowViewerPolicy::Disconnect => {
+                        Delivery::Disconnect
+                    }
+                    Err(TrySendError::Full(_)) => {
+                        debug!("Viewer buffer full, dropping until the next keyframe");
+                        Delivery::Dropped
+                    }
+                }
+            }
+        };
+
+        match delivery {
+            Delivery::Sent => {
+                self.awaiting_keyframe = false;
+                self.stats.sent_clusters.fetch_add(1, Ordering::Relaxed);
+                self.stats.sent_bytes.fetch_add(len, Ordering::Relaxed);
+            }
+            Delivery::Dropped => {
+                self.awaiting_keyframe = true;
+                self.stats.dropped_clusters.fetch_add(1, Ordering::Relaxed);
+            }
+            Delivery::Disconnect => {}
+        }
+        self.stats.buffered_clusters.store(
+            (self.sender.max_capacity() - self.sender.capacity()) as u64,
+            Ordering::Relaxed,
+        );
+
+        Ok(delivery)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prometheus_client::metrics::counter::Counter;
+    use tokio::io::AsyncReadExt;
+
+    use crate::jrec::webm::{fixture, splitter::ClusterSplitter};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_full_viewer_buffer_drops_clusters_without_stopping_the_feed() {
+        let mut splitter = ClusterSplitter::new(usize::MAX, Counter::default());
+        let mut chunks = splitter.push(&fixture::recording(8, 2));
+        chunks.extend(splitter.finish());
+        let (header, clusters) = chunks.split_first().unwrap();
+
+        let feed = Arc::new(LiveFeed::new(64, usize::MAX));
+        feed.publish(header.clone());
+        let config = LiveConfig {
+            viewer_buffer: 2,
+            ..LiveConfig::default()
+        };
+        let mut reader = spawn_viewer(&feed, &config);
+        let mut other_reader = spawn_viewer(&feed, &LiveConfig::default());
+
+        for cluster in clusters {
+            feed.publish(cluster.clone());
+        }
+        feed.finish();
+
+        // Nobody reads until everything is published: the small buffer only had room for the header and one cluster
+        let mut received = vec![];
+        reader.read_to_end(&mut received).await.unwrap();
+        let expected: Vec<u8> = chunks[..2]
+            .iter()
+            .flat_map(|chunk| chunk.bytes().to_vec())
+            .collect();
+        assert_eq!(expected, received);
+
+        let mut received = vec![];
+        other_reader.read_to_end(&mut received).await.unwrap();
+        assert_eq!(fixture::recording(8, 2), received);
+    }
+
+    #[tokio::test]
+    async fn test_viewer_joins_with_the_whole_group_of_pictures() {
+        let mut splitter = ClusterSplitter::new(usize::MAX, Counter::default());
+        let mut chunks = splitter.push(&fixture::recording(4, 2));
+        chunks.extend(splitter.finish());
+
+        let feed = Arc::new(LiveFeed::new(64, usize::MAX));
+        for (index, chunk) in chunks.iter().enumerate() {
+            let chunk = match chunk {
+                // Only the second cluster starts a group of pictures
+                LiveChunk::Cluster(cluster) if index != 2 => {
+                    let mut cluster = cluster.clone();
+                    cluster.keyframe = false;
+                    LiveChunk::Cluster(cluster)
+                }
+                chunk => chunk.clone(),
+            };
+            feed.publish(chunk);
+        }
+        let mut reader = spawn_viewer(&feed, &LiveConfig::default());
+        feed.finish();
+
+        let mut received = vec![];
+        reader.read_to_end(&mut received).await.unwrap();
+        let LiveChunk::Cluster(keyframe_cluster) = &chunks[2] else {
+            panic!("expected a cluster");
+        };
+        let mut expected = chunks[0].bytes().to_vec();
+        for chunk in &chunks[2..] {
+            let LiveChunk::Cluster(cluster) = chunk else {
+                panic!("expected a cluster");
+            };
+            expected.extend_from_slice(&cluster.rebased(keyframe_cluster.timestamp));
+        }
+        assert_eq!(expected, received);
+    }
+
+    #[tokio::test]
+    async fn test_group_of_pictures_over_the_limit_waits_for_the_next_keyframe() {
+        let mut splitter = ClusterSplitter::new(usize::MAX, Counter::default());
+        let mut chunks = splitter.push(&fixture::recording(4, 2));
+        chunks.extend(splitter.finish());
+        let clusters: Vec<_> = chunks[1..]
+            .iter()
+            .enumerate()
+            .map(|(index, chunk)| {
+                let LiveChunk::Cluster(cluster) = chunk else {
+                    panic!("expected a cluster");
+                };
+                let mut cluster = cluster.clone();
+                // Only the first and the last cluster start a group of pictures
+                cluster.keyframe = index == 0 || index == 3;
+                cluster
+            })
+            .collect();
+
+        // Room for the first two clusters, not the third
+        let limit = clusters[0].data.len() + clusters[1].data.len();
+        let feed = Arc::new(LiveFeed::new(64, limit));
+        feed.publish(chunks[0].clone());
+        feed.publish(LiveChunk::Cluster(clusters[0].clone()));
+        feed.publish(LiveChunk::Cluster(clusters[1].clone()));
+        assert_eq!(2, feed.subscribe().group_of_pictures.len());
+        feed.publish(LiveChunk::Cluster(clusters[2].clone()));
+        assert!(feed.subscribe().group_of_pictures.is_empty());
+
+        let mut reader = spawn_viewer(&feed, &LiveConfig::default());
+        feed.publish(LiveChunk::Cluster(clusters[3].clone()));
+        assert_eq!(1, feed.subscribe().group_of_pictures.len());
+        feed.finish();
+
+        let mut received = vec![];
+        reader.read_to_end(&mut received).await.unwrap();
+        let mut expected = chunks[0].bytes().to_vec();
+        expected.extend_from_slice(&clusters[3].rebased(clusters[3].timestamp));
+        assert_eq!(expected, received);
+    }
+}
@@ -0,0 +1,313 @@
+//! The JSON sidecar kept next to every recording, `<name>.meta.json` for `<name>.webm`.
+
+use std::{
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+use webm_iterable::{
+    matroska_spec::{Master, MatroskaSpec},
+    WebmIterator,
+};
+
+use crate::utils::file::{created_or_modified, std_open_read};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordingState {
+    /// The push session is still writing to the file
+    Active,
+    Finished,
+    /// Finalization failed, the file may not play
+    Corrupt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TrackKind {
+    Video,
+    Audio,
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct TrackInfo {
+    pub number: u64,
+    pub kind: TrackKind,
+    /// Matroska codec id, `V_VP9`, `A_OPUS`...
+    pub codec: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_rate: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channels: Option<u64>,
+}
+
+/// One file of a recording rotated during the push, the first one is the recording itself.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RecordingSegment {
+    pub file_name: String,
+    /// Cluster Timestamp the segment starts at in the push, its own timestamps start at zero.
+    /// In TimestampScale units, milliseconds for what browsers record
+    pub start_timestamp: u64,
+    pub byte_size: u64,
+    pub duration_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RecordingMetadata {
+    /// `None` for recordings made before sidecars existed
+    pub session_id: Option<Uuid>,
+    pub file_name: String,
+    /// Given by the pusher, retention limits the number of recordings per group
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    pub state: RecordingState,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: Option<DateTime<Utc>>,
+    pub byte_size: u64,
+    pub duration_ms: Option<u64>,
+    pub tracks: Vec<TrackInfo>,
+    /// Files of the recording in order, empty for recordings made before rotation existed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub segments: Vec<RecordingSegment>,
+    /// Set when the server died during the push and the recording was repaired on the next start
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recovered_at: Option<DateTime<Utc>>,
+}
+
+impl RecordingMetadata {
+    /// A recording starting now.
+    pub fn new(recording_path: &Path) -> Self {
+        Self {
+            session_id: Some(Uuid::new_v4()),
+            file_name: file_name(recording_path),
+            group: None,
+            state: RecordingState::Active,
+            started_at: Utc::now(),
+            ended_at: None,
+            byte_size: 0,
+            duration_ms: None,
+            tracks: vec![],
+            segments: vec![],
+            recovered_at: None,
+        }
+    }
+
+    /// What can be told from the file system about a recording without a sidecar.
+    pub fn synthesize(
+        recording_path: &Path,
+        metadata: &std::fs::Metadata,
+    ) -> std::io::Result<Self> {
+        Ok(Self {
+            session_id: None,
+            file_name: file_name(recording_path),
+            group: None,
+            state: RecordingState::Finished,
+            started_at: created_or_modified(metadata)?.into(),
+            ended_at: metadata.modified().ok().map(Into::into),
+            byte_size: metadata.len(),
+            duration_ms: None,
+            tracks: vec![],
+            segments: vec![],
+            recovered_at: None,
+        })
+    }
+
+    pub fn sidecar_path(recording_path: &Path) -> PathBuf {
+        recording_path.with_extension("meta.json")
+    }
+
+    /// Files holding the recording, in order.
+    pub fn segment_paths(&self, recording_path: &Path) -> Vec<PathBuf> {
+        if self.segments.is_empty() {
+            return vec![recording_path.to_path_buf()];
+        }
+
+        self.segments
+            .iter()
+            .map(|segment| recording_path.with_file_name(&segment.file_name))
+            .collect()
+    }
+
+    /// The recording a sidecar belongs to, `None` for any other file.
+    pub fn recording_path(sidecar_path: &Path) -> Option<PathBuf> {
+        let file_name = sidecar_path.file_name()?.to_str()?;
+        let stem = file_name.strip_suffix(".meta.json")?;
+        Some(sidecar_path.with_file_name(format!("{}.webm", stem)))
+    }
+
+    /// `Ok(None)` when the recording has no sidecar.
+    pub async fn load(recording_path: &Path) -> anyhow::Result<Option<Self>> {
+        let path = Self::sidecar_path(recording_path);
+        let content = match tokio::fs::read(&path).await {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("reading sidecar {:?}", path)),
+        };
+
+        serde_json::from_slice(&content)
+            .map(Some)
+            .with_context(|| format!("parsing sidecar {:?}", path))
+    }
+
+    /// Replaces the sidecar atomically, a listing never sees half of it.
+    pub async fn save(&self, recording_path: &Path) -> anyhow::Result<()> {
+        let path = Self::sidecar_path(recording_path);
+        let temp_path = path.with_extension("json.tmp");
+        let content = serde_json::to_vec_pretty(self)?;
+
+        tokio::fs::write(&temp_path, content)
+            .await
+            .with_context(|| format!("writing sidecar {:?}", temp_path))?;
+        tokio::fs::rename(&temp_path, &path)
+            .await
+            .with_context(|| format!("replacing sidecar {:?}", path))
+    }
+}
+
+/// Where the segment `index` of a rotated recording goes, `<name>.002.webm` for the second one of `<name>.webm`.
+pub fn segment_path(recording_path: &Path, index: usize) -> PathBuf {
+    if index == 0 {
+        return recording_path.to_path_buf();
+    }
+    let stem = recording_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    recording_path.with_file_name(format!("{}.{:03}.webm", stem, index + 1))
+}
+
+/// Whether the file is a later segment of a rotated recording rather than a recording of its own.
+pub fn is_segment_file(path: &Path) -> bool {
+    path.file_stem()
+        .and_then(|stem| Path::new(stem).extension())
+        .and_then(|index| index.to_str())
+        .is_some_and(|index| index.len() == 3 && index.bytes().all(|byte| byte.is_ascii_digit()))
+}
+
+pub(crate) fn file_name(recording_path: &Path) -> String {
+    recording_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Tracks declared in the header of a recording.
+pub fn read_tracks(recording_path: &Path) -> anyhow::Result<Vec<TrackInfo>> {
+    let file = std_open_read(recording_path)?;
+    let tracks = WebmIterator::new(BufReader::new(file), &[MatroskaSpec::Tracks(Master::Start)])
+        .map_while(Result::ok)
+        .find_map(|tag| match tag {
+            MatroskaSpec::Tracks(Master::Full(entries)) => Some(entries),
+            _ => None,
+        })
+        .context("recording has no Tracks")?;
+
+    Ok(tracks.iter().filter_map(track_info).collect())
+}
+
+pub(crate) fn track_info(entry: &MatroskaSpec) -> Option<TrackInfo> {
+    let MatroskaSpec::TrackEntry(Master::Full(children)) = entry else {
+        return None;
+    };
+
+    let mut track = TrackInfo {
+        number: 0,
+        kind: TrackKind::Other,
+        codec: String::new(),
+        width: None,
+        height: None,
+        sample_rate: None,
+        channels: None,
+    };
+    for child in children {
+        match child {
+            MatroskaSpec::TrackNumber(number) => track.number = *number,
+            MatroskaSpec::TrackType(1) => track.kind = TrackKind::Video,
+            MatroskaSpec::TrackType(2) => track.kind = TrackKind::Audio,
+            MatroskaSpec::CodecID(codec) => track.codec = codec.clone(),
+            MatroskaSpec::Video(Master::Full(video)) => {
+                for setting in video {
+                    match setting {
+                        MatroskaSpec::PixelWidth(width) => track.width = Some(*width),
+                        MatroskaSpec::PixelHeight(height) => track.height = Some(*height),
+                        _ => {}
+                    }
+                }
+            }
+            MatroskaSpec::Audio(Master::Full(audio)) => {
+                for setting in audio {
+                    match setting {
+                        MatroskaSpec::SamplingFrequency(rate) => track.sample_rate = Some(*rate),
+                        MatroskaSpec::Channels(channels) => track.channels = Some(*channels),
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    (track.number != 0).then_some(track)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jrec::webm::fixture;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sidecar_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let recording_path = dir.path().join("01_10_00_00.webm");
+        std::fs::write(&recording_path, fixture::recording(2, 4)).unwrap();
+
+        assert!(RecordingMetadata::load(&recording_path)
+            .await
+            .unwrap()
+            .is_none());
+
+        let mut metadata = RecordingMetadata::new(&recording_path);
+        metadata.tracks = read_tracks(&recording_path).unwrap();
+        metadata.save(&recording_path).await.unwrap();
+        assert!(dir.path().join("01_10_00_00.meta.json").exists());
+
+        let loaded = RecordingMetadata::load(&recording_path)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(metadata.session_id, loaded.session_id);
+        assert_eq!(RecordingState::Active, loaded.state);
+        assert_eq!(
+            vec![
+                TrackInfo {
+                    number: fixture::VIDEO_TRACK,
+                    kind: TrackKind::Video,
+                    codec: "V_VP9".to_string(),
+                    width: Some(640),
+                    height: Some(480),
+                    sample_rate: None,
+                    channels: None,
+                },
+                TrackInfo {
+                    number: fixture::AUDIO_TRACK,
+                    kind: TrackKind::Audio,
+                    codec: "A_OPUS".to_string(),
+                    width: None,
+                    height: None,
+                    sample_rate: Some(48000.0),
+                    channels: Some(2),
+                },
+            ],
+            loaded.tracks
+        );
+    }
+}
@@ -1,24 +1,45 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
+use auth::Operation;
 use axum::extract::{Query, State, WebSocketUpgrade};
+use axum::http::header;
+use axum::middleware;
 use axum::response::Response;
-use axum::routing::get;
+use axum::routing::{get, post};
 use axum::{body::Body, extract::ws::WebSocket};
 use axum::{Json, Router};
 use axum_extra::headers::Range;
 use axum_extra::TypedHeader;
+use futures::StreamExt;
 use hyper::StatusCode;
+use mp4::ExportFormat;
 use recording::ClientPush;
 use streaming::realtime::handle_realtime_stream;
 use streaming::test_stream;
 use tokio::fs::File;
+use tokio_util::io::ReaderStream;
 use tracing::info;
-use utils::{find_recording, get_latestest_recording, get_recording_list};
+use utils::{
+    find_recording, get_latestest_recording, list_recordings, segment_file, ListQuery,
+    RecordingPage,
+};
 use ws::websocket_compat;
 
 use crate::axum_range::{KnownSize, Ranged};
+use crate::utils::file::open_read;
+use crate::utils::growing_file::tail_segments;
+use crate::utils::metrics;
+use crate::utils::recording_manager::ViewGuard;
+use crate::utils::retention;
 use crate::utils::state::AppState;
 
+pub mod admin;
+pub mod auth;
+pub mod dash;
+pub mod events;
+pub mod live;
+pub mod metadata;
+pub mod mp4;
 pub mod recording;
 pub mod slow_reader;
 pub mod streaming;
@@ -26,34 +47,123 @@ pub mod utils;
 pub mod webm;
 pub mod ws;
 
-pub fn make_router() -> Router<AppState> {
+/// Every route asks for a token allowing its operation, once `auth.key_file` is configured.
+pub fn make_router(state: &AppState) -> Router<AppState> {
+    let require =
+        |operation| middleware::from_fn_with_state((state.clone(), operation), auth::authorize);
+    // Only on the routes sending recordings, the WebSocket ones are counted by their codec
+    let count_served = || {
+        middleware::from_fn_with_state(
+            state.recording_manager().metrics().clone(),
+            metrics::count_served,
+        )
+    };
+
     let router = Router::new()
-        .route("/push", get(jrec_push))
-        .route("/test", get(test))
-        .route("/stream-realtime", get(stream_realtime))
-        .route("/stream-file", get(stream_file))
-        .route("/list-recording", get(list_recording))
-        .route("/pull", get(pull_recording_file));
+        .route(
+            "/push",
+            get(jrec_push).route_layer(require(Operation::Push)),
+        )
+        .route("/test", get(test).route_layer(require(Operation::Stream)))
+        .route(
+            "/stream-realtime",
+            get(stream_realtime).route_layer(require(Operation::Stream)),
+        )
+        .route(
+            "/stream-file",
+            get(stream_file)
+                .route_layer(require(Operation::Stream))
+                .route_layer(count_served()),
+        )
+        .route(
+            "/stream-live",
+            get(stream_live)
+                .route_layer(require(Operation::Stream))
+                .route_layer(count_served()),
+        )
+        .route(
+            "/dash/:recording/manifest.mpd",
+            get(dash::manifest).route_layer(require(Operation::Stream)),
+        )
+        .route(
+            "/dash/:recording/:period/init.webm",
+            get(dash::init)
+                .route_layer(require(Operation::Stream))
+                .route_layer(count_served()),
+        )
+        .route(
+            "/dash/:recording/:period/:segment",
+            get(dash::segment)
+                .route_layer(require(Operation::Stream))
+                .route_layer(count_served()),
+        )
+        .route(
+            "/events",
+            get(events::events).route_layer(require(Operation::Stream)),
+        )
+        .route(
+            "/list-recording",
+            get(list_recording).route_layer(require(Operation::Stream)),
+        )
+        .route(
+            "/pull",
+            get(pull_recording_file)
+                .route_layer(require(Operation::Pull))
+                .route_layer(count_served()),
+        )
+        .route(
+            "/export",
+            get(export_recording)
+                .route_layer(require(Operation::Pull))
+                .route_layer(count_served()),
+        )
+        .route(
+            "/admin/recordings",
+            get(admin::active_recordings).route_layer(require(Operation::Admin)),
+        )
+        .route(
+            "/admin/recordings/:recording/stop",
+            post(admin::stop_recording).route_layer(require(Operation::Admin)),
+        );
 
-    Router::new().nest("/jet/jrec", router)
+    Router::new().nest("/jet/jrec", router).route(
+        "/metrics",
+        get(admin::prometheus_metrics).route_layer(require(Operation::Admin)),
+    )
 }
 
-pub async fn list_recording() -> Result<Json<Vec<(String, String)>>, StatusCode> {
-    let recording_list = get_recording_list().await?;
-    Ok(Json(recording_list))
+pub async fn list_recording(
+    query: Query<ListQuery>,
+    State(state): State<AppState>,
+) -> Result<Json<RecordingPage>, StatusCode> {
+    let limits = &state.config().limits;
+    let limit = query
+        .limit
+        .unwrap_or(limits.max_listed_recordings)
+        .min(limits.max_list_page_size);
+
+    let recordings = list_recordings(state.recording_manager().recording_dir()).await?;
+    Ok(Json(query.apply(recordings, limit)))
 }
 
 #[derive(serde::Deserialize)]
 pub struct RecordingQuery {
     pub recording: Option<String>,
+    /// Which file of a rotated recording to serve, counting from 0. Ignored by the live streams
+    pub segment: Option<usize>,
 }
 
-async fn get_path(query: Query<RecordingQuery>) -> Result<PathBuf, StatusCode> {
-    if let Some(recording) = query.0.recording {
-        find_recording(&recording).await
+async fn get_path(state: &AppState, query: Query<RecordingQuery>) -> Result<PathBuf, StatusCode> {
+    let recording_manager = state.recording_manager();
+    let recording_dir = recording_manager.recording_dir();
+    let path = if let Some(recording) = query.0.recording.as_deref() {
+        find_recording(recording_dir, recording).await
     } else {
-        get_latestest_recording().await
-    }
+        get_latestest_recording(recording_dir).await
+    }?;
+    recording_manager.mark_viewed(&path);
+
+    Ok(path)
 }
 
 async fn test(
@@ -61,9 +171,12 @@ async fn test(
     query: Query<RecordingQuery>,
     State(state): State<AppState>,
 ) -> Result<Response, StatusCode> {
-    let path = get_path(query).await?;
-    let response =
-        ws.on_upgrade(move |socket| test_stream(path, socket, state.recording_manager()));
+    let segment = query.segment;
+    let path = get_path(&state, query).await?;
+    let recording_manager = state.recording_manager();
+    let view = recording_manager.open_view(&path);
+    let path = segment_file(path, segment).await?;
+    let response = ws.on_upgrade(move |socket| test_stream(path, socket, recording_manager, view));
 
     Ok(response)
 }
@@ -71,10 +184,13 @@ async fn test(
 async fn stream_file(
     range: Option<TypedHeader<Range>>,
     query: Query<RecordingQuery>,
+    State(state): State<AppState>,
 ) -> Result<Ranged<KnownSize<File>>, StatusCode> {
-    let file = get_path(query).await?;
+    let segment = query.segment;
+    let file = get_path(&state, query).await?;
+    let file = segment_file(file, segment).await?;
 
-    let file = File::open(file)
+    let file = open_read(&file)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
@@ -83,24 +199,185 @@ async fn stream_file(
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
     let range = range.map(|TypedHeader(range)| range);
+    let metrics = state.recording_manager().metrics().clone();
+
+    Ok(Ranged::new(range, file).on_response(move |status| metrics.range_responded(status)))
+}
+
+#[derive(serde::Deserialize)]
+pub struct LiveStreamQuery {
+    pub recording: Option<String>,
+    /// Follow an active recording from its first byte instead of joining at the last keyframe
+    #[serde(default)]
+    pub from_start: bool,
+}
 
-    Ok(Ranged::new(range, file))
+/// An open-ended `video/webm` body that keeps growing until the push session ends,
+/// for players that only know how to fetch a URL.
+///
+/// From its start, an active recording is followed across the segments it is rotated into. Once finished,
+/// a rotated recording is rejected with 409 like any whole-file download, its segments are fetched one by one.
+async fn stream_live(
+    query: Query<LiveStreamQuery>,
+    State(state): State<AppState>,
+) -> Result<Response, StatusCode> {
+    let Query(LiveStreamQuery {
+        recording,
+        from_start,
+    }) = query;
+    let path = get_path(
+        &state,
+        Query(RecordingQuery {
+            recording,
+            segment: None,
+        }),
+    )
+    .await?;
+    let recording_manager = state.recording_manager();
+    let view = recording_manager.open_view(&path);
+
+    let body = if from_start {
+        match recording_manager.subscribe(&path).await {
+            Some(progress) => {
+                let file = open_read(&path)
+                    .await
+                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+                let max_element_bytes = recording_manager.config().limits.max_push_element_bytes;
+                Body::from_stream(tail_segments(path, file, progress, max_element_bytes))
+            }
+            None => file_body(&segment_file(path, None).await?).await?,
+        }
+    } else {
+        match recording_manager.live_viewer(&path).await {
+            Some(viewer) => Body::from_stream(ReaderStream::new(viewer)),
+            None => file_body(&segment_file(path, None).await?).await?,
+        }
+    };
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "video/webm")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(viewer_body(body, view))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Keeps the recording from being pruned until the response is over, however long the viewer takes.
+fn viewer_body(body: Body, view: ViewGuard) -> Body {
+    Body::from_stream(body.into_data_stream().map(move |data| {
+        let _view = &view;
+        data
+    }))
+}
+
+async fn file_body(path: &Path) -> Result<Body, StatusCode> {
+    let file = open_read(path)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Body::from_stream(ReaderStream::new(file)))
+}
+
+#[derive(serde::Deserialize)]
+pub struct ExportQuery {
+    pub recording: Option<String>,
+    pub segment: Option<usize>,
+    pub format: ExportFormat,
+}
+
+/// The recording in another container, remuxed while it is sent.
+async fn export_recording(
+    query: Query<ExportQuery>,
+    State(state): State<AppState>,
+) -> Result<Response, StatusCode> {
+    let Query(ExportQuery {
+        recording,
+        segment,
+        format,
+    }) = query;
+    let path = get_path(&state, Query(RecordingQuery { recording, segment })).await?;
+    let view = state.recording_manager().open_view(&path);
+    let path = segment_file(path, segment).await?;
+
+    let file_name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let (body, content_type, extension) = match format {
+        ExportFormat::Mp4 => {
+            let fragments = mp4::export_mp4(path).await.map_err(|e| {
+                tracing::warn!(?e, "Nothing to export in the recording");
+                StatusCode::NOT_FOUND
+            })?;
+            (
+                viewer_body(Body::from_stream(fragments), view),
+                "video/mp4",
+                "mp4",
+            )
+        }
+    };
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}.{}\"", file_name, extension),
+        )
+        .body(body)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[derive(serde::Deserialize)]
+pub struct PushQuery {
+    /// Retention keeps at most `retention.max_recordings_per_group` recordings of a group
+    pub group: Option<String>,
+    /// Session token of a push session whose connection dropped, the push goes on with the same recording
+    pub resume: Option<String>,
 }
 
 async fn jrec_push(
     ws: WebSocketUpgrade,
+    query: Query<PushQuery>,
     State(state): State<AppState>,
 ) -> Result<Response, StatusCode> {
     tracing::info!("JREC push request");
-    let response = ws.on_upgrade(|socket| handle_jrec_push(socket, state));
+    let recording_manager = state.recording_manager();
+    let Query(PushQuery { group, resume }) = query;
+    if let Some(session_token) = &resume {
+        if !recording_manager.can_resume(session_token).await {
+            tracing::warn!("Rejecting resume of an unknown push session");
+            return Err(StatusCode::NOT_FOUND);
+        }
+        let response = ws.on_upgrade(|socket| handle_jrec_push(socket, state, group, resume));
+        return Ok(response);
+    }
+
+    if !recording_manager.can_start_recording().await {
+        tracing::warn!("Rejecting push, too many active recordings");
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    match retention::ensure_free_space(&recording_manager).await {
+        Ok(true) => {}
+        Ok(false) => return Err(StatusCode::INSUFFICIENT_STORAGE),
+        Err(e) => {
+            tracing::error!(?e, "Failed to check free space");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+    let response = ws.on_upgrade(|socket| handle_jrec_push(socket, state, group, None));
     Ok(response)
 }
 
-async fn handle_jrec_push(ws: WebSocket, state: AppState) {
+async fn handle_jrec_push(
+    ws: WebSocket,
+    state: AppState,
+    group: Option<String>,
+    resume: Option<String>,
+) {
     tracing::info!("Upgrade to websocket");
     let result = ClientPush::builder()
         .client_stream(websocket_compat(ws))
         .recording_manager(state.recording_manager())
+        .group(group)
+        .resume(resume)
         .build()
         .run()
         .await;
@@ -110,15 +387,22 @@ async fn handle_jrec_push(ws: WebSocket, state: AppState) {
     }
 }
 
-async fn pull_recording_file(query: Query<RecordingQuery>) -> Result<Response, StatusCode> {
+async fn pull_recording_file(
+    query: Query<RecordingQuery>,
+    State(state): State<AppState>,
+) -> Result<Response, StatusCode> {
     info!("Pulling recording file: {:?}", query.recording);
-    let path = get_path(query).await?;
+    let segment = query.segment;
+    let path = get_path(&state, query).await?;
+    let view = state.recording_manager().open_view(&path);
+    let path = segment_file(path, segment).await?;
     info!("Serving recording: {:?}", path);
-    let body = Body::from_stream(tokio_util::io::ReaderStream::new(
-        tokio::fs::File::open(path).await.expect("file not found"),
-    ));
+    let file = open_read(&path)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let body = Body::from_stream(tokio_util::io::ReaderStream::new(file));
 
-    Ok(Response::new(body))
+    Ok(Response::new(viewer_body(body, view)))
 }
 
 async fn stream_realtime(
@@ -127,7 +411,7 @@ async fn stream_realtime(
     State(state): State<AppState>,
 ) -> Result<Response, StatusCode> {
     // We assume this path is a recording file and exist
-    let path = get_path(query).await?;
+    let path = get_path(&state, query).await?;
     let response = ws.on_upgrade(|socket| handle_realtime_stream(path, socket, state));
     Ok(response)
 }
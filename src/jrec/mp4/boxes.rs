@@ -0,0 +1,131 @@
+//! ISO-BMFF box writing, just what fragmented MP4 export needs.
+
+use bytes::{BufMut, BytesMut};
+
+/// Unity transformation matrix of `mvhd` and `tkhd`.
+const MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+/// Writes a box whose size is patched in once `body` has written its content.
+pub fn write_box(out: &mut BytesMut, kind: &[u8; 4], body: impl FnOnce(&mut BytesMut)) {
+    let start = out.len();
+    out.put_u32(0);
+    out.put_slice(kind);
+    body(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+pub fn write_full_box(
+    out: &mut BytesMut,
+    kind: &[u8; 4],
+    version: u8,
+    flags: u32,
+    body: impl FnOnce(&mut BytesMut),
+) {
+    write_box(out, kind, |out| {
+        out.put_u32((version as u32) << 24 | flags);
+        body(out);
+    });
+}
+
+pub fn write_matrix(out: &mut BytesMut) {
+    for value in MATRIX {
+        out.put_u32(value);
+    }
+}
+
+pub fn write_ftyp(out: &mut BytesMut) {
+    write_box(out, b"ftyp", |out| {
+        out.put_slice(b"isom");
+        out.put_u32(0x200);
+        for brand in [b"isom", b"iso6", b"mp41"] {
+            out.put_slice(brand);
+        }
+    });
+}
+
+/// `vpcC` for VP8 and VP9. WebM does not carry the profile and color description, these are the values
+/// of what browsers record: profile 0, 8 bit 4:2:0, BT.709 limited range.
+pub fn write_vpcc(out: &mut BytesMut) {
+    write_full_box(out, b"vpcC", 1, 0, |out| {
+        out.put_u8(0); // profile
+        out.put_u8(31); // level 3.1
+        out.put_u8((8 << 4) | (1 << 1)); // bit depth, chroma subsampling, full range flag
+        out.put_u8(1); // colour primaries
+        out.put_u8(1); // transfer characteristics
+        out.put_u8(1); // matrix coefficients
+        out.put_u16(0); // codec initialization data size
+    });
+}
+
+/// `av1C`, Matroska stores exactly its content in CodecPrivate.
+pub fn write_av1c(out: &mut BytesMut, codec_private: Option<&[u8]>) {
+    write_box(out, b"av1C", |out| match codec_private {
+        Some(config) if config.len() >= 4 => out.put_slice(config),
+        // Version 1, main profile level 4.0, 8 bit 4:2:0
+        _ => out.put_slice(&[0x81, 0x08, 0x0C, 0x00]),
+    });
+}
+
+/// `dOps` from the OpusHead Matroska stores in CodecPrivate, little endian there and big endian here.
+pub fn write_dops(out: &mut BytesMut, opus_head: Option<&[u8]>, channels: u16) {
+    write_box(out, b"dOps", |out| {
+        out.put_u8(0); // version
+        match opus_head {
+            Some(head) if head.len() >= 19 && head.starts_with(b"OpusHead") => {
+                out.put_u8(head[9]);
+                out.put_u16(u16::from_le_bytes([head[10], head[11]]));
+                out.put_u32(u32::from_le_bytes([head[12], head[13], head[14], head[15]]));
+                out.put_i16(i16::from_le_bytes([head[16], head[17]]));
+                out.put_u8(head[18]);
+                // Stream count, coupled count and channel mapping, only there for mapping families other than 0
+                if head[18] != 0 {
+                    out.put_slice(&head[19..]);
+                }
+            }
+            _ => {
+                out.put_u8(channels as u8);
+                out.put_u16(0); // pre-skip
+                out.put_u32(48000);
+                out.put_i16(0); // output gain
+                out.put_u8(0); // channel mapping family
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+pub(crate) mod reader {
+    //! Walks the boxes written above, for tests.
+
+    /// Type and content of every box in `data`, which holds boxes back to back.
+    pub fn boxes(mut data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut boxes = vec![];
+        while data.len() >= 8 {
+            let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+            boxes.push((data[4..8].try_into().unwrap(), &data[8..size]));
+            data = &data[size..];
+        }
+        boxes
+    }
+
+    /// Content of every box of type `kind` in `data`.
+    pub fn find_all<'a>(data: &'a [u8], kind: &[u8; 4]) -> Vec<&'a [u8]> {
+        boxes(data)
+            .into_iter()
+            .filter(|(box_kind, _)| box_kind == kind)
+            .map(|(_, body)| body)
+            .collect()
+    }
+
+    /// Content of the box at the end of `path`, following the first box of each type.
+    pub fn find<'a>(mut data: &'a [u8], path: &[&[u8; 4]]) -> &'a [u8] {
+        for kind in path {
+            data = find_all(data, kind)
+                .first()
+                .copied()
+                .unwrap_or_else(|| panic!("no {:?} box", String::from_utf8_lossy(*kind)));
+        }
+        data
+    }
+}
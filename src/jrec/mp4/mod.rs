@@ -0,0 +1,109 @@
+//! Export of recordings to fragmented MP4, for tools that do not read WebM.
+
+use std::{
+    io::{BufReader, Write},
+    path::PathBuf,
+};
+
+use bytes::Bytes;
+use futures::Stream;
+use tokio::sync::mpsc;
+use tracing::{error, info};
+
+use crate::{jrec::metadata::read_tracks, utils::file::std_open_read};
+
+pub mod boxes;
+pub mod remux;
+
+/// Fragments remuxed ahead of what the client has read
+const EXPORT_BUFFER_FRAGMENTS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Mp4,
+}
+
+/// Remuxes the recording on a blocking thread, fragments are streamed as soon as they are ready and
+/// the remux stops as soon as the stream is dropped.
+///
+/// Fails when the recording cannot be opened or has no track yet, before anything is streamed. A remux
+/// failing later ends the stream with the error, so the client does not take a cut off file for a whole one.
+pub async fn export_mp4(
+    path: PathBuf,
+) -> anyhow::Result<impl Stream<Item = std::io::Result<Bytes>>> {
+    let (file, path) = tokio::task::spawn_blocking(move || {
+        anyhow::ensure!(!read_tracks(&path)?.is_empty(), "recording has no track");
+        let file = std_open_read(&path)?;
+        anyhow::Ok((file, path))
+    })
+    .await??;
+
+    let (sender, mut receiver) = mpsc::channel(EXPORT_BUFFER_FRAGMENTS);
+    tokio::task::spawn_blocking(move || {
+        let output = ChannelWriter {
+            sender: sender.clone(),
+        };
+        match remux::remux(BufReader::new(file), output) {
+            Ok(summary) => info!(?path, ?summary, "Recording exported to MP4"),
+            Err(e) => {
+                error!(?path, ?e, "Failed to export recording to MP4");
+                sender
+                    .blocking_send(Err(std::io::Error::other(format!("{:#}", e))))
+                    .ok();
+            }
+        }
+    });
+
+    Ok(futures::stream::poll_fn(move |cx| receiver.poll_recv(cx)))
+}
+
+struct ChannelWriter {
+    sender: mpsc::Sender<std::io::Result<Bytes>>,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.sender
+            .blocking_send(Ok(Bytes::copy_from_slice(buf)))
+            .map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::BrokenPipe, "export client went away")
+            })?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use futures::TryStreamExt;
+    use webm_iterable::WebmWriter;
+
+    use crate::jrec::webm::fixture;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_export_checks_the_recording_before_streaming() {
+        let mut writer = WebmWriter::new(Vec::new());
+        writer.write(&fixture::ebml_header()).unwrap();
+        let mut no_tracks = tempfile::NamedTempFile::new().unwrap();
+        no_tracks.write_all(writer.get_ref()).unwrap();
+        assert!(export_mp4(no_tracks.path().to_path_buf()).await.is_err());
+
+        let mut recording = tempfile::NamedTempFile::new().unwrap();
+        recording.write_all(&fixture::recording(2, 4)).unwrap();
+        let fragments: Vec<Bytes> = export_mp4(recording.path().to_path_buf())
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(b"ftyp", &fragments.concat()[4..8]);
+    }
+}
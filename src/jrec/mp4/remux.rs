@@ -0,0 +1,597 @@
+//! WebM to fragmented MP4 without re-encoding: `ftyp` and `moov` from the Tracks,
+//! then one `moof`/`mdat` fragment per cluster.
+
+use std::io::{Read, Write};
+
+use anyhow::Context;
+use bytes::{BufMut, BytesMut};
+use tracing::warn;
+use webm_iterable::{
+    errors::TagIteratorError,
+    matroska_spec::{Master, MatroskaSpec},
+    WebmIterator,
+};
+
+use crate::jrec::{
+    metadata::{track_info, TrackInfo},
+    webm::{block::BlockHeader, finalize::timestamp_scale},
+};
+
+use super::boxes::{
+    write_av1c, write_box, write_dops, write_ftyp, write_full_box, write_matrix, write_vpcc,
+};
+
+const VIDEO_TIMESCALE: u32 = 90_000;
+/// Opus in ISO-BMFF always runs at 48 kHz, whatever the input rate was
+const OPUS_TIMESCALE: u32 = 48_000;
+const DEFAULT_TIMESTAMP_SCALE: u64 = 1_000_000;
+
+const TFHD_DEFAULT_BASE_IS_MOOF: u32 = 0x02_0000;
+/// Data offset, and a duration, size and flags for every sample
+const TRUN_FLAGS: u32 = 0x00_0001 | 0x00_0100 | 0x00_0200 | 0x00_0400;
+const SAMPLE_FLAGS_SYNC: u32 = 0x0200_0000;
+const SAMPLE_FLAGS_NON_SYNC: u32 = 0x0101_0000;
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct RemuxSummary {
+    pub tracks: usize,
+    pub fragments: u32,
+    pub samples: u64,
+    /// Laced blocks and blocks of tracks that cannot go in MP4
+    pub skipped_blocks: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Codec {
+    Vp8,
+    Vp9,
+    Av1,
+    Opus,
+}
+
+impl Codec {
+    fn from_id(codec_id: &str) -> Option<Self> {
+        match codec_id {
+            "V_VP8" => Some(Self::Vp8),
+            "V_VP9" => Some(Self::Vp9),
+            "V_AV1" => Some(Self::Av1),
+            "A_OPUS" => Some(Self::Opus),
+            _ => None,
+        }
+    }
+
+    fn is_video(self) -> bool {
+        self != Self::Opus
+    }
+}
+
+struct Mp4Track {
+    id: u32,
+    webm_number: u64,
+    codec: Codec,
+    info: TrackInfo,
+    codec_private: Option<Vec<u8>>,
+    timescale: u32,
+    /// Given to the last sample of a fragment when nothing follows it
+    last_duration: u32,
+}
+
+struct Sample {
+    decode_time: u64,
+    /// Known once the next sample of the track is read
+    duration: Option<u32>,
+    keyframe: bool,
+    data: Vec<u8>,
+}
+
+/// Samples of one cluster, per track in the order of [`Remuxer::tracks`].
+struct Fragment {
+    samples: Vec<Vec<Sample>>,
+}
+
+/// Turns the tags of a WebM recording into fragmented MP4 written to `output` as it goes.
+pub struct Remuxer<W> {
+    output: W,
+    timestamp_scale: u64,
+    tracks: Vec<Mp4Track>,
+    /// Written once the next cluster has given the duration of its last samples
+    previous: Option<Fragment>,
+    current: Option<Fragment>,
+    cluster_timestamp: u64,
+    summary: RemuxSummary,
+}
+
+/// Remuxes a whole recording, a recording cut in the middle of an element is exported up to there.
+pub fn remux<R: Read, W: Write>(input: R, output: W) -> anyhow::Result<RemuxSummary> {
+    let tag_iterator = WebmIterator::new(
+        input,
+        &[
+            MatroskaSpec::Ebml(Master::Start),
+            MatroskaSpec::SeekHead(Master::Start),
+            MatroskaSpec::Info(Master::Start),
+            MatroskaSpec::Tracks(Master::Start),
+            MatroskaSpec::BlockGroup(Master::Start),
+            MatroskaSpec::Cues(Master::Start),
+        ],
+    );
+
+    let mut remuxer = Remuxer::new(output);
+    for tag in tag_iterator {
+        let tag = match tag {
+            Ok(tag) => tag,
+            Err(TagIteratorError::UnexpectedEOF { tag_start, .. }) => {
+                warn!(tag_start, "Recording ends in the middle of an element");
+                break;
+            }
+            Err(e) if remuxer.summary.samples > 0 => {
+                warn!(?e, "Recording is corrupted, exporting what was read so far");
+                break;
+            }
+            Err(e) => return Err(e).context("reading recording"),
+        };
+        remuxer.push(tag)?;
+    }
+
+    remuxer.finish()
+}
+
+impl<W: Write> Remuxer<W> {
+    pub fn new(output: W) -> Self {
+        Self {
+            output,
+            timestamp_scale: DEFAULT_TIMESTAMP_SCALE,
+            tracks: vec![],
+            previous: None,
+            current: None,
+            cluster_timestamp: 0,
+            summary: RemuxSummary::default(),
+        }
+    }
+
+    pub fn push(&mut self, tag: MatroskaSpec) -> anyhow::Result<()> {
+        match tag {
+            MatroskaSpec::Info(Master::Full(_)) => self.timestamp_scale = timestamp_scale(&tag),
+            MatroskaSpec::Tracks(Master::Full(entries)) => {
+                self.set_tracks(&entries)?;
+                self.write_init()?;
+            }
+            MatroskaSpec::Cluster(Master::Start) => {
+                anyhow::ensure!(!self.tracks.is_empty(), "cluster before the Tracks");
+                if let Some(fragment) = self.previous.take() {
+                    self.write_fragment(fragment)?;
+                }
+                self.previous = self.current.take();
+                self.current = Some(Fragment {
+                    samples: self.tracks.iter().map(|_| vec![]).collect(),
+                });
+            }
+            MatroskaSpec::Timestamp(timestamp) => self.cluster_timestamp = timestamp,
+            MatroskaSpec::SimpleBlock(data) => {
+                let keyframe = BlockHeader::parse(&data).map(|header| header.is_keyframe());
+                self.push_block(&data, keyframe.unwrap_or(false));
+            }
+            MatroskaSpec::BlockGroup(Master::Full(children)) => {
+                // A Block is a keyframe unless it references another one
+                let keyframe = !children
+                    .iter()
+                    .any(|child| matches!(child, MatroskaSpec::ReferenceBlock(_)));
+                for child in children {
+                    if let MatroskaSpec::Block(data) = child {
+                        self.push_block(&data, keyframe);
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> anyhow::Result<RemuxSummary> {
+        anyhow::ensure!(!self.tracks.is_empty(), "recording has no Tracks");
+
+        for fragment in [self.previous.take(), self.current.take()]
+            .into_iter()
+            .flatten()
+        {
+            self.write_fragment(fragment)?;
+        }
+        self.output.flush()?;
+
+        Ok(self.summary)
+    }
+
+    fn set_tracks(&mut self, entries: &[MatroskaSpec]) -> anyhow::Result<()> {
+        for entry in entries {
+            let Some(info) = track_info(entry) else {
+                continue;
+            };
+            let Some(codec) = Codec::from_id(&info.codec) else {
+                warn!(codec = %info.codec, "Track cannot be exported to MP4, skipping it");
+                continue;
+            };
+            let codec_private = match entry {
+                MatroskaSpec::TrackEntry(Master::Full(children)) => {
+                    children.iter().find_map(|child| match child {
+                        MatroskaSpec::CodecPrivate(data) => Some(data.clone()),
+                        _ => None,
+                    })
+                }
+                _ => None,
+            };
+
+            self.tracks.push(Mp4Track {
+                id: self.tracks.len() as u32 + 1,
+                webm_number: info.number,
+                codec,
+                info,
+                codec_private,
+                timescale: if codec.is_video() {
+                    VIDEO_TIMESCALE
+                } else {
+                    OPUS_TIMESCALE
+                },
+                last_duration: 0,
+            });
+        }
+
+        anyhow::ensure!(
+            !self.tracks.is_empty(),
+            "recording has no VP8, VP9, AV1 or Opus track"
+        );
+        self.summary.tracks = self.tracks.len();
+        Ok(())
+    }
+
+    fn push_block(&mut self, data: &[u8], keyframe: bool) {
+        let Some(header) = BlockHeader::parse(data) else {
+            self.summary.skipped_blocks += 1;
+            return;
+        };
+        let Some(track_index) = self
+            .tracks
+            .iter()
+            .position(|track| track.webm_number == header.track)
+        else {
+            self.summary.skipped_blocks += 1;
+            return;
+        };
+        let Some(current) = self.current.as_mut() else {
+            return;
+        };
+        if header.is_laced() {
+            // Browsers never lace, splitting laced frames is not worth it here
+            self.summary.skipped_blocks += 1;
+            return;
+        }
+
+        let track = &mut self.tracks[track_index];
+        let timestamp_ns =
+            header.timestamp(self.cluster_timestamp) as u128 * self.timestamp_scale as u128;
+        let decode_time = (timestamp_ns * track.timescale as u128 / 1_000_000_000) as u64;
+
+        let waiting = match current.samples[track_index].last_mut() {
+            Some(sample) => Some(sample),
+            None => self
+                .previous
+                .as_mut()
+                .and_then(|previous| previous.samples[track_index].last_mut()),
+        };
+        if let Some(sample) = waiting.filter(|sample| sample.duration.is_none()) {
+            let duration = decode_time.saturating_sub(sample.decode_time) as u32;
+            sample.duration = Some(duration);
+            track.last_duration = duration;
+        }
+
+        current.samples[track_index].push(Sample {
+            decode_time,
+            duration: None,
+            keyframe: keyframe || !track.codec.is_video(),
+            data: data[header.len..].to_vec(),
+        });
+        self.summary.samples += 1;
+    }
+
+    fn write_init(&mut self) -> std::io::Result<()> {
+        let mut out = BytesMut::new();
+        write_ftyp(&mut out);
+        write_box(&mut out, b"moov", |out| {
+            write_full_box(out, b"mvhd", 0, 0, |out| {
+                out.put_u32(0); // creation time
+                out.put_u32(0); // modification time
+                out.put_u32(1000); // timescale
+                out.put_u32(0); // duration, given by the fragments
+                out.put_u32(0x0001_0000); // rate
+                out.put_u16(0x0100); // volume
+                out.put_bytes(0, 10);
+                write_matrix(out);
+                out.put_bytes(0, 24);
+                out.put_u32(self.tracks.len() as u32 + 1); // next track id
+            });
+            for track in &self.tracks {
+                write_trak(out, track);
+            }
+            write_box(out, b"mvex", |out| {
+                for track in &self.tracks {
+                    write_full_box(out, b"trex", 0, 0, |out| {
+                        out.put_u32(track.id);
+                        out.put_u32(1); // sample description index
+                        out.put_u32(0); // duration
+                        out.put_u32(0); // size
+                        out.put_u32(0); // flags
+                    });
+                }
+            });
+        });
+
+        self.output.write_all(&out)
+    }
+
+    fn write_fragment(&mut self, mut fragment: Fragment) -> std::io::Result<()> {
+        for (track, samples) in self.tracks.iter().zip(&mut fragment.samples) {
+            for sample in samples.iter_mut() {
+                sample.duration.get_or_insert(track.last_duration);
+            }
+        }
+        if fragment.samples.iter().all(Vec::is_empty) {
+            return Ok(());
+        }
+        self.summary.fragments += 1;
+
+        let mut out = BytesMut::new();
+        let mut data_offset_positions = vec![];
+        write_box(&mut out, b"moof", |out| {
+            write_full_box(out, b"mfhd", 0, 0, |out| {
+                out.put_u32(self.summary.fragments);
+            });
+            for (track, samples) in self.tracks.iter().zip(&fragment.samples) {
+                if samples.is_empty() {
+                    continue;
+                }
+                write_box(out, b"traf", |out| {
+                    write_full_box(out, b"tfhd", 0, TFHD_DEFAULT_BASE_IS_MOOF, |out| {
+                        out.put_u32(track.id);
+                    });
+                    write_full_box(out, b"tfdt", 1, 0, |out| {
+                        out.put_u64(samples[0].decode_time);
+                    });
+                    write_full_box(out, b"trun", 0, TRUN_FLAGS, |out| {
+                        out.put_u32(samples.len() as u32);
+                        data_offset_positions.push(out.len());
+                        out.put_u32(0);
+                        for sample in samples {
+                            out.put_u32(sample.duration.unwrap_or_default());
+                            out.put_u32(sample.data.len() as u32);
+                            out.put_u32(if sample.keyframe {
+                                SAMPLE_FLAGS_SYNC
+                            } else {
+                                SAMPLE_FLAGS_NON_SYNC
+                            });
+                        }
+                    });
+                });
+            }
+        });
+
+        // Offsets are from the start of the moof to the samples of each track in the mdat
+        let mut data_offset = out.len() + 8;
+        let track_samples = fragment
+            .samples
+            .iter()
+            .filter(|samples| !samples.is_empty());
+        for (position, samples) in data_offset_positions.into_iter().zip(track_samples) {
+            out[position..position + 4].copy_from_slice(&(data_offset as u32).to_be_bytes());
+            data_offset += samples
+                .iter()
+                .map(|sample| sample.data.len())
+                .sum::<usize>();
+        }
+
+        write_box(&mut out, b"mdat", |out| {
+            for sample in fragment.samples.iter().flatten() {
+                out.put_slice(&sample.data);
+            }
+        });
+
+        self.output.write_all(&out)
+    }
+}
+
+fn write_trak(out: &mut BytesMut, track: &Mp4Track) {
+    let width = track.info.width.unwrap_or_default() as u32;
+    let height = track.info.height.unwrap_or_default() as u32;
+    let channels = track.info.channels.unwrap_or(2) as u16;
+
+    write_box(out, b"trak", |out| {
+        // Enabled and in movie
+        write_full_box(out, b"tkhd", 0, 0x3, |out| {
+            out.put_u32(0); // creation time
+            out.put_u32(0); // modification time
+            out.put_u32(track.id);
+            out.put_u32(0);
+            out.put_u32(0); // duration
+            out.put_bytes(0, 8);
+            out.put_u16(0); // layer
+            out.put_u16(0); // alternate group
+            out.put_u16(if track.codec.is_video() { 0 } else { 0x0100 }); // volume
+            out.put_u16(0);
+            write_matrix(out);
+            out.put_u32(width << 16);
+            out.put_u32(height << 16);
+        });
+        write_box(out, b"mdia", |out| {
+            write_full_box(out, b"mdhd", 0, 0, |out| {
+                out.put_u32(0); // creation time
+                out.put_u32(0); // modification time
+                out.put_u32(track.timescale);
+                out.put_u32(0); // duration
+                out.put_u16(0x55C4); // "und"
+                out.put_u16(0);
+            });
+            write_full_box(out, b"hdlr", 0, 0, |out| {
+                out.put_u32(0);
+                if track.codec.is_video() {
+                    out.put_slice(b"vide");
+                } else {
+                    out.put_slice(b"soun");
+                }
+                out.put_bytes(0, 12);
+                out.put_slice(b"webm-streamer\0");
+            });
+            write_box(out, b"minf", |out| {
+                if track.codec.is_video() {
+                    write_full_box(out, b"vmhd", 0, 1, |out| out.put_bytes(0, 8));
+                } else {
+                    write_full_box(out, b"smhd", 0, 0, |out| out.put_u32(0));
+                }
+                write_box(out, b"dinf", |out| {
+                    write_full_box(out, b"dref", 0, 0, |out| {
+                        out.put_u32(1);
+                        // Samples are in this file
+                        write_full_box(out, b"url ", 0, 1, |_| {});
+                    });
+                });
+                write_box(out, b"stbl", |out| {
+                    write_full_box(out, b"stsd", 0, 0, |out| {
+                        out.put_u32(1);
+                        write_sample_entry(out, track, width as u16, height as u16, channels);
+                    });
+                    // Samples are all in the fragments
+                    write_full_box(out, b"stts", 0, 0, |out| out.put_u32(0));
+                    write_full_box(out, b"stsc", 0, 0, |out| out.put_u32(0));
+                    write_full_box(out, b"stsz", 0, 0, |out| out.put_bytes(0, 8));
+                    write_full_box(out, b"stco", 0, 0, |out| out.put_u32(0));
+                });
+            });
+        });
+    });
+}
+
+fn write_sample_entry(
+    out: &mut BytesMut,
+    track: &Mp4Track,
+    width: u16,
+    height: u16,
+    channels: u16,
+) {
+    let codec_private = track.codec_private.as_deref();
+    let kind = match track.codec {
+        Codec::Vp8 => b"vp08",
+        Codec::Vp9 => b"vp09",
+        Codec::Av1 => b"av01",
+        Codec::Opus => b"Opus",
+    };
+
+    write_box(out, kind, |out| {
+        out.put_bytes(0, 6);
+        out.put_u16(1); // data reference index
+
+        if track.codec == Codec::Opus {
+            out.put_bytes(0, 8);
+            out.put_u16(channels);
+            out.put_u16(16); // sample size
+            out.put_u32(0);
+            out.put_u32(OPUS_TIMESCALE << 16);
+            write_dops(out, codec_private, channels);
+            return;
+        }
+
+        out.put_bytes(0, 16);
+        out.put_u16(width);
+        out.put_u16(height);
+        out.put_u32(0x0048_0000); // 72 dpi
+        out.put_u32(0x0048_0000);
+        out.put_u32(0);
+        out.put_u16(1); // frame count
+        out.put_bytes(0, 32); // compressor name
+        out.put_u16(0x0018); // depth
+        out.put_i16(-1);
+        match track.codec {
+            Codec::Av1 => write_av1c(out, codec_private),
+            _ => write_vpcc(out),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jrec::{
+        mp4::boxes::reader::{boxes, find, find_all},
+        webm::fixture,
+    };
+
+    use super::*;
+
+    fn trun_samples(traf: &[u8]) -> Vec<(u32, u32, u32)> {
+        let trun = find(traf, &[b"trun"]);
+        let count = u32::from_be_bytes(trun[4..8].try_into().unwrap()) as usize;
+        (0..count)
+            .map(|i| {
+                let sample = &trun[12 + i * 12..24 + i * 12];
+                let field = |at: usize| u32::from_be_bytes(sample[at..at + 4].try_into().unwrap());
+                (field(0), field(4), field(8))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_remux_fixture() {
+        let recording = fixture::recording(3, 4);
+        let mut output = vec![];
+        let summary = remux(recording.as_slice(), &mut output).unwrap();
+        assert_eq!(2, summary.tracks);
+        assert_eq!(3, summary.fragments);
+        assert_eq!(24, summary.samples);
+        assert_eq!(0, summary.skipped_blocks);
+
+        let kinds: Vec<_> = boxes(&output).iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(
+            vec![*b"ftyp", *b"moov", *b"moof", *b"mdat", *b"moof", *b"mdat", *b"moof", *b"mdat"],
+            kinds
+        );
+
+        // Skipping the version, flags and entry count of each stsd
+        let moov = find(&output, &[b"moov"]);
+        let sample_entries: Vec<_> = find_all(moov, b"trak")
+            .into_iter()
+            .map(|trak| boxes(&find(trak, &[b"mdia", b"minf", b"stbl", b"stsd"])[8..])[0])
+            .collect();
+        let kinds: Vec<_> = sample_entries.iter().map(|(kind, _)| *kind).collect();
+        assert_eq!(vec![*b"vp09", *b"Opus"], kinds);
+
+        // The Opus config follows the 28 bytes of the audio sample entry
+        let dops = find(&sample_entries[1].1[28..], &[b"dOps"]);
+        assert_eq!(2, dops[1]);
+        assert_eq!(312, u16::from_be_bytes([dops[2], dops[3]]));
+
+        let fragments = boxes(&output)[2..].to_vec();
+        let (_, moof) = fragments[2];
+        let (_, mdat) = fragments[3];
+        let trafs = find_all(moof, b"traf");
+
+        // 33 ms frames at 90 kHz, the last one timed by the first frame of the next cluster
+        let video = trun_samples(trafs[0]);
+        assert_eq!(vec![2970; 4], video.iter().map(|s| s.0).collect::<Vec<_>>());
+        assert_eq!(SAMPLE_FLAGS_SYNC, video[0].2);
+        assert_eq!(SAMPLE_FLAGS_NON_SYNC, video[1].2);
+        let tfdt = find(trafs[0], &[b"tfdt"]);
+        assert_eq!(
+            132 * 90,
+            u64::from_be_bytes(tfdt[4..12].try_into().unwrap())
+        );
+
+        let audio = trun_samples(trafs[1]);
+        assert_eq!(
+            vec![33 * 48; 4],
+            audio.iter().map(|s| s.0).collect::<Vec<_>>()
+        );
+
+        // The data offset of the video run points at the first frame in the mdat
+        let trun = find(trafs[0], &[b"trun"]);
+        let data_offset = u32::from_be_bytes(trun[8..12].try_into().unwrap()) as usize;
+        assert_eq!(moof.len() + 8 + 8, data_offset);
+        assert_eq!(&[0u8; 16], &mdat[..16]);
+        let sample_bytes: u32 = video.iter().chain(&audio).map(|s| s.1).sum();
+        assert_eq!(sample_bytes as usize, mdat.len());
+    }
+}
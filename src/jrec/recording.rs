@@ -1,25 +1,22 @@
-use std::{cell::LazyCell, path::PathBuf, sync::Arc};
+use std::sync::Arc;
 
 use chrono::Local;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tracing::info;
 use typed_builder::TypedBuilder;
 
-use crate::utils::recording_manager::RecordingManager;
-
-pub const RECORDING_DIR: LazyCell<Arc<PathBuf>> = LazyCell::new(|| {
-    let home_dir = dirs::home_dir().expect("home directory");
-    let recording_dir = home_dir
-        .join("code")
-        .join("webm-streamer")
-        .join("recordings");
-    Arc::new(recording_dir)
-});
+use crate::utils::recording_manager::{RecordingManager, RecordingOptions};
 
 #[derive(TypedBuilder)]
 pub struct ClientPush<S> {
     client_stream: S,
     recording_manager: Arc<RecordingManager>,
+    /// Stored in the sidecar, retention counts recordings per group
+    #[builder(default)]
+    group: Option<String>,
+    /// Session token of a push session this connection resumes, the recording it goes on with
+    #[builder(default)]
+    resume: Option<String>,
 }
 
 impl<S> ClientPush<S>
@@ -31,15 +28,31 @@ where
         let Self {
             client_stream,
             recording_manager,
+            group,
+            resume,
         } = self;
+
+        if let Some(session_token) = resume {
+            // The session that was waiting for the client reads from this connection from now on
+            let recording_file = recording_manager
+                .resume_recording(&session_token, client_stream)
+                .await?;
+            info!("Resumed recording to file: {:?}", recording_file);
+            return Ok(());
+        }
+
+        let options = RecordingOptions::builder()
+            .group(group)
+            .rotation(recording_manager.config().rotation.clone())
+            .build();
         let date = Local::now();
         let recording_file_name = format!("{}.webm", date.format("%d_%H_%M_%S"));
-        let recording_file = RECORDING_DIR.as_ref().join(&recording_file_name);
+        let recording_file = recording_manager.recording_dir().join(&recording_file_name);
 
         info!("Recording to file: {:?}", recording_file);
 
         recording_manager
-            .start_recording(recording_file.clone(), client_stream)
+            .start_recording(recording_file.clone(), options, client_stream)
             .await?
             .await?
     }
@@ -4,39 +4,34 @@ use std::{
     task::{Context, Poll},
 };
 
-use futures::FutureExt;
-use tokio::{
-    fs,
-    io::{self, AsyncRead},
-    time::Sleep,
-};
+use futures::{future::BoxFuture, FutureExt};
+use tokio::io::{self, AsyncRead};
 use tracing::{error, info};
-use winapi::um::winnt::{FILE_SHARE_READ, FILE_SHARE_WRITE};
 
-use crate::axum_range::{AsyncSeekStart, RangeBody};
+use crate::{
+    axum_range::{AsyncSeekStart, RangeBody},
+    utils::{file::open_read, growing_file::GrowingFile},
+};
 
 // Candidate for streaming the webm file
 pub struct FileReaderCandidate {
     file: tokio::fs::File,
     size: u64,
-    sleep: Option<Pin<Box<Sleep>>>,
+    /// Set while the file is still being recorded, moved into `changed` while waiting
+    progress: Option<GrowingFile>,
+    changed: Option<BoxFuture<'static, GrowingFile>>,
 }
 
 impl FileReaderCandidate {
-    pub async fn open(path: PathBuf) -> std::io::Result<Self> {
-        let mut open_options = fs::OpenOptions::new();
-
-        let file = open_options
-            .read(true)
-            .share_mode(FILE_SHARE_WRITE | FILE_SHARE_READ)
-            .open(&path)
-            .await?;
+    pub async fn open(path: PathBuf, progress: Option<GrowingFile>) -> std::io::Result<Self> {
+        let file = open_read(&path).await?;
 
         let size = file.metadata().await?.len();
         Ok(FileReaderCandidate {
             file,
             size,
-            sleep: None,
+            progress,
+            changed: None,
         })
     }
 }
@@ -60,10 +55,11 @@ impl AsyncRead for FileReaderCandidate {
         cx: &mut std::task::Context<'_>,
         buf: &mut tokio::io::ReadBuf<'_>,
     ) -> std::task::Poll<std::io::Result<()>> {
-        if let Some(sleep) = self.sleep.as_mut() {
-            match sleep.as_mut().poll_unpin(cx) {
-                Poll::Ready(()) => {
-                    self.sleep = None;
+        if let Some(changed) = self.changed.as_mut() {
+            match changed.poll_unpin(cx) {
+                Poll::Ready(progress) => {
+                    self.changed = None;
+                    self.progress = Some(progress);
                 }
                 Poll::Pending => {
                     return Poll::Pending;
@@ -87,14 +83,28 @@ impl AsyncRead for FileReaderCandidate {
                     || buf.filled().len() == lenth_before_fill
                     || buf.filled().len() < MAX_SIZE_PER_REQUEST as usize
                 {
-                    info!("Sleeping for 1 second, waiting for more data");
-                    let sleep = tokio::time::sleep(std::time::Duration::from_secs(1));
-                    let mut sleep = Box::pin(sleep);
-                    let poll_sleep = sleep.poll_unpin(cx);
-                    self.sleep = Some(sleep);
-
-                    if !poll_sleep.is_pending() {
-                        panic!("Sleep should always be pending");
+                    let Some(mut progress) = self
+                        .progress
+                        .take()
+                        .filter(|progress| !progress.progress().finished)
+                    else {
+                        // Nothing more is coming
+                        return Poll::Ready(Ok(()));
+                    };
+
+                    info!("Waiting for more data");
+                    let mut changed = async move {
+                        progress.changed().await;
+                        progress
+                    }
+                    .boxed();
+                    match changed.poll_unpin(cx) {
+                        Poll::Ready(progress) => {
+                            // Written while we were reading, try again right away
+                            self.progress = Some(progress);
+                            cx.waker().wake_by_ref();
+                        }
+                        Poll::Pending => self.changed = Some(changed),
                     }
 
                     Poll::Pending
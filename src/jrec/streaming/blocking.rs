@@ -1,6 +1,6 @@
-use std::{io, os::windows::fs::OpenOptionsExt};
+use std::io;
 
-use winapi::um::winnt::{FILE_SHARE_READ, FILE_SHARE_WRITE};
+use crate::utils::file::{std_open_read, std_open_write};
 
 use super::StreamFile;
 
@@ -11,19 +11,13 @@ pub struct StdStreamingFile {
 
 impl StdStreamingFile {
     pub fn open_read(path: std::path::PathBuf) -> io::Result<Self> {
-        let file = std::fs::OpenOptions::new()
-            .read(true)
-            .share_mode(FILE_SHARE_WRITE | FILE_SHARE_READ)
-            .open(&path)?;
+        let file = std_open_read(&path)?;
 
         Ok(StdStreamingFile { inner: file, path })
     }
 
     pub fn open_write(path: std::path::PathBuf) -> io::Result<Self> {
-        let file = std::fs::OpenOptions::new()
-            .write(true)
-            .share_mode(FILE_SHARE_WRITE | FILE_SHARE_READ)
-            .open(&path)?;
+        let file = std_open_write(&path)?;
 
         Ok(StdStreamingFile { inner: file, path })
     }
@@ -4,12 +4,16 @@ use axum::extract::ws::WebSocket;
 use blocking::StdStreamingFile;
 use bytes::{BufMut, BytesMut};
 use futures::{SinkExt, StreamExt, TryStreamExt};
+use prometheus_client::metrics::counter::Counter;
 use tokio::io::{self, AsyncReadExt, AsyncSeekExt};
 use tokio_util::codec::{Decoder, Encoder, Framed};
-use tracing::{debug, error, info};
-use winapi::um::winnt::{FILE_SHARE_READ, FILE_SHARE_WRITE};
+use tracing::{debug, error, info, warn};
 
-use crate::utils::recording_manager::RecordingManager;
+use crate::utils::{
+    file::open_read,
+    metrics::{Metrics, Transport},
+    recording_manager::{RecordingManager, ViewGuard},
+};
 
 use super::ws::websocket_compat;
 
@@ -20,12 +24,16 @@ pub mod std_stream;
 #[derive(Debug, Clone, serde::Deserialize)]
 pub enum ClientRequest {
     Pull { size: Option<usize> },
+    Seek { time_ms: u64 },
+    SeekBytes { offset: u64 },
     Stop,
 }
 
 #[derive(Debug, Clone, serde::Serialize)]
 pub struct Metadata {
     pub chunk_size: usize,
+    /// Where in the file the next Pull reads from: right after the data of a Pull, the cluster seeked to
+    /// after the header a Seek sends, the offset asked for by a SeekBytes
     pub offset: usize,
     total_size: usize,
 }
@@ -68,20 +76,11 @@ pub struct StreamFile {
 
 impl StreamFile {
     pub async fn open_read(path: std::path::PathBuf) -> io::Result<Self> {
-        let file = tokio::fs::OpenOptions::new()
-            .read(true)
-            .share_mode(FILE_SHARE_WRITE | FILE_SHARE_READ)
-            .open(&path)
-            .await?;
+        let file = open_read(&path).await?;
 
         Ok(StreamFile { inner: file, path })
     }
 
-    pub async fn open_from(old: &mut Self) -> io::Result<Self> {
-        let path = old.path.clone();
-        Self::open_read(path).await
-    }
-
     pub(crate) fn from_std(file: StdStreamingFile) -> Self {
         let (inner, path) = file.destruct();
 
@@ -92,13 +91,15 @@ impl StreamFile {
     }
 }
 
+/// `view` is held on the recording `file` belongs to, `file` may be one of its segments.
 pub async fn test_stream(
     file: std::path::PathBuf,
     ws: WebSocket,
     recording_manager: Arc<RecordingManager>,
+    view: ViewGuard,
 ) {
     let ws = websocket_compat(ws);
-    let ws_frame = Framed::new(ws, SimpleCodec);
+    let ws_frame = Framed::new(ws, SimpleCodec::new(recording_manager.metrics()));
 
     // we should have a rwlock here that keep reading the file and write it to a temp file, use the temp file to stream
     let source_file = StreamFile::open_read(file.clone())
@@ -106,6 +107,7 @@ pub async fn test_stream(
         .expect("open file");
 
     tokio::spawn(async move {
+        let _view = view;
         if let Err(e) = handle_request(source_file, ws_frame, recording_manager).await {
             error!("Error handling request: {:?}", e);
         }
@@ -119,6 +121,8 @@ async fn handle_request(
 ) -> anyhow::Result<()> {
     let mut ws_frame = ws_frame;
 
+    // Never opened again by path: finalization replaces the file with one where every offset has moved,
+    // the handle keeps reading the recording the positions were taken in
     let mut seek_position = file.inner.seek(io::SeekFrom::Start(0)).await?;
     loop {
         let Some(request) = ws_frame.next().await else {
@@ -141,16 +145,18 @@ async fn handle_request(
                 return Ok(());
             }
             ClientRequest::Pull { size } => 'pull_loop: loop {
-                file = reopen(file, seek_position).await?;
+                file.inner.seek(io::SeekFrom::Start(seek_position)).await?;
                 let size = size.unwrap_or(1024);
                 let mut buf = vec![0; size];
                 let n = file.inner.read(&mut buf).await?;
                 debug!(data_size = n, "Read data from file");
                 if n == 0 {
-                    if recording_manager.is_recording(&file.path).await {
+                    if let Some(mut progress) = recording_manager.subscribe(&file.path).await {
                         info!("Recording is still in progress, waiting for more data");
-                        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-                        continue 'pull_loop;
+                        let progress = progress.wait_for_data(seek_position).await;
+                        if !progress.finished || progress.bytes_written > seek_position {
+                            continue 'pull_loop;
+                        }
                     }
 
                     let response = ServerResponse::EOF;
@@ -158,32 +164,95 @@ async fn handle_request(
                     ws_frame.send(response).await?;
                 }
 
+                seek_position = file.inner.seek(io::SeekFrom::Current(0)).await?;
                 let response = ServerResponse::Chunk {
                     metadata: Some(Metadata {
                         chunk_size: n,
-                        offset: file.inner.seek(io::SeekFrom::Current(0)).await? as usize,
+                        offset: seek_position as usize,
                         total_size: file.inner.metadata().await?.len() as usize,
                     }),
                     data: buf[..n].to_vec(),
                 };
 
-                seek_position = file.inner.seek(io::SeekFrom::Current(0)).await?;
-
                 info!(data_size = n, "Sending response");
                 ws_frame.send(response).await?;
                 break 'pull_loop;
             },
+            ClientRequest::Seek { time_ms } => {
+                let handle = file.inner.try_clone().await?.into_std().await;
+                // An active recording before its first cluster does not even index
+                let indexed = recording_manager
+                    .index_file(&file.path, handle)
+                    .await
+                    .inspect_err(|e| warn!(?e, "Failed to index recording"))
+                    .ok();
+                let Some((indexed, target)) = indexed.and_then(|indexed| {
+                    let target = *indexed.index.seek_target(time_ms)?;
+                    Some((indexed, target))
+                }) else {
+                    // Before the first keyframe of an active recording, the client may try again later
+                    warn!(time_ms, "No keyframe cluster to seek to, staying in place");
+                    let response = ServerResponse::Chunk {
+                        metadata: Some(Metadata {
+                            chunk_size: 0,
+                            offset: seek_position as usize,
+                            total_size: file.inner.metadata().await?.len() as usize,
+                        }),
+                        data: Vec::new(),
+                    };
+                    ws_frame.send(response).await?;
+                    continue;
+                };
+                info!(time_ms, target = ?target, "Seeking");
+
+                // The player resets its decoder on seek, so it needs the header again before the cluster
+                let mut header = vec![0; indexed.index.header_end as usize];
+                let mut indexed_file = tokio::fs::File::from_std(indexed.file);
+                indexed_file.seek(io::SeekFrom::Start(0)).await?;
+                indexed_file.read_exact(&mut header).await?;
+                seek_position = target.offset;
+
+                let response = ServerResponse::Chunk {
+                    metadata: Some(Metadata {
+                        chunk_size: header.len(),
+                        offset: seek_position as usize,
+                        total_size: indexed.len as usize,
+                    }),
+                    data: header,
+                };
+                ws_frame.send(response).await?;
+            }
+            ClientRequest::SeekBytes { offset } => {
+                let total_size = file.inner.metadata().await?.len();
+                seek_position = offset.min(total_size);
+                info!(offset, seek_position, "Seeking to byte offset");
+
+                let response = ServerResponse::Chunk {
+                    metadata: Some(Metadata {
+                        chunk_size: 0,
+                        offset: seek_position as usize,
+                        total_size: total_size as usize,
+                    }),
+                    data: Vec::new(),
+                };
+                ws_frame.send(response).await?;
+            }
         }
     }
 }
 
-async fn reopen(mut file: StreamFile, seek_position: u64) -> tokio::io::Result<StreamFile> {
-    let mut file = StreamFile::open_from(&mut file).await?;
-    file.inner.seek(io::SeekFrom::Start(seek_position)).await?;
-    Ok(file)
+pub struct SimpleCodec {
+    /// Bytes of recordings sent
+    served: Counter,
 }
 
-pub struct SimpleCodec;
+impl SimpleCodec {
+    pub fn new(metrics: &Metrics) -> Self {
+        Self {
+            served: metrics.served_counter(Transport::Websocket),
+        }
+    }
+}
 
 impl Decoder for SimpleCodec {
     type Item = ClientRequest;
@@ -226,9 +295,100 @@ impl Encoder<ServerResponse> for SimpleCodec {
                 }
 
                 dst.put_slice(&data);
+                self.served.inc_by(data.len() as u64);
             }
         };
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncWriteExt, DuplexStream};
+
+    use crate::{config::ServerConfig, jrec::webm::fixture};
+
+    use super::*;
+
+    /// The client end of a pull session on `recording`.
+    async fn pull_session(recording: &[u8]) -> (tempfile::TempDir, DuplexStream) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("01_10_00_00.webm");
+        std::fs::write(&path, recording).unwrap();
+        let recording_manager = RecordingManager::new(Arc::new(ServerConfig {
+            recording_dir: dir.path().to_path_buf(),
+            ..Default::default()
+        }));
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let file = StreamFile::open_read(path).await.unwrap();
+        let codec = SimpleCodec::new(recording_manager.metrics());
+        tokio::spawn(handle_request(
+            file,
+            Framed::new(server, codec),
+            recording_manager,
+        ));
+
+        (dir, client)
+    }
+
+    /// Sends `request` and reads the chunk it is answered with, as encoded by [`SimpleCodec`].
+    async fn request(client: &mut DuplexStream, request: &str) -> (serde_json::Value, Vec<u8>) {
+        client.write_all(request.as_bytes()).await.unwrap();
+
+        assert_eq!(0, client.read_u8().await.unwrap(), "not a chunk");
+        let mut metadata = vec![0; client.read_u32().await.unwrap() as usize];
+        client.read_exact(&mut metadata).await.unwrap();
+        let metadata: serde_json::Value = serde_json::from_slice(&metadata).unwrap();
+        let mut data = vec![0; metadata["chunk_size"].as_u64().unwrap() as usize];
+        client.read_exact(&mut data).await.unwrap();
+
+        (metadata, data)
+    }
+
+    #[tokio::test]
+    async fn test_seek_then_pull_from_the_offset_reported() {
+        let recording = fixture::recording(3, 4);
+        let (_dir, mut client) = pull_session(&recording).await;
+
+        let cluster_duration = 4 * fixture::FRAME_DURATION_MS;
+        let (metadata, header) = request(
+            &mut client,
+            &format!(r#"{{"Seek":{{"time_ms":{}}}}}"#, cluster_duration + 1),
+        )
+        .await;
+        assert_eq!(fixture::header(), header);
+        let cluster = metadata["offset"].as_u64().unwrap() as usize;
+        assert!(cluster > header.len());
+
+        let (metadata, data) = request(&mut client, r#"{"Pull":{"size":16}}"#).await;
+        assert_eq!(recording[cluster..cluster + 16], data);
+        assert_eq!(cluster + 16, metadata["offset"].as_u64().unwrap() as usize);
+
+        let (metadata, data) = request(&mut client, r#"{"SeekBytes":{"offset":5}}"#).await;
+        assert!(data.is_empty());
+        assert_eq!(5, metadata["offset"]);
+
+        let (metadata, data) = request(&mut client, r#"{"Pull":{"size":16}}"#).await;
+        assert_eq!(recording[5..21], data);
+        assert_eq!(21, metadata["offset"]);
+    }
+
+    #[tokio::test]
+    async fn test_seek_without_keyframe_keeps_the_session() {
+        let header = fixture::header();
+        let (_dir, mut client) = pull_session(&header).await;
+
+        let (metadata, data) = request(&mut client, r#"{"Pull":{"size":4}}"#).await;
+        assert_eq!(header[..4], data);
+
+        let (metadata_after_seek, data) =
+            request(&mut client, r#"{"Seek":{"time_ms":1000}}"#).await;
+        assert!(data.is_empty());
+        assert_eq!(metadata["offset"], metadata_after_seek["offset"]);
+
+        let (_, data) = request(&mut client, r#"{"Pull":{"size":4}}"#).await;
+        assert_eq!(header[4..8], data);
+    }
+}
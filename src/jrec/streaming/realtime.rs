@@ -7,7 +7,7 @@ use tracing::{error, info, warn};
 
 use crate::{
     jrec::{streaming::std_stream::{AsyncBufferReader, StdStream}, ws::websocket_compat},
-    utils::state::AppState,
+    utils::{file::open_read, state::AppState},
 };
 
 use super::SimpleCodec;
@@ -18,12 +18,13 @@ pub async fn handle_realtime_stream(
     state: AppState,
 ) {
     let recording_manager = state.recording_manager();
+    let view = recording_manager.open_view(&file);
     let mut stream_read = match recording_manager.start_streaming(&file).await {
         Ok(stream_read) => stream_read,
         Err(e) => {
             warn!("Not streaming, read as file, error: {:?}", e);
             let open_file = tokio::task::spawn(async move {
-                let file = tokio::fs::File::open(&file).await?;
+                let file = open_read(&file).await?;
                 let stream = AsyncBufferReader::from_file(file).await?;
                 Ok::<_, anyhow::Error>(stream)
             });
@@ -42,9 +43,13 @@ pub async fn handle_realtime_stream(
         }
     };
 
-    let mut framed = Framed::new(websocket_compat(websocket), SimpleCodec);
+    let mut framed = Framed::new(
+        websocket_compat(websocket),
+        SimpleCodec::new(recording_manager.metrics()),
+    );
 
     tokio::spawn(async move {
+        let _view = view;
         {
             info!("Starting realtime stream");
             loop {
@@ -63,9 +68,18 @@ pub async fn handle_realtime_stream(
                         tracing::info!("Stopping stream");
                         break;
                     }
+                    super::ClientRequest::Seek { .. } | super::ClientRequest::SeekBytes { .. } => {
+                        warn!(?request, "Seeking is not supported on a realtime stream");
+                        continue;
+                    }
                 };
                 let mut buffer = vec![0; size];
                 let data = stream_read.read(&mut buffer).await?;
+                if data == 0 {
+                    info!("Stream ended, sending EOF");
+                    framed.send(super::ServerResponse::EOF).await?;
+                    break;
+                }
 
                 framed
                     .send(super::ServerResponse::Chunk {
@@ -6,6 +6,7 @@ use std::{
     task::{Context, Poll},
 };
 
+use bytes::{Buf, Bytes};
 use futures::FutureExt;
 use tokio::{
     io::{AsyncReadExt, ReadBuf},
@@ -137,7 +138,7 @@ impl StdStream {
         };
 
         let reader = AsyncBufferReader {
-            buffer: self.read_buffer,
+            buffer: self.read_buffer.into(),
             receiver,
         };
 
@@ -148,7 +149,7 @@ impl StdStream {
 // Implement the BufferWriter
 pub struct BufferWriter {
     buffer: Vec<u8>,
-    sender: mpsc::Sender<Vec<u8>>,
+    sender: mpsc::Sender<Bytes>,
 }
 
 impl std::io::Write for BufferWriter {
@@ -158,19 +159,18 @@ impl std::io::Write for BufferWriter {
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        self.sender.try_send(self.buffer.clone()).map_err(|_| {
+        let data = Bytes::from(std::mem::take(&mut self.buffer));
+        self.sender.try_send(data).map_err(|_| {
             std::io::Error::new(std::io::ErrorKind::Other, "Failed to send data to reader")
         })?;
 
-        self.buffer.clear();
-
         Ok(())
     }
 }
 #[derive(Debug)]
 pub struct AsyncBufferReader {
-    buffer: Vec<u8>,
-    receiver: mpsc::Receiver<Vec<u8>>,
+    buffer: Bytes,
+    receiver: mpsc::Receiver<Bytes>,
 }
 
 impl tokio::io::AsyncRead for AsyncBufferReader {
@@ -179,32 +179,43 @@ impl tokio::io::AsyncRead for AsyncBufferReader {
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<tokio::io::Result<()>> {
-        // Poll the receiver for any available data
-        match Pin::new(&mut self.receiver).poll_recv(cx) {
-            Poll::Ready(Some(data)) => {
-                self.buffer.extend_from_slice(&data);
-            }
-            _ => {}
-        };
-
-        if self.buffer.is_empty() {
-            return Poll::Pending;
-        } else {
-            let len = std::cmp::min(buf.remaining(), self.buffer.len());
-            buf.put_slice(&self.buffer[..len]);
-            self.buffer.drain(..len);
-            return Poll::Ready(Ok(()));
+        while self.buffer.is_empty() {
+            // Poll the receiver for any available data
+            match Pin::new(&mut self.receiver).poll_recv(cx) {
+                Poll::Ready(Some(data)) => {
+                    self.buffer = data;
+                }
+                // Every writer is gone and the buffer is drained, this is the end of the stream
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            };
         }
+
+        let len = std::cmp::min(buf.remaining(), self.buffer.len());
+        buf.put_slice(&self.buffer[..len]);
+        self.buffer.advance(len);
+        Poll::Ready(Ok(()))
     }
 }
 
 impl AsyncBufferReader {
+    /// A reader fed through the returned sender, it reaches its end once every sender is dropped.
+    pub fn channel(capacity: usize) -> (mpsc::Sender<Bytes>, Self) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let reader = Self {
+            buffer: Bytes::new(),
+            receiver,
+        };
+
+        (sender, reader)
+    }
+
     pub async fn from_file(file: tokio::fs::File) -> std::io::Result<Self> {
         let mut buffer = Vec::new();
         let mut file = tokio::io::BufReader::new(file);
         file.read_to_end(&mut buffer).await?;
         Ok(Self {
-            buffer,
+            buffer: buffer.into(),
             receiver: mpsc::channel(1024).1,
         })
     }
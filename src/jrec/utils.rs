@@ -1,16 +1,25 @@
-use std::path::PathBuf;
+use std::{
+    cmp::Reverse,
+    path::{Path, PathBuf},
+};
 
+use chrono::{DateTime, Utc};
 use hyper::StatusCode;
 
-use super::recording::RECORDING_DIR;
+use crate::utils::file::created_or_modified;
 
-pub async fn get_latestest_recording() -> Result<PathBuf, StatusCode> {
-    let mut recordings = tokio::fs::read_dir(RECORDING_DIR.as_ref())
-        .await
-        .map_err(|e| {
-            tracing::error!("Error reading recording directory: {:?}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+use super::metadata::{is_segment_file, RecordingMetadata, RecordingState};
+
+/// Sidecars, temporary files and the later segments of rotated recordings live next to the recordings.
+fn is_recording_file(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "webm") && !is_segment_file(path)
+}
+
+pub async fn get_latestest_recording(recording_dir: &Path) -> Result<PathBuf, StatusCode> {
+    let mut recordings = tokio::fs::read_dir(recording_dir).await.map_err(|e| {
+        tracing::error!("Error reading recording directory: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
 
     let mut latest_recording = None;
 
@@ -20,13 +29,11 @@ pub async fn get_latestest_recording() -> Result<PathBuf, StatusCode> {
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
 
-        if !metadata.is_file() {
+        if !metadata.is_file() || !is_recording_file(&recording.path()) {
             continue;
         }
 
-        let time = metadata
-            .created()
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let time = created_or_modified(&metadata).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
         if let Some((latest_time, _)) = latest_recording {
             if time > latest_time {
@@ -42,13 +49,12 @@ pub async fn get_latestest_recording() -> Result<PathBuf, StatusCode> {
     Ok(recording.path())
 }
 
-pub async fn get_recording_list() -> Result<Vec<(String, String)>, StatusCode> {
-    let mut recordings = tokio::fs::read_dir(RECORDING_DIR.as_ref())
-        .await
-        .map_err(|e| {
-            tracing::error!("Error reading recording directory: {:?}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+/// Every recording in the directory, from its sidecar or synthesized for older recordings.
+pub async fn list_recordings(recording_dir: &Path) -> Result<Vec<RecordingMetadata>, StatusCode> {
+    let mut recordings = tokio::fs::read_dir(recording_dir).await.map_err(|e| {
+        tracing::error!("Error reading recording directory: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
 
     let mut recording_list = Vec::new();
 
@@ -58,55 +64,103 @@ pub async fn get_recording_list() -> Result<Vec<(String, String)>, StatusCode> {
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
 
-        if !metadata.is_file() {
+        let path = recording.path();
+        if !metadata.is_file() || !is_recording_file(&path) {
             continue;
         }
 
-        let time = metadata
-            .created()
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-        let time: u64 = time
-            .duration_since(std::time::SystemTime::UNIX_EPOCH)
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-            .as_secs();
+        let sidecar = RecordingMetadata::load(&path).await.unwrap_or_else(|e| {
+            tracing::warn!(?e, "Ignoring unreadable sidecar");
+            None
+        });
+        let entry = match sidecar {
+            Some(mut entry) => {
+                if entry.state == RecordingState::Active {
+                    entry.byte_size = metadata.len();
+                }
+                entry
+            }
+            None => RecordingMetadata::synthesize(&path, &metadata)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        };
 
-        let name = recording.file_name().to_string_lossy().to_string();
+        recording_list.push(entry);
+    }
 
-        if !name.ends_with(".webm") {
-            continue;
-        }
+    Ok(recording_list)
+}
 
-        recording_list.push((name, time));
-    }
+#[derive(Debug, Clone, Copy, Default, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Newest,
+    Oldest,
+    Longest,
+    Largest,
+}
 
-    //sort the recording list by time, newest first
-    recording_list.sort_by(|a, b| b.1.cmp(&a.1));
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default)]
+pub struct ListQuery {
+    pub offset: usize,
+    /// `limits.max_listed_recordings` when not given
+    pub limit: Option<usize>,
+    pub state: Option<RecordingState>,
+    /// Only recordings started at or after this time
+    pub from: Option<DateTime<Utc>>,
+    /// Only recordings started before this time
+    pub to: Option<DateTime<Utc>>,
+    pub sort: SortOrder,
+    /// Only this recording, tokens good for one recording can only list that one
+    pub recording: Option<String>,
+}
 
+#[derive(Debug, serde::Serialize)]
+pub struct RecordingPage {
+    /// Recordings matching the filters, across all pages
+    pub total: usize,
+    pub offset: usize,
+    pub items: Vec<RecordingMetadata>,
+}
 
-    // tranclate the list to only the nearest 10 recordings
+impl ListQuery {
+    pub fn apply(&self, mut recordings: Vec<RecordingMetadata>, limit: usize) -> RecordingPage {
+        recordings.retain(|recording| {
+            self.recording
+                .as_ref()
+                .is_none_or(|file_name| recording.file_name == *file_name)
+                && self.state.is_none_or(|state| recording.state == state)
+                && self.from.is_none_or(|from| recording.started_at >= from)
+                && self.to.is_none_or(|to| recording.started_at < to)
+        });
 
-    let recording_list = recording_list
-        .into_iter()
-        .take(10)
-        .map(|(name, time)| {
-            let time = chrono::DateTime::from_timestamp(time as i64, 0).unwrap();
-            // make the time local
-            let time = time.with_timezone(&chrono::Local);
-            (name, time.to_string())
-        })
-        .collect();
+        match self.sort {
+            SortOrder::Newest => recordings.sort_by_key(|recording| Reverse(recording.started_at)),
+            SortOrder::Oldest => recordings.sort_by_key(|recording| recording.started_at),
+            SortOrder::Longest => {
+                recordings.sort_by_key(|recording| Reverse(recording.duration_ms))
+            }
+            SortOrder::Largest => recordings.sort_by_key(|recording| Reverse(recording.byte_size)),
+        }
 
-    Ok(recording_list)
+        RecordingPage {
+            total: recordings.len(),
+            offset: self.offset,
+            items: recordings
+                .into_iter()
+                .skip(self.offset)
+                .take(limit)
+                .collect(),
+        }
+    }
 }
 
-pub async fn find_recording(file_name: &str) -> Result<PathBuf, StatusCode> {
-    let mut recordings = tokio::fs::read_dir(RECORDING_DIR.as_ref())
-        .await
-        .map_err(|e| {
-            tracing::error!("Error reading recording directory: {:?}", e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        })?;
+pub async fn find_recording(recording_dir: &Path, file_name: &str) -> Result<PathBuf, StatusCode> {
+    let mut recordings = tokio::fs::read_dir(recording_dir).await.map_err(|e| {
+        tracing::error!("Error reading recording directory: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
 
     while let Ok(Some(recording)) = recordings.next_entry().await {
         let metadata = recording.metadata().await.map_err(|e| {
@@ -129,3 +183,128 @@ pub async fn find_recording(file_name: &str) -> Result<PathBuf, StatusCode> {
     // If no matching file is found, return a 404 status
     Err(StatusCode::NOT_FOUND)
 }
+
+/// The file to serve as a whole. A recording rotated into several segments cannot be served as one file,
+/// it is rejected unless `segment` picks one of them.
+pub async fn segment_file(
+    recording_path: PathBuf,
+    segment: Option<usize>,
+) -> Result<PathBuf, StatusCode> {
+    let metadata = RecordingMetadata::load(&recording_path)
+        .await
+        .map_err(|e| {
+            tracing::error!("Error reading recording sidecar: {:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let mut segment_paths = match metadata {
+        Some(metadata) => metadata.segment_paths(&recording_path),
+        None => vec![recording_path],
+    };
+
+    match segment {
+        Some(segment) if segment < segment_paths.len() => Ok(segment_paths.swap_remove(segment)),
+        Some(_) => Err(StatusCode::NOT_FOUND),
+        None if segment_paths.len() > 1 => {
+            tracing::warn!(
+                ?segment_paths,
+                "Recording was rotated, one of its segments must be asked for"
+            );
+            Err(StatusCode::CONFLICT)
+        }
+        None => Ok(segment_paths.swap_remove(0)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use chrono::TimeZone;
+
+    use crate::jrec::metadata::RecordingSegment;
+
+    use super::*;
+
+    fn recording(
+        name: &str,
+        hour: u32,
+        state: RecordingState,
+        byte_size: u64,
+    ) -> RecordingMetadata {
+        let mut recording = RecordingMetadata::new(Path::new(name));
+        recording.started_at = Utc.with_ymd_and_hms(2024, 10, 1, hour, 0, 0).unwrap();
+        recording.state = state;
+        recording.byte_size = byte_size;
+        recording
+    }
+
+    #[test]
+    fn test_filter_sort_and_paginate() {
+        let recordings = vec![
+            recording("a.webm", 8, RecordingState::Finished, 300),
+            recording("b.webm", 9, RecordingState::Corrupt, 100),
+            recording("c.webm", 10, RecordingState::Finished, 200),
+            recording("d.webm", 11, RecordingState::Finished, 400),
+            recording("e.webm", 12, RecordingState::Active, 50),
+        ];
+
+        let query = ListQuery {
+            offset: 1,
+            state: Some(RecordingState::Finished),
+            to: Some(Utc.with_ymd_and_hms(2024, 10, 1, 11, 0, 0).unwrap()),
+            ..Default::default()
+        };
+        let page = query.apply(recordings.clone(), 10);
+        assert_eq!(2, page.total);
+        let names: Vec<_> = page.items.iter().map(|r| r.file_name.as_str()).collect();
+        assert_eq!(vec!["a.webm"], names);
+
+        let query = ListQuery {
+            sort: SortOrder::Largest,
+            ..Default::default()
+        };
+        let page = query.apply(recordings.clone(), 2);
+        assert_eq!(5, page.total);
+        let names: Vec<_> = page.items.iter().map(|r| r.file_name.as_str()).collect();
+        assert_eq!(vec!["d.webm", "a.webm"], names);
+
+        let query = ListQuery {
+            recording: Some("b.webm".to_string()),
+            ..Default::default()
+        };
+        let page = query.apply(recordings, 10);
+        let names: Vec<_> = page.items.iter().map(|r| r.file_name.as_str()).collect();
+        assert_eq!(vec!["b.webm"], names);
+    }
+
+    #[tokio::test]
+    async fn test_rotated_recording_is_served_one_segment_at_a_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.webm");
+        let mut metadata = RecordingMetadata::new(&path);
+        metadata.save(&path).await.unwrap();
+        assert_eq!(path, segment_file(path.clone(), None).await.unwrap());
+
+        metadata.segments = [("a.webm", 0), ("a.002.webm", 1000)]
+            .map(|(file_name, start_timestamp)| RecordingSegment {
+                file_name: file_name.to_string(),
+                start_timestamp,
+                byte_size: 0,
+                duration_ms: None,
+            })
+            .to_vec();
+        metadata.save(&path).await.unwrap();
+        assert_eq!(
+            Err(StatusCode::CONFLICT),
+            segment_file(path.clone(), None).await
+        );
+        assert_eq!(
+            dir.path().join("a.002.webm"),
+            segment_file(path.clone(), Some(1)).await.unwrap()
+        );
+        assert_eq!(
+            Err(StatusCode::NOT_FOUND),
+            segment_file(path, Some(2)).await
+        );
+    }
+}
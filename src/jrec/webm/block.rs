@@ -0,0 +1,123 @@
+use webm_iterable::matroska_spec::MatroskaSpec;
+
+/// The header every (Simple)Block payload starts with: track number, timestamp relative to the cluster and flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockHeader {
+    pub track: u64,
+    pub relative_timestamp: i16,
+    pub flags: u8,
+    /// Number of bytes taken by the header, the frame data starts right after
+    pub len: usize,
+}
+
+impl BlockHeader {
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let (track, track_len) = read_vint(data)?;
+        let rest = data.get(track_len..track_len + 3)?;
+
+        Some(Self {
+            track,
+            relative_timestamp: i16::from_be_bytes([rest[0], rest[1]]),
+            flags: rest[2],
+            len: track_len + 3,
+        })
+    }
+
+    pub fn from_tag(tag: &MatroskaSpec) -> Option<Self> {
+        match tag {
+            MatroskaSpec::SimpleBlock(data) | MatroskaSpec::Block(data) => Self::parse(data),
+            _ => None,
+        }
+    }
+
+    /// Only meaningful for SimpleBlocks, a Block inside a BlockGroup is a keyframe when it has no ReferenceBlock.
+    pub fn is_keyframe(&self) -> bool {
+        self.flags & 0x80 != 0
+    }
+
+    pub fn is_laced(&self) -> bool {
+        self.flags & 0x06 != 0
+    }
+
+    /// Absolute timestamp of the block, in TimestampScale units.
+    pub fn timestamp(&self, cluster_timestamp: u64) -> u64 {
+        cluster_timestamp.saturating_add_signed(self.relative_timestamp as i64)
+    }
+}
+
+/// Whether the first SimpleBlock of `track` in the cluster is a keyframe, of any track when `track` is None.
+/// Players can only start decoding at such a cluster.
+pub fn starts_with_keyframe(cluster: &[MatroskaSpec], track: Option<u64>) -> bool {
+    cluster
+        .iter()
+        .filter(|tag| matches!(tag, MatroskaSpec::SimpleBlock(_)))
+        .filter_map(BlockHeader::from_tag)
+        .find(|header| track.is_none_or(|track| track == header.track))
+        .is_some_and(|header| header.is_keyframe())
+}
+
+/// Reads an EBML variable size integer, returning the value with the length marker removed and the number of bytes read.
+pub fn read_vint(data: &[u8]) -> Option<(u64, usize)> {
+    let first = *data.first()?;
+    if first == 0 {
+        return None;
+    }
+
+    let len = first.leading_zeros() as usize + 1;
+    let bytes = data.get(..len)?;
+
+    let mut value = (first as u64) & (0xFF >> len);
+    for byte in &bytes[1..] {
+        value = (value << 8) | *byte as u64;
+    }
+
+    Some((value, len))
+}
+
+/// Encodes `value` as a vint of exactly `len` bytes, `len` must be large enough to hold it.
+pub fn write_vint(value: u64, len: usize) -> Vec<u8> {
+    debug_assert!((1..=8).contains(&len));
+    let mut bytes = value.to_be_bytes()[8 - len..].to_vec();
+    bytes[0] |= 0x80 >> (len - 1);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jrec::webm::fixture;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_keyframe_header() {
+        let data = [0x81, 0x00, 0x21, 0x80, 0xDE, 0xAD];
+        let header = BlockHeader::parse(&data).unwrap();
+
+        assert_eq!(1, header.track);
+        assert_eq!(33, header.relative_timestamp);
+        assert!(header.is_keyframe());
+        assert!(!header.is_laced());
+        assert_eq!(4, header.len);
+        assert_eq!(1033, header.timestamp(1000));
+    }
+
+    #[test]
+    fn test_starts_with_keyframe() {
+        let cluster = fixture::cluster_children(0, 3);
+        assert!(starts_with_keyframe(&cluster, Some(fixture::VIDEO_TRACK)));
+        assert!(!starts_with_keyframe(
+            &cluster[2..],
+            Some(fixture::VIDEO_TRACK)
+        ));
+        assert!(!starts_with_keyframe(&[], None));
+    }
+
+    #[test]
+    fn test_vint_round_trip() {
+        for (value, len) in [(1, 1), (127, 2), (0x3FFF, 3), (1 << 40, 8)] {
+            let encoded = write_vint(value, len);
+            assert_eq!(len, encoded.len());
+            assert_eq!(Some((value, len)), read_vint(&encoded));
+        }
+    }
+}
@@ -0,0 +1,454 @@
+use std::{
+    collections::HashMap,
+    io::{BufWriter, Seek, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use tracing::warn;
+use webm_iterable::{
+    errors::TagIteratorError,
+    matroska_spec::{Master, MatroskaSpec},
+    WebmIterator, WebmWriter, WriteOptions,
+};
+
+use crate::{jrec::webm::element_id, utils::file::std_open_read};
+
+use super::block::{write_vint, BlockHeader};
+
+const DEFAULT_TIMESTAMP_SCALE: u64 = 1_000_000;
+/// Segment ID and the 8 byte unknown size the writer puts after it
+const UNKNOWN_SEGMENT_HEADER_LEN: usize = 4 + 8;
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct FinalizeSummary {
+    pub duration_ms: u64,
+    pub cluster_count: usize,
+    pub cue_count: usize,
+    /// The source ended in the middle of a cluster, which was dropped
+    pub truncated: bool,
+}
+
+struct ClusterEntry {
+    /// Offset of the cluster inside the temporary cluster file
+    offset: u64,
+    timestamp: u64,
+    has_cue_keyframe: bool,
+}
+
+/// Rewrites a raw MediaRecorder capture (unknown-sized Segment and Clusters, no Duration, no Cues)
+/// into a seekable WebM: known-sized Segment, SeekHead, Info with Duration, Tracks, Clusters and Cues.
+///
+/// The file is replaced atomically, a crash halfway leaves the original untouched.
+pub fn finalize_recording(path: &Path) -> anyhow::Result<FinalizeSummary> {
+    let directory = path.parent().context("recording has no parent directory")?;
+    let source = std_open_read(path).with_context(|| format!("opening {:?}", path))?;
+
+    let mut tag_iterator = WebmIterator::new(
+        source,
+        &[
+            MatroskaSpec::Ebml(Master::Start),
+            MatroskaSpec::SeekHead(Master::Start),
+            MatroskaSpec::Info(Master::Start),
+            MatroskaSpec::Tracks(Master::Start),
+            MatroskaSpec::Cues(Master::Start),
+            MatroskaSpec::Tags(Master::Start),
+        ],
+    );
+
+    let mut ebml = None;
+    let mut info = None;
+    let mut tracks = None;
+    let mut cue_track = None;
+
+    let mut cluster_file =
+        BufWriter::new(tempfile::tempfile_in(directory).context("creating cluster file")?);
+    let mut cluster_file_len = 0u64;
+    let mut clusters = vec![];
+    let mut current_cluster: Option<Vec<MatroskaSpec>> = None;
+    let mut current_entry = None;
+    let mut last_timestamp = 0u64;
+    // Per track, the timestamp of its last block and how long that block lasts: its BlockDuration,
+    // or as long as the block before it, like the MP4 export does
+    let mut last_blocks: HashMap<u64, (u64, u64)> = HashMap::new();
+    let mut last_block_track = None;
+    let mut truncated = false;
+
+    for tag in tag_iterator.by_ref() {
+        let tag = match tag {
+            Ok(tag) => tag,
+            Err(TagIteratorError::UnexpectedEOF { tag_start, .. }) => {
+                warn!(tag_start, "Recording ends in the middle of an element");
+                truncated = true;
+                break;
+            }
+            Err(e) if !clusters.is_empty() => {
+                warn!(?e, "Recording is corrupted, keeping what was read so far");
+                truncated = true;
+                break;
+            }
+            Err(e) => return Err(e).context("reading recording"),
+        };
+
+        match tag {
+            MatroskaSpec::Ebml(Master::Full(_)) => ebml = Some(tag),
+            MatroskaSpec::Info(Master::Full(_)) => info = Some(tag),
+            MatroskaSpec::Tracks(Master::Full(ref entries)) => {
+                cue_track = find_cue_track(entries);
+                tracks = Some(tag);
+            }
+            MatroskaSpec::Cluster(Master::Start) => {
+                current_cluster = Some(vec![tag]);
+                current_entry = Some(ClusterEntry {
+                    offset: cluster_file_len,
+                    timestamp: 0,
+                    has_cue_keyframe: false,
+                });
+            }
+            MatroskaSpec::Cluster(Master::End) => {
+                let (Some(mut cluster), Some(entry)) =
+                    (current_cluster.take(), current_entry.take())
+                else {
+                    continue;
+                };
+                cluster.push(tag);
+
+                let encoded = encode_in_segment(&cluster)?;
+                cluster_file.write_all(&encoded)?;
+                cluster_file_len += encoded.len() as u64;
+                clusters.push(entry);
+            }
+            // Top level elements the source may already contain, they are regenerated
+            MatroskaSpec::SeekHead(_)
+            | MatroskaSpec::Cues(_)
+            | MatroskaSpec::Tags(_)
+            | MatroskaSpec::Segment(_) => {}
+            MatroskaSpec::Void(_) if current_cluster.is_none() => {}
+            tag => {
+                let (Some(cluster), Some(entry)) =
+                    (current_cluster.as_mut(), current_entry.as_mut())
+                else {
+                    continue;
+                };
+
+                if let MatroskaSpec::Timestamp(timestamp) = &tag {
+                    entry.timestamp = *timestamp;
+                    last_timestamp = last_timestamp.max(*timestamp);
+                }
+
+                if let MatroskaSpec::BlockDuration(duration) = &tag {
+                    if let Some(last_block) =
+                        last_block_track.and_then(|track| last_blocks.get_mut(&track))
+                    {
+                        last_block.1 = *duration;
+                    }
+                }
+
+                if let Some(header) = BlockHeader::from_tag(&tag) {
+                    let timestamp = header.timestamp(entry.timestamp);
+                    last_timestamp = last_timestamp.max(timestamp);
+                    let duration = last_blocks
+                        .get(&header.track)
+                        .map_or(0, |(previous, _)| timestamp.saturating_sub(*previous));
+                    last_blocks.insert(header.track, (timestamp, duration));
+                    last_block_track = Some(header.track);
+                    if matches!(tag, MatroskaSpec::SimpleBlock(_))
+                        && header.is_keyframe()
+                        && cue_track.is_none_or(|track| track == header.track)
+                    {
+                        entry.has_cue_keyframe = true;
+                    }
+                }
+
+                cluster.push(tag);
+            }
+        }
+    }
+
+    if current_cluster.is_some() {
+        // The iterator stopped cleanly but never closed the last unknown-sized cluster
+        truncated = true;
+    }
+
+    let ebml = ebml.context("recording has no EBML header")?;
+    let info = info.context("recording has no Info element")?;
+    let tracks = tracks.context("recording has no Tracks element")?;
+
+    // Up to the end of the last frame, not its start
+    let end_timestamp = last_blocks
+        .values()
+        .map(|(timestamp, duration)| timestamp + duration)
+        .fold(last_timestamp, u64::max);
+    let timestamp_scale = timestamp_scale(&info);
+    let info = with_duration(info, end_timestamp as f64);
+
+    let info = encode_in_segment(&[info])?;
+    let tracks = encode_in_segment(&[tracks])?;
+
+    let has_cues = clusters.iter().any(|cluster| cluster.has_cue_keyframe);
+
+    // SeekHead positions are relative to the first byte of Segment data, and the SeekHead is that first byte,
+    // so its own size feeds back into the positions it stores. A couple of rounds are enough to settle.
+    let mut seek_head = vec![];
+    let mut seek_head_len = 0u64;
+    for _ in 0..8 {
+        let info_position = seek_head_len;
+        let tracks_position = info_position + info.len() as u64;
+        let cues_position = tracks_position + tracks.len() as u64 + cluster_file_len;
+
+        let mut entries = vec![
+            (element_id::INFO, info_position),
+            (element_id::TRACKS, tracks_position),
+        ];
+        if has_cues {
+            entries.push((element_id::CUES, cues_position));
+        }
+        seek_head = encode_in_segment(&[build_seek_head(&entries)])?;
+
+        if seek_head.len() as u64 == seek_head_len {
+            break;
+        }
+        seek_head_len = seek_head.len() as u64;
+    }
+    anyhow::ensure!(
+        seek_head.len() as u64 == seek_head_len,
+        "SeekHead size did not converge"
+    );
+
+    let first_cluster_position = seek_head_len + info.len() as u64 + tracks.len() as u64;
+    let cue_points = clusters
+        .iter()
+        .filter(|cluster| cluster.has_cue_keyframe)
+        .map(|cluster| {
+            build_cue_point(
+                cluster.timestamp,
+                cue_track.unwrap_or(1),
+                first_cluster_position + cluster.offset,
+            )
+        })
+        .collect::<Vec<_>>();
+    let cue_count = cue_points.len();
+    let cues = if !has_cues {
+        vec![]
+    } else {
+        encode_in_segment(&[MatroskaSpec::Cues(Master::Full(cue_points))])?
+    };
+
+    let segment_size = first_cluster_position + cluster_file_len + cues.len() as u64;
+
+    let mut cluster_file = cluster_file
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .context("flushing cluster file")?;
+    cluster_file.rewind()?;
+
+    let output = tempfile::NamedTempFile::new_in(directory).context("creating output file")?;
+    {
+        let mut writer = BufWriter::new(output.as_file());
+        writer.write_all(&encode(&[ebml])?)?;
+        writer.write_all(&segment_header(segment_size))?;
+        writer.write_all(&seek_head)?;
+        writer.write_all(&info)?;
+        writer.write_all(&tracks)?;
+        std::io::copy(&mut cluster_file, &mut writer).context("copying clusters")?;
+        writer.write_all(&cues)?;
+        writer.flush()?;
+    }
+    output.as_file().sync_all()?;
+
+    output
+        .persist(path)
+        .with_context(|| format!("replacing {:?}", path))?;
+
+    let summary = FinalizeSummary {
+        duration_ms: end_timestamp * timestamp_scale / 1_000_000,
+        cluster_count: clusters.len(),
+        cue_count,
+        truncated,
+    };
+
+    Ok(summary)
+}
+
+/// The track Cues point at: the first video track, or the first track when there is no video.
+pub(crate) fn find_cue_track(track_entries: &[MatroskaSpec]) -> Option<u64> {
+    let tracks = track_entries
+        .iter()
+        .filter_map(|entry| match entry {
+            MatroskaSpec::TrackEntry(Master::Full(children)) => {
+                let number = children.iter().find_map(|child| match child {
+                    MatroskaSpec::TrackNumber(number) => Some(*number),
+                    _ => None,
+                })?;
+                let track_type = children.iter().find_map(|child| match child {
+                    MatroskaSpec::TrackType(track_type) => Some(*track_type),
+                    _ => None,
+                });
+                Some((number, track_type))
+            }
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    tracks
+        .iter()
+        .find(|(_, track_type)| *track_type == Some(1))
+        .or(tracks.first())
+        .map(|(number, _)| *number)
+}
+
+pub(crate) fn timestamp_scale(info: &MatroskaSpec) -> u64 {
+    let MatroskaSpec::Info(Master::Full(children)) = info else {
+        return DEFAULT_TIMESTAMP_SCALE;
+    };
+
+    children
+        .iter()
+        .find_map(|child| match child {
+            MatroskaSpec::TimestampScale(scale) => Some(*scale),
+            _ => None,
+        })
+        .unwrap_or(DEFAULT_TIMESTAMP_SCALE)
+}
+
+fn with_duration(info: MatroskaSpec, duration: f64) -> MatroskaSpec {
+    let MatroskaSpec::Info(Master::Full(children)) = info else {
+        return info;
+    };
+
+    let mut children = children
+        .into_iter()
+        .filter(|child| !matches!(child, MatroskaSpec::Duration(_)))
+        .collect::<Vec<_>>();
+    children.push(MatroskaSpec::Duration(duration));
+
+    MatroskaSpec::Info(Master::Full(children))
+}
+
+fn build_seek_head(entries: &[(u32, u64)]) -> MatroskaSpec {
+    let seeks = entries
+        .iter()
+        .map(|(id, position)| {
+            MatroskaSpec::Seek(Master::Full(vec![
+                MatroskaSpec::SeekID(id.to_be_bytes().to_vec()),
+                MatroskaSpec::SeekPosition(*position),
+            ]))
+        })
+        .collect();
+
+    MatroskaSpec::SeekHead(Master::Full(seeks))
+}
+
+fn build_cue_point(timestamp: u64, track: u64, cluster_position: u64) -> MatroskaSpec {
+    MatroskaSpec::CuePoint(Master::Full(vec![
+        MatroskaSpec::CueTime(timestamp),
+        MatroskaSpec::CueTrackPositions(Master::Full(vec![
+            MatroskaSpec::CueTrack(track),
+            MatroskaSpec::CueClusterPosition(cluster_position),
+        ])),
+    ]))
+}
+
+/// Segment ID followed by an 8 byte size, large enough for any recording.
+fn segment_header(size: u64) -> Vec<u8> {
+    let mut header = element_id::SEGMENT.to_be_bytes().to_vec();
+    header.extend(write_vint(size, 8));
+    header
+}
+
+pub(crate) fn encode(tags: &[MatroskaSpec]) -> anyhow::Result<Vec<u8>> {
+    let mut writer = WebmWriter::new(Vec::new());
+    for tag in tags {
+        writer.write(tag)?;
+    }
+    Ok(writer.into_inner()?)
+}
+
+/// Encodes tags that only validate as children of a Segment (Info, Tracks, Cluster...),
+/// without the Segment header itself.
+pub(crate) fn encode_in_segment(tags: &[MatroskaSpec]) -> anyhow::Result<Vec<u8>> {
+    let mut writer = WebmWriter::new(Vec::new());
+    writer.write_advanced(
+        &MatroskaSpec::Segment(Master::Start),
+        WriteOptions::is_unknown_sized_element(),
+    )?;
+    for tag in tags {
+        writer.write(tag)?;
+    }
+
+    // The header only reaches the output along with the first tag, its length is known though
+    let mut encoded = writer.into_inner()?;
+    encoded.drain(..UNKNOWN_SEGMENT_HEADER_LEN);
+    Ok(encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use webm_iterable::{
+        matroska_spec::{Master, MatroskaSpec},
+        WebmIterator,
+    };
+
+    use crate::jrec::webm::fixture;
+
+    use super::finalize_recording;
+
+    #[test]
+    fn test_finalize_adds_duration_and_cues() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("recording.webm");
+        std::fs::write(&path, fixture::recording(4, 5)).unwrap();
+
+        let summary = finalize_recording(&path).unwrap();
+
+        assert_eq!(4, summary.cluster_count);
+        assert_eq!(4, summary.cue_count);
+        assert!(!summary.truncated);
+        // The last frame lasts as long as the others
+        assert_eq!(20 * fixture::FRAME_DURATION_MS, summary.duration_ms);
+
+        let file = std::fs::File::open(&path).unwrap();
+        let tags = WebmIterator::new(
+            file,
+            &[
+                MatroskaSpec::SeekHead(Master::Start),
+                MatroskaSpec::Info(Master::Start),
+                MatroskaSpec::Cues(Master::Start),
+            ],
+        )
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+
+        assert!(tags
+            .iter()
+            .any(|tag| matches!(tag, MatroskaSpec::SeekHead(Master::Full(_)))));
+        let info = tags
+            .iter()
+            .find_map(|tag| match tag {
+                MatroskaSpec::Info(Master::Full(children)) => Some(children),
+                _ => None,
+            })
+            .unwrap();
+        assert!(info
+            .iter()
+            .any(|child| matches!(child, MatroskaSpec::Duration(duration) if *duration > 0.0)));
+        assert!(tags.iter().any(
+            |tag| matches!(tag, MatroskaSpec::Cues(Master::Full(points)) if points.len() == 4)
+        ));
+    }
+
+    #[test]
+    fn test_finalize_drops_truncated_cluster() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("recording.webm");
+        let mut recording = fixture::recording(3, 5);
+        // Cut into the last block
+        recording.truncate(recording.len() - 5);
+        std::fs::write(&path, recording).unwrap();
+
+        let summary = finalize_recording(&path).unwrap();
+
+        assert_eq!(2, summary.cluster_count);
+        assert!(summary.truncated);
+    }
+}
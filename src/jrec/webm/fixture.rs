@@ -0,0 +1,149 @@
+//! Synthetic recordings shaped like what MediaRecorder pushes: unknown-sized Segment and Clusters,
+//! a VP9 video track and an Opus audio track.
+
+use webm_iterable::{
+    matroska_spec::{Master, MatroskaSpec},
+    WebmWriter, WriteOptions,
+};
+
+pub const VIDEO_TRACK: u64 = 1;
+pub const AUDIO_TRACK: u64 = 2;
+pub const FRAME_DURATION_MS: u64 = 33;
+
+pub fn ebml_header() -> MatroskaSpec {
+    MatroskaSpec::Ebml(Master::Full(vec![
+        MatroskaSpec::EbmlVersion(1),
+        MatroskaSpec::EbmlReadVersion(1),
+        MatroskaSpec::EbmlMaxIdLength(4),
+        MatroskaSpec::EbmlMaxSizeLength(8),
+        MatroskaSpec::DocType("webm".to_string()),
+        MatroskaSpec::DocTypeVersion(4),
+        MatroskaSpec::DocTypeReadVersion(2),
+    ]))
+}
+
+pub fn info() -> MatroskaSpec {
+    MatroskaSpec::Info(Master::Full(vec![
+        MatroskaSpec::TimestampScale(1_000_000),
+        MatroskaSpec::MuxingApp("fixture".to_string()),
+        MatroskaSpec::WritingApp("fixture".to_string()),
+    ]))
+}
+
+pub fn opus_head() -> Vec<u8> {
+    let mut head = b"OpusHead".to_vec();
+    head.push(1); // version
+    head.push(2); // channels
+    head.extend(312u16.to_le_bytes()); // pre-skip
+    head.extend(48000u32.to_le_bytes()); // input sample rate
+    head.extend(0i16.to_le_bytes()); // output gain
+    head.push(0); // channel mapping family
+    head
+}
+
+pub fn tracks() -> MatroskaSpec {
+    MatroskaSpec::Tracks(Master::Full(vec![
+        MatroskaSpec::TrackEntry(Master::Full(vec![
+            MatroskaSpec::TrackNumber(VIDEO_TRACK),
+            MatroskaSpec::TrackUID(VIDEO_TRACK),
+            MatroskaSpec::TrackType(1),
+            MatroskaSpec::CodecID("V_VP9".to_string()),
+            MatroskaSpec::Video(Master::Full(vec![
+                MatroskaSpec::PixelWidth(640),
+                MatroskaSpec::PixelHeight(480),
+            ])),
+        ])),
+        MatroskaSpec::TrackEntry(Master::Full(vec![
+            MatroskaSpec::TrackNumber(AUDIO_TRACK),
+            MatroskaSpec::TrackUID(AUDIO_TRACK),
+            MatroskaSpec::TrackType(2),
+            MatroskaSpec::CodecID("A_OPUS".to_string()),
+            MatroskaSpec::CodecPrivate(opus_head()),
+            MatroskaSpec::Audio(Master::Full(vec![
+                MatroskaSpec::SamplingFrequency(48000.0),
+                MatroskaSpec::Channels(2),
+            ])),
+        ])),
+    ]))
+}
+
+pub fn simple_block(
+    track: u64,
+    relative_timestamp: i16,
+    keyframe: bool,
+    payload: &[u8],
+) -> MatroskaSpec {
+    let mut data = vec![0x80 | track as u8];
+    data.extend(relative_timestamp.to_be_bytes());
+    data.push(if keyframe { 0x80 } else { 0x00 });
+    data.extend_from_slice(payload);
+    MatroskaSpec::SimpleBlock(data)
+}
+
+/// Children of one cluster starting with a video keyframe, interleaved with audio frames.
+pub fn cluster_children(timestamp: u64, frames: usize) -> Vec<MatroskaSpec> {
+    let mut children = vec![MatroskaSpec::Timestamp(timestamp)];
+    for frame in 0..frames {
+        let relative_timestamp = (frame as u64 * FRAME_DURATION_MS) as i16;
+        children.push(simple_block(
+            VIDEO_TRACK,
+            relative_timestamp,
+            frame == 0,
+            &[frame as u8; 16],
+        ));
+        children.push(simple_block(
+            AUDIO_TRACK,
+            relative_timestamp,
+            true,
+            &[0xFC, frame as u8, 0, 0],
+        ));
+    }
+    children
+}
+
+pub fn write_cluster(writer: &mut WebmWriter<Vec<u8>>, children: &[MatroskaSpec]) {
+    writer
+        .write_advanced(
+            &MatroskaSpec::Cluster(Master::Start),
+            WriteOptions::is_unknown_sized_element(),
+        )
+        .unwrap();
+    for child in children {
+        writer.write(child).unwrap();
+    }
+    writer.write(&MatroskaSpec::Cluster(Master::End)).unwrap();
+}
+
+/// The header part of a recording: EBML header, unknown-sized Segment start, Info and Tracks.
+pub fn header() -> Vec<u8> {
+    let mut writer = WebmWriter::new(Vec::new());
+    write_header(&mut writer);
+    writer.get_ref().clone()
+}
+
+fn write_header(writer: &mut WebmWriter<Vec<u8>>) {
+    writer.write(&ebml_header()).unwrap();
+    writer
+        .write_advanced(
+            &MatroskaSpec::Segment(Master::Start),
+            WriteOptions::is_unknown_sized_element(),
+        )
+        .unwrap();
+    writer.write(&info()).unwrap();
+    writer.write(&tracks()).unwrap();
+}
+
+pub fn recording(clusters: usize, frames_per_cluster: usize) -> Vec<u8> {
+    let mut writer = WebmWriter::new(Vec::new());
+    write_header(&mut writer);
+
+    for cluster in 0..clusters {
+        let timestamp = (cluster * frames_per_cluster) as u64 * FRAME_DURATION_MS;
+        write_cluster(
+            &mut writer,
+            &cluster_children(timestamp, frames_per_cluster),
+        );
+    }
+
+    writer.get_ref().clone()
+}
@@ -0,0 +1,486 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::Context;
+use tracing::{debug, warn};
+use webm_iterable::{
+    matroska_spec::{Master, MatroskaSpec},
+    WebmIterator,
+};
+
+use crate::{
+    jrec::webm::element_id,
+    utils::file::{identity, std_open_read},
+};
+
+use super::{
+    block::BlockHeader,
+    finalize::{find_cue_track, timestamp_scale},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClusterIndexEntry {
+    /// Absolute offset of the Cluster element in the file
+    pub offset: u64,
+    pub timestamp_ms: u64,
+    /// The first video frame of the cluster is a keyframe, playback can start here
+    pub keyframe: bool,
+}
+
+/// Where the clusters of a recording are, used to reposition readers by time.
+#[derive(Debug, Clone)]
+pub struct RecordingIndex {
+    /// Everything before this offset is header (EBML, Segment, Info, Tracks...)
+    pub header_end: u64,
+    pub timestamp_scale: u64,
+    pub clusters: Vec<ClusterIndexEntry>,
+    /// Where the last cluster ends when something follows it, Cues in a finalized recording
+    pub clusters_end: Option<u64>,
+    /// Duration written in Info by finalization
+    pub duration_ms: Option<u64>,
+    /// Track whose keyframes the clusters are marked by, any track when there is no video
+    video_track: Option<u64>,
+}
+
+impl RecordingIndex {
+    /// Uses the Cues of finalized recordings, and scans the clusters of the ones without (still recording, or
+    /// never finalized). A recording still being written is indexed up to its last complete element.
+    pub fn build(path: &Path) -> anyhow::Result<Self> {
+        let file = std_open_read(path).with_context(|| format!("opening {:?}", path))?;
+        Self::read(&file)
+    }
+
+    /// Same as [`RecordingIndex::build`] on an open recording, which stays the one indexed even if
+    /// finalization renames another file over its path meanwhile.
+    pub fn read(file: &File) -> anyhow::Result<Self> {
+        let mut reader = file;
+        reader.seek(SeekFrom::Start(0))?;
+        let mut tag_iterator = WebmIterator::new(
+            reader,
+            &[
+                MatroskaSpec::Ebml(Master::Start),
+                MatroskaSpec::SeekHead(Master::Start),
+                MatroskaSpec::Info(Master::Start),
+                MatroskaSpec::Tracks(Master::Start),
+                MatroskaSpec::Cues(Master::Start),
+            ],
+        );
+
+        let mut segment_data_start = None;
+        let mut in_segment = false;
+        let mut cues_position = None;
+        let mut timestamp_scale_value = None;
+        let mut duration = None;
+        let mut video_track = None;
+        let mut header_end = None;
+
+        while let Some(tag) = tag_iterator.next() {
+            let tag = tag.context("reading recording header")?;
+
+            if in_segment && segment_data_start.is_none() {
+                segment_data_start = Some(tag_iterator.last_emitted_tag_offset() as u64);
+            }
+
+            match tag {
+                MatroskaSpec::Segment(Master::Start) => in_segment = true,
+                MatroskaSpec::SeekHead(Master::Full(ref seeks)) => {
+                    cues_position = find_seek_position(seeks, element_id::CUES);
+                }
+                MatroskaSpec::Info(ref children) => {
+                    timestamp_scale_value = Some(timestamp_scale(&tag));
+                    duration = info_duration(children);
+                }
+                MatroskaSpec::Tracks(Master::Full(ref entries)) => {
+                    video_track = find_cue_track(entries);
+                }
+                MatroskaSpec::Cluster(Master::Start) => {
+                    header_end = Some(tag_iterator.last_emitted_tag_offset() as u64);
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        let header_end = header_end.context("recording has no cluster yet")?;
+        let timestamp_scale = timestamp_scale_value.unwrap_or(1_000_000);
+        let duration_ms = duration.map(|duration| (duration * timestamp_scale as f64 / 1e6) as u64);
+        let mut index = Self {
+            header_end,
+            timestamp_scale,
+            clusters: vec![],
+            clusters_end: None,
+            duration_ms,
+            video_track,
+        };
+
+        if let (Some(segment_data_start), Some(cues_position)) = (segment_data_start, cues_position)
+        {
+            match index.read_cues(file, segment_data_start, cues_position) {
+                Ok(clusters) if !clusters.is_empty() => {
+                    debug!(cue_count = clusters.len(), "Indexed recording from Cues");
+                    index.clusters = clusters;
+                    // Finalization writes the Cues right after the last cluster
+                    index.clusters_end = Some(segment_data_start + cues_position);
+                    return Ok(index);
+                }
+                Ok(_) => {}
+                Err(e) => warn!(?e, "Failed to read Cues, scanning clusters instead"),
+            }
+        }
+
+        index.scan(file, header_end)?;
+        debug!(
+            cluster_count = index.clusters.len(),
+            "Indexed recording by scanning clusters"
+        );
+
+        Ok(index)
+    }
+
+    /// Indexes what was appended to a recording still being written since it was indexed.
+    pub fn extend(&mut self, file: &File) -> anyhow::Result<()> {
+        // Something follows the clusters, no cluster comes after it
+        if self.clusters_end.is_some() {
+            return Ok(());
+        }
+
+        // The last cluster may have been cut off by the end of the data, it is scanned again
+        let from = match self.clusters.pop() {
+            Some(last) => last.offset,
+            None => self.header_end,
+        };
+        self.scan(file, from)
+    }
+
+    /// Adds the clusters from `from` on, which is the start of a cluster.
+    fn scan(&mut self, file: &File, from: u64) -> anyhow::Result<()> {
+        let mut reader = file;
+        reader.seek(SeekFrom::Start(from))?;
+        let mut tag_iterator = WebmIterator::new(reader, &[]);
+        let mut first_video_block_seen = false;
+
+        while let Some(tag) = tag_iterator.next() {
+            // A recording in progress ends in the middle of an element, what we have so far is enough
+            let Ok(tag) = tag else {
+                break;
+            };
+            let offset = from + tag_iterator.last_emitted_tag_offset() as u64;
+
+            match tag {
+                MatroskaSpec::Cluster(Master::Start) => {
+                    self.clusters.push(ClusterIndexEntry {
+                        offset,
+                        timestamp_ms: 0,
+                        keyframe: false,
+                    });
+                    first_video_block_seen = false;
+                }
+                MatroskaSpec::Cues(_) | MatroskaSpec::Tags(_) if self.clusters_end.is_none() => {
+                    self.clusters_end = Some(offset);
+                }
+                MatroskaSpec::Timestamp(timestamp) => {
+                    if let Some(cluster) = self.clusters.last_mut() {
+                        cluster.timestamp_ms = timestamp * self.timestamp_scale / 1_000_000;
+                    }
+                }
+                MatroskaSpec::SimpleBlock(ref data) if !first_video_block_seen => {
+                    let Some(header) = BlockHeader::parse(data) else {
+                        continue;
+                    };
+                    if self.video_track.is_none_or(|track| track == header.track) {
+                        first_video_block_seen = true;
+                        if let Some(cluster) = self.clusters.last_mut() {
+                            cluster.keyframe = header.is_keyframe();
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The last keyframe cluster starting at or before `time_ms`, or the first keyframe cluster.
+    pub fn seek_target(&self, time_ms: u64) -> Option<&ClusterIndexEntry> {
+        let keyframes = || self.clusters.iter().filter(|cluster| cluster.keyframe);
+
+        keyframes()
+            .take_while(|cluster| cluster.timestamp_ms <= time_ms)
+            .last()
+            .or_else(|| keyframes().next())
+    }
+
+    fn read_cues(
+        &self,
+        file: &File,
+        segment_data_start: u64,
+        cues_position: u64,
+    ) -> anyhow::Result<Vec<ClusterIndexEntry>> {
+        let mut reader = file;
+        reader.seek(SeekFrom::Start(segment_data_start + cues_position))?;
+
+        let mut tag_iterator = WebmIterator::new(reader, &[MatroskaSpec::Cues(Master::Start)]);
+        let Some(MatroskaSpec::Cues(Master::Full(cue_points))) = tag_iterator.next().transpose()?
+        else {
+            anyhow::bail!("SeekHead does not point at Cues");
+        };
+
+        let clusters = cue_points
+            .iter()
+            .filter_map(|cue_point| {
+                let MatroskaSpec::CuePoint(Master::Full(children)) = cue_point else {
+                    return None;
+                };
+
+                let time = children.iter().find_map(|child| match child {
+                    MatroskaSpec::CueTime(time) => Some(*time),
+                    _ => None,
+                })?;
+                let position = children.iter().find_map(|child| match child {
+                    MatroskaSpec::CueTrackPositions(Master::Full(positions)) => {
+                        positions.iter().find_map(|position| match position {
+                            MatroskaSpec::CueClusterPosition(position) => Some(*position),
+                            _ => None,
+                        })
+                    }
+                    _ => None,
+                })?;
+
+                Some(ClusterIndexEntry {
+                    offset: segment_data_start + position,
+                    timestamp_ms: time * self.timestamp_scale / 1_000_000,
+                    keyframe: true,
+                })
+            })
+            .collect();
+
+        Ok(clusters)
+    }
+}
+
+/// Recordings kept indexed, the least recently used is forgotten past that
+const CACHED_INDEXES: usize = 64;
+
+/// A recording opened along with its index, both of the same file.
+#[derive(Debug)]
+pub struct IndexedRecording {
+    pub index: Arc<RecordingIndex>,
+    pub file: File,
+    /// Size of `file` when it was indexed
+    pub len: u64,
+}
+
+#[derive(Debug)]
+struct CachedIndex {
+    index: Arc<RecordingIndex>,
+    identity: (u64, u64),
+    len: u64,
+    used_at: Instant,
+}
+
+/// Indexes of the recordings being served, so a request does not scan the whole file again. An active
+/// recording is only scanned from where it was last indexed, a finalized one is indexed again from its Cues.
+#[derive(Debug, Default)]
+pub struct IndexCache {
+    entries: Mutex<HashMap<PathBuf, CachedIndex>>,
+}
+
+impl IndexCache {
+    /// Blocks on reading the file.
+    pub fn open(&self, path: &Path) -> anyhow::Result<IndexedRecording> {
+        let file = std_open_read(path).with_context(|| format!("opening {:?}", path))?;
+        self.index(path, file)
+    }
+
+    /// Indexes a file already open at `path`, which may since have been replaced by its finalized version.
+    /// Blocks on reading the file.
+    pub fn index(&self, path: &Path, file: File) -> anyhow::Result<IndexedRecording> {
+        let identity = identity(&file)?;
+        let len = file.metadata()?.len();
+
+        // A recording never shrinks while it is written, a shorter file is another one whatever its identity says
+        let cached = self
+            .entries
+            .lock()
+            .expect("index cache lock")
+            .get(path)
+            .filter(|cached| cached.identity == identity && cached.len <= len)
+            .map(|cached| (cached.index.clone(), cached.len));
+        let index = match cached {
+            Some((index, cached_len)) if cached_len == len => index,
+            Some((index, _)) => {
+                let mut index = RecordingIndex::clone(&index);
+                index.extend(&file)?;
+                Arc::new(index)
+            }
+            None => Arc::new(
+                RecordingIndex::read(&file).with_context(|| format!("indexing {:?}", path))?,
+            ),
+        };
+
+        let mut entries = self.entries.lock().expect("index cache lock");
+        entries.insert(
+            path.to_path_buf(),
+            CachedIndex {
+                index: index.clone(),
+                identity,
+                len,
+                used_at: Instant::now(),
+            },
+        );
+        if entries.len() > CACHED_INDEXES {
+            let least_recently_used = entries
+                .iter()
+                .min_by_key(|(_, cached)| cached.used_at)
+                .map(|(path, _)| path.clone());
+            if let Some(path) = least_recently_used {
+                entries.remove(&path);
+            }
+        }
+
+        Ok(IndexedRecording { index, file, len })
+    }
+}
+
+fn info_duration(info: &Master<MatroskaSpec>) -> Option<f64> {
+    let Master::Full(children) = info else {
+        return None;
+    };
+
+    children.iter().find_map(|child| match child {
+        MatroskaSpec::Duration(duration) => Some(*duration),
+        _ => None,
+    })
+}
+
+fn find_seek_position(seeks: &[MatroskaSpec], id: u32) -> Option<u64> {
+    seeks.iter().find_map(|seek| {
+        let MatroskaSpec::Seek(Master::Full(children)) = seek else {
+            return None;
+        };
+
+        let matches_id = children.iter().any(
+            |child| matches!(child, MatroskaSpec::SeekID(seek_id) if *seek_id == id.to_be_bytes()),
+        );
+        if !matches_id {
+            return None;
+        }
+
+        children.iter().find_map(|child| match child {
+            MatroskaSpec::SeekPosition(position) => Some(*position),
+            _ => None,
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jrec::webm::{finalize::finalize_recording, fixture};
+
+    use std::io::Write;
+
+    use super::{IndexCache, RecordingIndex};
+
+    #[test]
+    fn test_scan_and_cues_agree() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("recording.webm");
+        std::fs::write(&path, fixture::recording(4, 10)).unwrap();
+
+        let scanned = RecordingIndex::build(&path).unwrap();
+        assert_eq!(4, scanned.clusters.len());
+        assert!(scanned.clusters.iter().all(|cluster| cluster.keyframe));
+
+        finalize_recording(&path).unwrap();
+        let from_cues = RecordingIndex::build(&path).unwrap();
+        assert_eq!(4, from_cues.clusters.len());
+
+        let timestamps = |index: &RecordingIndex| {
+            index
+                .clusters
+                .iter()
+                .map(|cluster| cluster.timestamp_ms)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(timestamps(&scanned), timestamps(&from_cues));
+
+        let target = from_cues.seek_target(700).unwrap();
+        assert_eq!(660, target.timestamp_ms);
+        assert_eq!(from_cues.clusters[2], *target);
+    }
+
+    #[test]
+    fn test_cache_extends_the_index_of_a_growing_recording() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("recording.webm");
+        let recording = fixture::recording(6, 10);
+        // Cut in the middle of the fourth cluster
+        let cut = recording.len() * 3 / 5;
+        std::fs::write(&path, &recording[..cut]).unwrap();
+
+        let cache = IndexCache::default();
+        let partial = cache.open(&path).unwrap();
+        assert!(partial.index.clusters.len() < 6);
+
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(&recording[cut..]).unwrap();
+        let grown = cache.open(&path).unwrap();
+        assert_eq!(
+            RecordingIndex::build(&path).unwrap().clusters,
+            grown.index.clusters
+        );
+        assert_eq!(6, grown.index.clusters.len());
+
+        // A new file takes the place of the recording
+        finalize_recording(&path).unwrap();
+        let finalized = cache.open(&path).unwrap();
+        assert!(finalized.index.clusters_end.is_some());
+        assert_eq!(
+            grown
+                .index
+                .clusters
+                .iter()
+                .map(|cluster| cluster.timestamp_ms)
+                .collect::<Vec<_>>(),
+            finalized
+                .index
+                .clusters
+                .iter()
+                .map(|cluster| cluster.timestamp_ms)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_cache_drops_the_index_of_a_file_that_shrank() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("recording.webm");
+        std::fs::write(&path, fixture::recording(6, 10)).unwrap();
+
+        let cache = IndexCache::default();
+        assert_eq!(6, cache.open(&path).unwrap().index.clusters.len());
+
+        // Rewritten in place, the identity of the file stays the same
+        std::fs::OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&fixture::recording(2, 10))
+            .unwrap();
+        assert_eq!(
+            RecordingIndex::build(&path).unwrap().clusters,
+            cache.open(&path).unwrap().index.clusters
+        );
+    }
+}
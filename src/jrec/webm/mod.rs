@@ -14,7 +14,48 @@ use webm_iterable::{
 
 use crate::utils;
 
-pub mod stream_parser;
+pub mod block;
+pub mod finalize;
+pub mod index;
+pub mod repair;
+pub mod splitter;
+
+#[cfg(test)]
+pub(crate) mod fixture;
+
+/// EBML IDs of the elements we need to reference by value (SeekHead entries, raw headers)
+pub mod element_id {
+    pub const EBML: u32 = 0x1A45_DFA3;
+    pub const SEGMENT: u32 = 0x1853_8067;
+    pub const SEEK_HEAD: u32 = 0x114D_9B74;
+    pub const INFO: u32 = 0x1549_A966;
+    pub const TRACKS: u32 = 0x1654_AE6B;
+    pub const CHAPTERS: u32 = 0x1043_A770;
+    pub const CUES: u32 = 0x1C53_BB6B;
+    pub const ATTACHMENTS: u32 = 0x1941_A469;
+    pub const TAGS: u32 = 0x1254_C367;
+    pub const CLUSTER: u32 = 0x1F43_B675;
+    pub const TIMESTAMP: u32 = 0xE7;
+    pub const SIMPLE_BLOCK: u32 = 0xA3;
+    pub const BLOCK_GROUP: u32 = 0xA0;
+    pub const BLOCK: u32 = 0xA1;
+
+    /// Direct children of the Segment, seeing one of them ends an unknown-sized Cluster
+    pub fn is_top_level(id: u32) -> bool {
+        matches!(
+            id,
+            EBML | SEGMENT
+                | SEEK_HEAD
+                | INFO
+                | TRACKS
+                | CHAPTERS
+                | CUES
+                | ATTACHMENTS
+                | TAGS
+                | CLUSTER
+        )
+    }
+}
 
 pub struct TimedTagWriter<T>
 where
@@ -35,6 +76,12 @@ where
         }
     }
 
+    /// Cluster Timestamps are rebased by `time_offset`, in TimestampScale units.
+    pub fn set_time_offset(&self, time_offset: u64) {
+        self.time_offset
+            .swap(Box::new(time_offset), Ordering::SeqCst);
+    }
+
     pub fn write(&self, tag: &MatroskaSpec) -> anyhow::Result<()> {
         let mut writer = self.writer.blocking_lock();
 
@@ -54,10 +101,11 @@ where
         } else {
             // Handle the Timestamp tag with offset adjustment
             if let MatroskaSpec::Timestamp(timestamp) = *tag {
+                // Without an explicit offset, the first cluster this writer sees becomes time zero
                 let time_offset = self
                     .time_offset
                     .take(Ordering::SeqCst)
-                    .unwrap_or(Box::new(0));
+                    .unwrap_or(Box::new(timestamp));
 
                 let adjusted_timestamp = timestamp.saturating_sub(*time_offset);
 
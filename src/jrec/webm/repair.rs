@@ -0,0 +1,479 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{BufReader, Read, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+
+use crate::utils::file::std_open_read;
+
+use super::{
+    block::BlockHeader,
+    element_id,
+    finalize::{finalize_recording, FinalizeSummary},
+    splitter::ElementHeader,
+};
+
+/// Longest element header: a 4 byte ID and an 8 byte size
+const MAX_HEADER_LEN: u64 = 12;
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Issue {
+    MissingEbmlHeader,
+    MissingSegment,
+    MissingTracks,
+    /// The element runs past the end of the file, `id` is unknown when its header is cut off too
+    TruncatedElement {
+        offset: u64,
+        id: Option<u32>,
+    },
+    /// Not a valid EBML ID or size, or an unknown size where only Segments and Clusters may have one
+    BadElementSize {
+        offset: u64,
+    },
+    /// A block of `track` goes back in time, timestamps in TimestampScale units. Only a warning, players
+    /// cope with it and repairing does not reorder blocks
+    NonMonotonicTimestamp {
+        offset: u64,
+        track: u64,
+        timestamp: u64,
+        previous: u64,
+    },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::MissingEbmlHeader => write!(f, "no EBML header"),
+            Issue::MissingSegment => write!(f, "no Segment after the EBML header"),
+            Issue::MissingTracks => write!(f, "no Tracks before the first cluster"),
+            Issue::TruncatedElement {
+                offset,
+                id: Some(id),
+            } => write!(f, "element {:#x} at {} is truncated", id, offset),
+            Issue::TruncatedElement { offset, id: None } => {
+                write!(f, "element header at {} is truncated", offset)
+            }
+            Issue::BadElementSize { offset } => write!(f, "bad element ID or size at {}", offset),
+            Issue::NonMonotonicTimestamp {
+                offset,
+                track,
+                timestamp,
+                previous,
+            } => write!(
+                f,
+                "block at {} of track {} goes back from {} to {}",
+                offset, track, previous, timestamp
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, serde::Serialize)]
+pub struct ValidationReport {
+    pub file_len: u64,
+    pub issues: Vec<Issue>,
+    pub cluster_count: usize,
+    /// End of the last cluster read in full, a repaired recording keeps everything before it
+    pub complete_end: Option<u64>,
+}
+
+impl ValidationReport {
+    /// No issue a repair would fix, warnings aside.
+    pub fn is_valid(&self) -> bool {
+        self.issues
+            .iter()
+            .all(|issue| matches!(issue, Issue::NonMonotonicTimestamp { .. }))
+    }
+
+    /// Issues that leave nothing a player could use.
+    fn fatal_issue(&self) -> Option<&Issue> {
+        self.issues.iter().find(|issue| {
+            matches!(
+                issue,
+                Issue::MissingEbmlHeader | Issue::MissingSegment | Issue::MissingTracks
+            )
+        })
+    }
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct RepairSummary {
+    pub report: ValidationReport,
+    /// Bytes of the original recording kept, before rewriting
+    pub kept_bytes: u64,
+    pub finalized: FinalizeSummary,
+}
+
+enum ClusterEnd {
+    Complete(u64),
+    Broken,
+}
+
+/// Reads element headers and small bodies at arbitrary offsets, without giving up the read buffer
+/// when moving forward a little.
+struct Walker {
+    reader: BufReader<File>,
+    position: u64,
+    file_len: u64,
+}
+
+impl Walker {
+    fn seek(&mut self, position: u64) -> std::io::Result<()> {
+        self.reader
+            .seek_relative(position as i64 - self.position as i64)?;
+        self.position = position;
+        Ok(())
+    }
+
+    fn read(&mut self, position: u64, len: u64) -> std::io::Result<Vec<u8>> {
+        self.seek(position)?;
+        let len = len.min(self.file_len.saturating_sub(position));
+        let mut data = vec![0; len as usize];
+        self.reader.read_exact(&mut data)?;
+        self.position += len;
+        Ok(data)
+    }
+
+    /// `Ok(None)` when the file ends inside the header.
+    fn read_header(&mut self, position: u64) -> anyhow::Result<Option<ElementHeader>> {
+        let data = self.read(position, MAX_HEADER_LEN)?;
+        ElementHeader::read(&data)
+    }
+}
+
+/// Walks the structure of a recording without trusting anything in it.
+pub fn validate(path: &Path) -> anyhow::Result<ValidationReport> {
+    let file = std_open_read(path).with_context(|| format!("opening {:?}", path))?;
+    let file_len = file.metadata()?.len();
+    let mut walker = Walker {
+        reader: BufReader::new(file),
+        position: 0,
+        file_len,
+    };
+    let mut report = ValidationReport {
+        file_len,
+        ..Default::default()
+    };
+
+    let ebml = match walker.read_header(0) {
+        Ok(Some(header)) if header.id == element_id::EBML => header,
+        _ => {
+            report.issues.push(Issue::MissingEbmlHeader);
+            return Ok(report);
+        }
+    };
+    let Some(ebml_size) = ebml.size else {
+        report.issues.push(Issue::BadElementSize { offset: 0 });
+        return Ok(report);
+    };
+
+    let segment_start = ebml.len as u64 + ebml_size;
+    let segment = match walker.read_header(segment_start) {
+        Ok(Some(header)) if header.id == element_id::SEGMENT => header,
+        _ => {
+            report.issues.push(Issue::MissingSegment);
+            return Ok(report);
+        }
+    };
+    let data_start = segment_start + segment.len as u64;
+    // A finalized Segment cut short has a size past the end of the file
+    let segment_end = segment
+        .size
+        .map_or(file_len, |size| (data_start + size).min(file_len));
+    let segment_cut = segment
+        .size
+        .is_some_and(|size| data_start + size > file_len);
+
+    let mut has_tracks = false;
+    let mut last_timestamps = HashMap::new();
+    let mut position = data_start;
+    while position < segment_end {
+        let header = match walker.read_header(position) {
+            Ok(Some(header)) => header,
+            Ok(None) => {
+                report.issues.push(Issue::TruncatedElement {
+                    offset: position,
+                    id: None,
+                });
+                break;
+            }
+            Err(_) => {
+                report
+                    .issues
+                    .push(Issue::BadElementSize { offset: position });
+                break;
+            }
+        };
+
+        if header.id == element_id::CLUSTER {
+            if !has_tracks {
+                break;
+            }
+            match walk_cluster(
+                &mut walker,
+                position,
+                &header,
+                &mut last_timestamps,
+                &mut report,
+            )? {
+                ClusterEnd::Complete(end) => {
+                    report.cluster_count += 1;
+                    report.complete_end = Some(end);
+                    position = end;
+                    continue;
+                }
+                ClusterEnd::Broken => break,
+            }
+        }
+
+        has_tracks |= header.id == element_id::TRACKS;
+        let Some(size) = header.size else {
+            report
+                .issues
+                .push(Issue::BadElementSize { offset: position });
+            break;
+        };
+        let end = position + header.len as u64 + size;
+        if end > file_len {
+            report.issues.push(Issue::TruncatedElement {
+                offset: position,
+                id: Some(header.id),
+            });
+            break;
+        }
+        position = end;
+    }
+
+    if !has_tracks {
+        report.issues.push(Issue::MissingTracks);
+    }
+    // The file may end right between two children of a Segment whose size says otherwise
+    if segment_cut && report.issues.is_empty() {
+        report.issues.push(Issue::TruncatedElement {
+            offset: segment_start,
+            id: Some(element_id::SEGMENT),
+        });
+    }
+
+    Ok(report)
+}
+
+fn walk_cluster(
+    walker: &mut Walker,
+    start: u64,
+    header: &ElementHeader,
+    last_timestamps: &mut HashMap<u64, u64>,
+    report: &mut ValidationReport,
+) -> anyhow::Result<ClusterEnd> {
+    let body_start = start + header.len as u64;
+    let end = header.size.map(|size| body_start + size);
+    let limit = end.unwrap_or(walker.file_len);
+    if limit > walker.file_len {
+        report.issues.push(Issue::TruncatedElement {
+            offset: start,
+            id: Some(element_id::CLUSTER),
+        });
+        return Ok(ClusterEnd::Broken);
+    }
+
+    let mut cluster_timestamp = 0;
+    let mut position = body_start;
+    while position < limit {
+        let child = match walker.read_header(position) {
+            Ok(Some(child)) => child,
+            Ok(None) => {
+                report.issues.push(Issue::TruncatedElement {
+                    offset: position,
+                    id: None,
+                });
+                return Ok(ClusterEnd::Broken);
+            }
+            Err(_) => {
+                report
+                    .issues
+                    .push(Issue::BadElementSize { offset: position });
+                return Ok(ClusterEnd::Broken);
+            }
+        };
+        if end.is_none() && element_id::is_top_level(child.id) {
+            return Ok(ClusterEnd::Complete(position));
+        }
+
+        let Some(size) = child.size else {
+            report
+                .issues
+                .push(Issue::BadElementSize { offset: position });
+            return Ok(ClusterEnd::Broken);
+        };
+        let body_start = position + child.len as u64;
+        let child_end = body_start + size;
+        if child_end > limit {
+            report.issues.push(Issue::TruncatedElement {
+                offset: position,
+                id: Some(child.id),
+            });
+            return Ok(ClusterEnd::Broken);
+        }
+
+        let block = match child.id {
+            element_id::TIMESTAMP => {
+                cluster_timestamp = walker
+                    .read(body_start, size)?
+                    .iter()
+                    .fold(0u64, |value, byte| (value << 8) | *byte as u64);
+                None
+            }
+            element_id::SIMPLE_BLOCK => {
+                BlockHeader::parse(&walker.read(body_start, size.min(MAX_HEADER_LEN))?)
+            }
+            element_id::BLOCK_GROUP => find_block(&walker.read(body_start, size)?),
+            _ => None,
+        };
+        if let Some(block) = block {
+            let timestamp = block.timestamp(cluster_timestamp);
+            if let Some(previous) = last_timestamps.insert(block.track, timestamp) {
+                if timestamp < previous {
+                    report.issues.push(Issue::NonMonotonicTimestamp {
+                        offset: position,
+                        track: block.track,
+                        timestamp,
+                        previous,
+                    });
+                }
+            }
+        }
+
+        position = child_end;
+    }
+
+    Ok(ClusterEnd::Complete(limit))
+}
+
+/// Header of the Block inside a BlockGroup's content.
+fn find_block(mut group: &[u8]) -> Option<BlockHeader> {
+    while let Ok(Some(header)) = ElementHeader::read(group) {
+        let body = group.get(header.len..)?;
+        if header.id == element_id::BLOCK {
+            return BlockHeader::parse(body);
+        }
+        group = body.get(header.known_size().ok()?..)?;
+    }
+    None
+}
+
+/// Keeps everything up to the last complete cluster and rewrites it as a finalized recording,
+/// to `output` or over the recording itself.
+pub fn repair(path: &Path, output: Option<&Path>) -> anyhow::Result<RepairSummary> {
+    let report = validate(path)?;
+    if let Some(issue) = report.fatal_issue() {
+        anyhow::bail!("cannot repair {:?}: {}", path, issue);
+    }
+    let kept_bytes = report
+        .complete_end
+        .with_context(|| format!("{:?} has no complete cluster", path))?;
+
+    let output = output.unwrap_or(path);
+    let directory = output.parent().context("output has no parent directory")?;
+    let mut truncated = tempfile::Builder::new()
+        .suffix(".webm")
+        .tempfile_in(directory)
+        .context("creating repair file")?;
+    let copied = std::io::copy(
+        &mut std_open_read(path)?.take(kept_bytes),
+        truncated.as_file_mut(),
+    )?;
+    anyhow::ensure!(copied == kept_bytes, "{:?} changed while repairing", path);
+    truncated.as_file_mut().flush()?;
+
+    let truncated = truncated.into_temp_path();
+    let finalized = finalize_recording(&truncated)?;
+    truncated
+        .persist(output)
+        .with_context(|| format!("replacing {:?}", output))?;
+
+    Ok(RepairSummary {
+        report,
+        kept_bytes,
+        finalized,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use webm_iterable::{
+        matroska_spec::{Master, MatroskaSpec},
+        WebmWriter, WriteOptions,
+    };
+
+    use crate::jrec::webm::fixture;
+
+    use super::*;
+
+    #[test]
+    fn test_repair_truncated_recording() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("recording.webm");
+        let recording = fixture::recording(3, 4);
+        std::fs::write(&path, &recording[..recording.len() - 5]).unwrap();
+
+        let report = validate(&path).unwrap();
+        assert_eq!(2, report.cluster_count);
+        assert!(matches!(
+            report.issues.as_slice(),
+            [Issue::TruncatedElement { .. }]
+        ));
+
+        let repaired = directory.path().join("repaired.webm");
+        let summary = repair(&path, Some(&repaired)).unwrap();
+        assert_eq!(2, summary.finalized.cluster_count);
+        assert!(!summary.finalized.truncated);
+
+        let report = validate(&repaired).unwrap();
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+        assert_eq!(2, report.cluster_count);
+    }
+
+    #[test]
+    fn test_report_timestamps_going_back() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("recording.webm");
+
+        let mut writer = WebmWriter::new(Vec::new());
+        writer.write(&fixture::ebml_header()).unwrap();
+        writer
+            .write_advanced(
+                &MatroskaSpec::Segment(Master::Start),
+                WriteOptions::is_unknown_sized_element(),
+            )
+            .unwrap();
+        writer.write(&fixture::info()).unwrap();
+        writer.write(&fixture::tracks()).unwrap();
+        fixture::write_cluster(
+            &mut writer,
+            &[
+                MatroskaSpec::Timestamp(100),
+                fixture::simple_block(fixture::VIDEO_TRACK, 0, true, &[0; 8]),
+                fixture::simple_block(fixture::VIDEO_TRACK, -40, false, &[1; 8]),
+            ],
+        );
+        std::fs::write(&path, writer.get_ref()).unwrap();
+
+        let report = validate(&path).unwrap();
+        assert_eq!(1, report.cluster_count);
+        assert!(report.is_valid());
+        assert!(matches!(
+            report.issues.as_slice(),
+            [Issue::NonMonotonicTimestamp {
+                track: fixture::VIDEO_TRACK,
+                timestamp: 60,
+                previous: 100,
+                ..
+            }]
+        ));
+    }
+}
@@ -0,0 +1,481 @@
+use std::{io::Cursor, ops::Range};
+
+use anyhow::Context;
+use bytes::{BufMut, Bytes, BytesMut};
+use prometheus_client::metrics::counter::Counter;
+use tracing::warn;
+use webm_iterable::{
+    matroska_spec::{Master, MatroskaSpec},
+    WebmIterator,
+};
+
+use super::{
+    block::{read_vint, write_vint, BlockHeader},
+    element_id,
+    finalize::find_cue_track,
+};
+
+/// A complete Cluster element as pushed by the client.
+#[derive(Debug, Clone)]
+pub struct LiveCluster {
+    pub data: Bytes,
+    /// Cluster Timestamp, in TimestampScale units
+    pub timestamp: u64,
+    /// The first video frame is a keyframe, a viewer can start here
+    pub keyframe: bool,
+    /// Time from the cluster Timestamp to its last frame
+    pub last_frame_offset: u64,
+    body_start: usize,
+    timestamp_range: Range<usize>,
+}
+
+impl LiveCluster {
+    /// The cluster with its Timestamp moved back by `time_offset`, shared as is when there is nothing to change.
+    pub fn rebased(&self, time_offset: u64) -> Bytes {
+        if time_offset == 0 {
+            return self.data.clone();
+        }
+
+        self.with_timestamp(self.timestamp.saturating_sub(time_offset))
+            .data
+    }
+
+    /// The same cluster at another Timestamp.
+    pub fn with_timestamp(&self, timestamp: u64) -> LiveCluster {
+        // Rewritten as unknown-sized, so a Timestamp encoded on a different length does not matter
+        let children_before = &self.data[self.body_start..self.timestamp_range.start];
+        let mut data = BytesMut::with_capacity(self.data.len() + 16);
+        data.put_u32(element_id::CLUSTER);
+        data.put_slice(&UNKNOWN_SIZE);
+        let body_start = data.len();
+        data.put_slice(children_before);
+        let timestamp_start = data.len();
+        data.put_u8(element_id::TIMESTAMP as u8);
+        data.put_slice(&write_vint(8, 1));
+        data.put_u64(timestamp);
+        let timestamp_end = data.len();
+        data.put_slice(&self.data[self.timestamp_range.end..]);
+
+        LiveCluster {
+            data: data.freeze(),
+            timestamp,
+            keyframe: self.keyframe,
+            last_frame_offset: self.last_frame_offset,
+            body_start,
+            timestamp_range: timestamp_start..timestamp_end,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum LiveChunk {
+    /// Everything before the first Cluster: EBML header, Segment start, Info, Tracks...
+    Header(Bytes),
+    Cluster(LiveCluster),
+    /// Bytes that belong in the file but not to live viewers: Cues or Tags after the clusters,
+    /// a cut off tail, or anything after data we could not parse
+    Raw(Bytes),
+}
+
+impl LiveChunk {
+    pub fn bytes(&self) -> &Bytes {
+        match self {
+            LiveChunk::Header(data) | LiveChunk::Raw(data) => data,
+            LiveChunk::Cluster(cluster) => &cluster.data,
+        }
+    }
+}
+
+pub(crate) const UNKNOWN_SIZE: [u8; 8] = [0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF];
+
+pub(crate) struct ElementHeader {
+    pub id: u32,
+    /// `None` for unknown-sized elements
+    pub size: Option<u64>,
+    /// Length of the ID and size
+    pub len: usize,
+}
+
+impl ElementHeader {
+    /// `Ok(None)` when `data` does not hold the whole header yet.
+    pub fn read(data: &[u8]) -> anyhow::Result<Option<Self>> {
+        let Some(&first) = data.first() else {
+            return Ok(None);
+        };
+        let id_len = first.leading_zeros() as usize + 1;
+        anyhow::ensure!(id_len <= 4, "invalid element id {:#04x}", first);
+        let Some(id) = data.get(..id_len) else {
+            return Ok(None);
+        };
+        let id = id.iter().fold(0u32, |id, byte| (id << 8) | *byte as u32);
+
+        let Some(&size_first) = data.get(id_len) else {
+            return Ok(None);
+        };
+        anyhow::ensure!(size_first != 0, "invalid size for element {:#x}", id);
+        let Some((size, size_len)) = read_vint(&data[id_len..]) else {
+            return Ok(None);
+        };
+        let unknown = size == (1 << (7 * size_len)) - 1;
+
+        Ok(Some(Self {
+            id,
+            size: (!unknown).then_some(size),
+            len: id_len + size_len,
+        }))
+    }
+
+    pub fn known_size(&self) -> anyhow::Result<usize> {
+        self.size
+            .map(|size| size as usize)
+            .with_context(|| format!("element {:#x} has an unknown size", self.id))
+    }
+}
+
+/// What has been learned about the Cluster at the start of the buffer.
+struct ClusterScan {
+    /// End of the last complete child
+    position: usize,
+    /// End of the Cluster when its size is known
+    end: Option<usize>,
+    body_start: usize,
+    timestamp: u64,
+    timestamp_range: Range<usize>,
+    keyframe: Option<bool>,
+    last_frame_offset: u64,
+}
+
+enum State {
+    /// Accumulating everything up to the first Cluster, `position` is the next element to look at
+    Header {
+        position: usize,
+        in_segment: bool,
+    },
+    /// The buffer starts at a direct child of the Segment
+    Segment,
+    Cluster(ClusterScan),
+    /// The stream could not be parsed, the rest goes to the file untouched
+    Passthrough,
+}
+
+/// Cuts the bytes of a push session into its header and complete clusters as they arrive,
+/// without copying them again for every consumer.
+pub struct ClusterSplitter {
+    buffer: BytesMut,
+    /// A client declaring a huge or never ending element must not make us buffer without limit
+    max_buffered: usize,
+    /// Bumped when the splitter gives up
+    errors: Counter,
+    state: State,
+    video_track: Option<u64>,
+}
+
+impl ClusterSplitter {
+    /// Gives up on splitting once an element does not fit in `max_buffered` bytes, counting it in `errors`.
+    pub fn new(max_buffered: usize, errors: Counter) -> Self {
+        Self {
+            buffer: BytesMut::new(),
+            max_buffered,
+            errors,
+            state: State::Header {
+                position: 0,
+                in_segment: false,
+            },
+            video_track: None,
+        }
+    }
+
+    /// Every byte pushed comes back out in exactly one chunk, in order.
+    pub fn push(&mut self, data: &[u8]) -> Vec<LiveChunk> {
+        self.buffer.extend_from_slice(data);
+
+        let mut chunks = vec![];
+        loop {
+            match self.next_chunk() {
+                Ok(Some(chunk)) => chunks.push(chunk),
+                Ok(None) if self.buffer.len() > self.max_buffered => {
+                    warn!(
+                        buffered = self.buffer.len(),
+                        "Pushed element too large to buffer, live viewers get nothing more"
+                    );
+                    self.errors.inc();
+                    self.state = State::Passthrough;
+                    break;
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!(
+                        ?e,
+                        "Unexpected data in the pushed stream, live viewers get nothing more"
+                    );
+                    self.errors.inc();
+                    self.state = State::Passthrough;
+                    break;
+                }
+            }
+        }
+
+        if matches!(self.state, State::Passthrough) && !self.buffer.is_empty() {
+            chunks.push(LiveChunk::Raw(self.buffer.split().freeze()));
+        }
+
+        chunks
+    }
+
+    /// The end of the push: an unknown-sized Cluster whose children are all there is complete.
+    pub fn finish(mut self) -> Option<LiveChunk> {
+        if self.buffer.is_empty() {
+            return None;
+        }
+
+        if let State::Cluster(scan) = &self.state {
+            if scan.end.is_none() && scan.position == self.buffer.len() {
+                return Some(self.take_cluster());
+            }
+        }
+
+        Some(LiveChunk::Raw(self.buffer.split().freeze()))
+    }
+
+    fn next_chunk(&mut self) -> anyhow::Result<Option<LiveChunk>> {
+        loop {
+            match &mut self.state {
+                State::Passthrough => return Ok(None),
+                State::Header {
+                    position,
+                    in_segment,
+                } => {
+                    let Some(header) = ElementHeader::read(&self.buffer[*position..])? else {
+                        return Ok(None);
+                    };
+
+                    if header.id == element_id::SEGMENT && !*in_segment {
+                        *in_segment = true;
+                        *position += header.len;
+                        continue;
+                    }
+
+                    if header.id == element_id::CLUSTER && *in_segment {
+                        let header = self.buffer.split_to(*position).freeze();
+                        self.video_track = find_video_track(&header);
+                        self.state = State::Segment;
+                        return Ok(Some(LiveChunk::Header(header)));
+                    }
+
+                    let end = *position + header.len + header.known_size()?;
+                    if end > self.buffer.len() {
+                        return Ok(None);
+                    }
+                    *position = end;
+                }
+                State::Segment => {
+                    let Some(header) = ElementHeader::read(&self.buffer)? else {
+                        return Ok(None);
+                    };
+
+                    if header.id == element_id::CLUSTER {
+                        self.state = State::Cluster(ClusterScan {
+                            position: header.len,
+                            end: header.size.map(|size| header.len + size as usize),
+                            body_start: header.len,
+                            timestamp: 0,
+                            timestamp_range: header.len..header.len,
+                            keyframe: None,
+                            last_frame_offset: 0,
+                        });
+                        continue;
+                    }
+
+                    let end = header.len + header.known_size()?;
+                    if end > self.buffer.len() {
+                        return Ok(None);
+                    }
+                    return Ok(Some(LiveChunk::Raw(self.buffer.split_to(end).freeze())));
+                }
+                State::Cluster(scan) => {
+                    if Some(scan.position) == scan.end {
+                        return Ok(Some(self.take_cluster()));
+                    }
+
+                    let Some(header) = ElementHeader::read(&self.buffer[scan.position..])? else {
+                        return Ok(None);
+                    };
+                    if scan.end.is_none() && element_id::is_top_level(header.id) {
+                        return Ok(Some(self.take_cluster()));
+                    }
+
+                    let body_start = scan.position + header.len;
+                    let end = body_start + header.known_size()?;
+                    if end > self.buffer.len() {
+                        return Ok(None);
+                    }
+
+                    let body = &self.buffer[body_start..end];
+                    match header.id {
+                        element_id::TIMESTAMP => {
+                            scan.timestamp = body
+                                .iter()
+                                .fold(0u64, |value, byte| (value << 8) | *byte as u64);
+                            scan.timestamp_range = scan.position..end;
+                        }
+                        element_id::SIMPLE_BLOCK => {
+                            let block = BlockHeader::parse(body)
+                                .context("SimpleBlock too short for its header")?;
+                            if scan.keyframe.is_none()
+                                && self.video_track.is_none_or(|track| track == block.track)
+                            {
+                                scan.keyframe = Some(block.is_keyframe());
+                            }
+                            scan.last_frame_offset = scan
+                                .last_frame_offset
+                                .max(block.relative_timestamp.max(0) as u64);
+                        }
+                        _ => {}
+                    }
+                    scan.position = end;
+                }
+            }
+        }
+    }
+
+    fn take_cluster(&mut self) -> LiveChunk {
+        let State::Cluster(scan) = std::mem::replace(&mut self.state, State::Segment) else {
+            unreachable!("only called while reading a cluster");
+        };
+
+        LiveChunk::Cluster(LiveCluster {
+            data: self.buffer.split_to(scan.position).freeze(),
+            timestamp: scan.timestamp,
+            keyframe: scan.keyframe.unwrap_or(false),
+            last_frame_offset: scan.last_frame_offset,
+            body_start: scan.body_start,
+            timestamp_range: scan.timestamp_range,
+        })
+    }
+}
+
+fn find_video_track(header: &[u8]) -> Option<u64> {
+    WebmIterator::new(Cursor::new(header), &[MatroskaSpec::Tracks(Master::Start)])
+        .map_while(Result::ok)
+        .find_map(|tag| match tag {
+            MatroskaSpec::Tracks(Master::Full(entries)) => find_cue_track(&entries),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::jrec::webm::fixture;
+
+    use super::*;
+
+    fn split(recording: &[u8], piece_size: usize) -> Vec<LiveChunk> {
+        split_counting(recording, piece_size, &Counter::default())
+    }
+
+    fn split_counting(recording: &[u8], piece_size: usize, errors: &Counter) -> Vec<LiveChunk> {
+        let mut splitter = ClusterSplitter::new(usize::MAX, errors.clone());
+        let mut chunks: Vec<_> = recording
+            .chunks(piece_size)
+            .flat_map(|piece| splitter.push(piece))
+            .collect();
+        chunks.extend(splitter.finish());
+        chunks
+    }
+
+    #[test]
+    fn test_split_recording_in_small_pieces() {
+        let recording = fixture::recording(3, 4);
+        let chunks = split(&recording, 7);
+
+        let rejoined: Vec<u8> = chunks
+            .iter()
+            .flat_map(|chunk| chunk.bytes().to_vec())
+            .collect();
+        assert_eq!(recording, rejoined);
+
+        assert!(matches!(&chunks[0], LiveChunk::Header(header) if *header == fixture::header()));
+        let clusters: Vec<_> = chunks[1..]
+            .iter()
+            .map(|chunk| match chunk {
+                LiveChunk::Cluster(cluster) => (cluster.timestamp, cluster.keyframe),
+                _ => panic!("expected only clusters after the header"),
+            })
+            .collect();
+        assert_eq!(vec![(0, true), (132, true), (264, true)], clusters);
+    }
+
+    #[test]
+    fn test_cut_off_cluster_is_raw() {
+        let recording = fixture::recording(2, 4);
+        let chunks = split(&recording[..recording.len() - 3], 64);
+
+        assert!(matches!(chunks.last(), Some(LiveChunk::Raw(_))));
+        assert_eq!(
+            2,
+            chunks
+                .iter()
+                .filter(|chunk| !matches!(chunk, LiveChunk::Raw(_)))
+                .count()
+        );
+    }
+
+    #[test]
+    fn test_unreadable_data_is_raw_and_counted() {
+        let mut recording = fixture::recording(1, 4);
+        recording.extend_from_slice(&[0; 16]);
+        let errors = Counter::default();
+        let chunks = split_counting(&recording, 4096, &errors);
+
+        // The cluster the data was found in goes out raw with it
+        assert!(matches!(chunks.last(), Some(LiveChunk::Raw(raw)) if raw.ends_with(&[0; 16])));
+        assert_eq!(1, errors.get());
+    }
+
+    #[test]
+    fn test_oversized_cluster_is_not_buffered() {
+        // A lone unknown-sized Cluster only ends with the push
+        let recording = fixture::recording(1, 8);
+        let header_len = fixture::header().len();
+        let mut splitter = ClusterSplitter::new(header_len + 64, Counter::default());
+        let chunks = splitter.push(&recording);
+
+        assert!(matches!(&chunks[0], LiveChunk::Header(_)));
+        assert!(
+            matches!(&chunks[1..], [LiveChunk::Raw(raw)] if raw.len() == recording.len() - header_len)
+        );
+        // Anything after goes straight through
+        assert!(matches!(
+            splitter.push(&[1, 2, 3]).as_slice(),
+            [LiveChunk::Raw(_)]
+        ));
+    }
+
+    #[test]
+    fn test_rebased_cluster_starts_at_zero() {
+        let recording = fixture::recording(2, 4);
+        let Some(LiveChunk::Cluster(cluster)) = split(&recording, 4096).pop() else {
+            panic!("expected the last chunk to be a cluster");
+        };
+        assert_eq!(132, cluster.timestamp);
+        assert_eq!(3 * fixture::FRAME_DURATION_MS, cluster.last_frame_offset);
+
+        let rebased = cluster.rebased(cluster.timestamp);
+        let timestamps: Vec<_> = WebmIterator::new(Cursor::new(rebased.to_vec()), &[])
+            .map_while(Result::ok)
+            .filter_map(|tag| match tag {
+                MatroskaSpec::Timestamp(timestamp) => Some(timestamp),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(vec![0], timestamps);
+
+        // Moved again from there, as a resumed push session does
+        let shifted = cluster.with_timestamp(1000);
+        assert_eq!(1000, shifted.timestamp);
+        assert_eq!(
+            shifted.with_timestamp(1100).data,
+            cluster.with_timestamp(1100).data
+        );
+    }
+}
@@ -1,4 +1,5 @@
 pub mod axum_range;
+pub mod config;
 pub mod jrec;
 pub mod transport;
 pub mod utils;
@@ -1,33 +1,44 @@
-use std::time::Duration;
-
 use anyhow::Context;
-use axum::{http::HeaderName, Router};
+use axum::{
+    http::{HeaderName, HeaderValue},
+    Router,
+};
+use config::ServerConfig;
 use hyper::Request;
-use tokio::net::TcpListener;
+use jrec::auth;
+use tokio::{net::TcpListener, sync::watch};
 use tower_http::{
-    cors::{Any, CorsLayer},
+    cors::{AllowOrigin, Any, CorsLayer},
     trace::TraceLayer,
 };
-use tracing::{info, Span};
+use tracing::{error, info, warn, Span};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-use utils::state::AppState;
+use transport::tls::TlsServer;
+use utils::{retention, state::AppState, webhooks};
 
 pub mod axum_range;
+pub mod config;
 pub mod jrec;
 pub mod transport;
 pub mod utils;
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let config = ServerConfig::load()?;
+
+    let env_filter = match config.log_filter.as_deref() {
+        Some(log_filter) => tracing_subscriber::EnvFilter::try_new(log_filter)
+            .with_context(|| format!("invalid log filter {:?}", log_filter))?,
+        None => tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+            format!(
+                "{}=trace,tower_http=trace,axum=trace",
+                env!("CARGO_CRATE_NAME")
+            )
+            .into()
+        }),
+    };
+
     tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| {
-                format!(
-                    "{}=trace,tower_http=trace,axum=trace",
-                    env!("CARGO_CRATE_NAME")
-                )
-                .into()
-            }),
-        )
+        .with(env_filter)
         .with(
             tracing_subscriber::fmt::layer()
                 .with_file(true)
@@ -35,19 +46,31 @@ async fn main() -> anyhow::Result<()> {
         )
         .init();
 
-    let router = jrec::make_router();
-    let state = AppState::new();
+    tokio::fs::create_dir_all(&config.recording_dir)
+        .await
+        .with_context(|| format!("creating recording directory {:?}", config.recording_dir))?;
+
+    let listen = config.listen;
+    let cors = cors_layer(&config)?;
+
+    let token_verifier = auth::load_verifier(&config.auth)?;
+    let tls = TlsServer::load(&config.tls)?;
+    let state = AppState::new(config, token_verifier);
+    let router = jrec::make_router(&state);
+    // Subscribed first, the recordings recovered are notified about too
+    let webhooks = webhooks::spawn(&state.recording_manager()).await?;
+    let recovered = state.recording_manager().recover().await?;
+    if recovered > 0 {
+        info!(
+            recovered,
+            "Recovered recordings interrupted by the last shutdown"
+        );
+    }
+    retention::spawn(state.recording_manager());
     let app = Router::new()
         .nest("/", router)
-        .with_state(state)
-        .layer(
-            CorsLayer::new()
-                .allow_origin(Any) // Allow any origin
-                .allow_headers(Any) // Allow any headers
-                .allow_methods(Any) // Allow any HTTP methods
-                .expose_headers(Any)
-                .max_age(Duration::from_secs(86400)), // Expose any headers
-        )
+        .with_state(state.clone())
+        .layer(cors)
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(|request: &Request<_>| {
@@ -79,11 +102,105 @@ async fn main() -> anyhow::Result<()> {
                 ),
         );
 
-    let listener = TcpListener::bind("127.0.0.1:3000")
+    let listener = TcpListener::bind(listen)
         .await
-        .context("binding to port 3000")?;
+        .with_context(|| format!("binding to {}", listen))?;
+
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    info!("listening on {}://{}", scheme, listener.local_addr()?);
+
+    let recording_manager = state.recording_manager();
+    let shutdown_deadline = state.config().shutdown_deadline();
+    let (shutdown_sender, mut shutdown_receiver) = watch::channel(false);
+    let graceful = async move {
+        shutdown_signal().await;
+        info!("Shutting down, finishing active recordings");
+        shutdown_sender.send(true).ok();
+        recording_manager.shutdown().await;
+    };
+    let server = async move {
+        match tls {
+            Some(tls) => tls.serve(listener, app, graceful).await?,
+            None => axum::serve(listener, app)
+                .with_graceful_shutdown(graceful)
+                .await
+                .context("running server")?,
+        }
+        // The last events of the shutdown still make it to the outbox
+        if let Some(webhooks) = webhooks {
+            webhooks.await.ok();
+        }
+        anyhow::Ok(())
+    };
+
+    // Whatever is not done by the deadline is cut off, interrupted recordings are recovered on the next start
+    let deadline = async {
+        shutdown_receiver
+            .wait_for(|requested| *requested)
+            .await
+            .ok();
+        tokio::time::sleep(shutdown_deadline).await;
+    };
+
+    tokio::select! {
+        result = server => result,
+        () = deadline => {
+            warn!(?shutdown_deadline, "Shutdown deadline reached, exiting anyway");
+            Ok(())
+        }
+    }
+}
+
+/// Ctrl-C, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!(?e, "Failed to listen for Ctrl-C");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!(?e, "Failed to listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+}
 
-    info!("listening on {}", listener.local_addr()?);
+fn cors_layer(config: &ServerConfig) -> anyhow::Result<CorsLayer> {
+    let allow_origin = if config.cors.allowed_origins.is_empty() {
+        AllowOrigin::any()
+    } else {
+        let origins = config
+            .cors
+            .allowed_origins
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin)
+                    .with_context(|| format!("invalid CORS origin {:?}", origin))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        AllowOrigin::list(origins)
+    };
 
-    axum::serve(listener, app).await.context("running server")
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_headers(Any) // Allow any headers
+        .allow_methods(Any) // Allow any HTTP methods
+        .expose_headers(Any) // Expose any headers
+        .max_age(config.cors_max_age()))
 }
@@ -1,4 +1,5 @@
 mod copy_bidirectional;
+pub mod tls;
 mod ws;
 
 pub use self::copy_bidirectional::*;
@@ -0,0 +1,341 @@
+//! TLS termination, so browsers can push and watch over `https://` and `wss://` without a reverse proxy.
+
+use std::{
+    collections::HashSet,
+    fmt,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::Context;
+use axum::Router;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{debug, error, info, warn};
+
+use crate::config::TlsConfig;
+
+/// A client that has not finished its handshake by then is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Hands out the certificate last read from disk, renewals are picked up by new connections.
+pub struct ReloadingCertResolver {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    provider: Arc<CryptoProvider>,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl fmt::Debug for ReloadingCertResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReloadingCertResolver")
+            .field("cert_file", &self.cert_file)
+            .field("key_file", &self.key_file)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ReloadingCertResolver {
+    pub fn new(
+        cert_file: PathBuf,
+        key_file: PathBuf,
+        provider: Arc<CryptoProvider>,
+    ) -> anyhow::Result<Self> {
+        let current = load_certified_key(&cert_file, &key_file, &provider)?;
+        Ok(Self {
+            cert_file,
+            key_file,
+            provider,
+            current: RwLock::new(Arc::new(current)),
+        })
+    }
+
+    /// Reads the files again, the certificate in use is kept when they do not make a valid pair.
+    /// Returns whether the certificate changed.
+    pub fn reload(&self) -> anyhow::Result<bool> {
+        let loaded = load_certified_key(&self.cert_file, &self.key_file, &self.provider)?;
+        let mut current = self.current.write().unwrap();
+        if current.cert == loaded.cert {
+            return Ok(false);
+        }
+
+        *current = Arc::new(loaded);
+        Ok(true)
+    }
+
+    pub fn current(&self) -> Arc<CertifiedKey> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Reloads on every change in the directories of the two files, which also catches renewals
+    /// that swap a symlink or rename a new file over the old one. Stops when the watcher is dropped.
+    pub fn watch(self: &Arc<Self>) -> anyhow::Result<RecommendedWatcher> {
+        let resolver = Arc::clone(self);
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                match event {
+                    Ok(event) if matches!(event.kind, EventKind::Access(_)) => {}
+                    Ok(_) => match resolver.reload() {
+                        Ok(true) => {
+                            info!(cert_file = ?resolver.cert_file, "Reloaded TLS certificate")
+                        }
+                        Ok(false) => {}
+                        // Usually a renewal halfway through, with the certificate written and not the key yet
+                        Err(e) => warn!(?e, "Keeping the current TLS certificate"),
+                    },
+                    Err(e) => error!(?e, "Failed to watch the TLS certificate"),
+                }
+            })?;
+
+        let directories: HashSet<_> = [&self.cert_file, &self.key_file]
+            .into_iter()
+            .map(|file| match file.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            })
+            .collect();
+        for directory in directories {
+            watcher
+                .watch(directory, RecursiveMode::NonRecursive)
+                .with_context(|| format!("watching {:?}", directory))?;
+        }
+
+        Ok(watcher)
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current())
+    }
+}
+
+fn load_certified_key(
+    cert_file: &Path,
+    key_file: &Path,
+    provider: &CryptoProvider,
+) -> anyhow::Result<CertifiedKey> {
+    let cert_chain = CertificateDer::pem_file_iter(cert_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("reading TLS certificate file {:?}", cert_file))?;
+    anyhow::ensure!(
+        !cert_chain.is_empty(),
+        "no certificate in TLS certificate file {:?}",
+        cert_file
+    );
+    let key = PrivateKeyDer::from_pem_file(key_file)
+        .with_context(|| format!("reading TLS key file {:?}", key_file))?;
+
+    CertifiedKey::from_der(cert_chain, key, provider)
+        .with_context(|| format!("TLS key {:?} does not match {:?}", key_file, cert_file))
+}
+
+/// Serves the router over TLS, with the certificate reloaded as long as the server runs.
+pub struct TlsServer {
+    resolver: Arc<ReloadingCertResolver>,
+    acceptor: TlsAcceptor,
+}
+
+impl TlsServer {
+    /// The server `config` asks for, `None` when TLS is off.
+    pub fn load(config: &TlsConfig) -> anyhow::Result<Option<Self>> {
+        let (cert_file, key_file) = match (&config.cert_file, &config.key_file) {
+            (None, None) => return Ok(None),
+            (Some(cert_file), Some(key_file)) => (cert_file.clone(), key_file.clone()),
+            _ => anyhow::bail!("TLS needs both a certificate file and a key file"),
+        };
+
+        let provider = Arc::new(ring::default_provider());
+        let resolver = Arc::new(ReloadingCertResolver::new(
+            cert_file,
+            key_file,
+            Arc::clone(&provider),
+        )?);
+
+        let mut server_config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver.clone());
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(Some(Self {
+            resolver,
+            acceptor: TlsAcceptor::from(Arc::new(server_config)),
+        }))
+    }
+
+    pub fn resolver(&self) -> &Arc<ReloadingCertResolver> {
+        &self.resolver
+    }
+
+    /// Accepts connections until `signal` completes, then waits for the open ones like `axum::serve` does.
+    pub async fn serve(
+        self,
+        listener: TcpListener,
+        app: Router,
+        signal: impl Future<Output = ()>,
+    ) -> anyhow::Result<()> {
+        let _watcher = self.resolver.watch()?;
+        let shutdown = CancellationToken::new();
+        let connections = TaskTracker::new();
+
+        tokio::pin!(signal);
+        loop {
+            let (stream, remote_addr) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        // Mostly running out of file descriptors, give the open connections time to close
+                        error!(?e, "Failed to accept connection");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                },
+                () = &mut signal => break,
+            };
+
+            let acceptor = self.acceptor.clone();
+            let service = TowerToHyperService::new(app.clone());
+            let shutdown = shutdown.clone();
+            connections.spawn(async move {
+                let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream));
+                let stream = tokio::select! {
+                    result = handshake => match result {
+                        Ok(Ok(stream)) => stream,
+                        Ok(Err(e)) => {
+                            debug!(?e, %remote_addr, "TLS handshake failed");
+                            return;
+                        }
+                        Err(_) => {
+                            debug!(%remote_addr, "TLS handshake timed out");
+                            return;
+                        }
+                    },
+                    () = shutdown.cancelled() => return,
+                };
+
+                let builder = auto::Builder::new(TokioExecutor::new());
+                let connection =
+                    builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+                tokio::pin!(connection);
+                let result = tokio::select! {
+                    result = connection.as_mut() => result,
+                    () = shutdown.cancelled() => {
+                        // Lets the request in flight finish, upgraded connections live on in their own task
+                        connection.as_mut().graceful_shutdown();
+                        connection.await
+                    }
+                };
+                if let Err(e) = result {
+                    debug!(?e, %remote_addr, "Connection closed with an error");
+                }
+            });
+        }
+
+        drop(listener);
+        shutdown.cancel();
+        connections.close();
+        connections.wait().await;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use rustls::{pki_types::ServerName, RootCertStore};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    use super::*;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test")
+            .join("tls")
+            .join(name)
+    }
+
+    /// GETs `/` trusting only `ca_file`, returns the status line.
+    async fn get(addr: std::net::SocketAddr, ca_file: &Path) -> anyhow::Result<String> {
+        let mut roots = RootCertStore::empty();
+        roots.add(CertificateDer::from_pem_file(ca_file)?)?;
+        let mut config =
+            rustls::ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()?
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        let stream = tokio::net::TcpStream::connect(addr).await?;
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost")?, stream)
+            .await?;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+
+        Ok(response.lines().next().unwrap_or_default().to_string())
+    }
+
+    #[tokio::test]
+    async fn test_serves_the_certificate_on_disk() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = TlsConfig {
+            cert_file: Some(dir.path().join("server.crt")),
+            key_file: Some(dir.path().join("server.key")),
+        };
+        std::fs::copy(fixture("first.crt"), dir.path().join("server.crt"))?;
+        std::fs::copy(fixture("first.key"), dir.path().join("server.key"))?;
+
+        let server = TlsServer::load(&config)?.expect("TLS is configured");
+        let resolver = Arc::clone(server.resolver());
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let app = Router::new().route("/", axum::routing::get(|| async { "ok" }));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let serving = tokio::spawn(server.serve(listener, app, async {
+            stopped.await.ok();
+        }));
+
+        assert_eq!(get(addr, &fixture("first.crt")).await?, "HTTP/1.1 200 OK");
+        assert!(get(addr, &fixture("second.crt")).await.is_err());
+
+        let first = resolver.current();
+        std::fs::copy(fixture("second.key"), dir.path().join("server.key"))?;
+        std::fs::copy(fixture("second.crt"), dir.path().join("server.crt"))?;
+        let started = Instant::now();
+        while Arc::ptr_eq(&first, &resolver.current()) {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "certificate not reloaded"
+            );
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        assert_eq!(get(addr, &fixture("second.crt")).await?, "HTTP/1.1 200 OK");
+
+        stop.send(()).ok();
+        serving.await??;
+        Ok(())
+    }
+}
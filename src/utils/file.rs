@@ -1,33 +1,97 @@
-use std::path::Path;
+use std::{fs::Metadata, path::Path, time::SystemTime};
 
-use tokio::{fs::OpenOptions, io};
-use winapi::um::winnt::{FILE_SHARE_READ, FILE_SHARE_WRITE};
+use tokio::io;
+
+// Recordings are read by viewers while the push session is still appending to them,
+// so every open in the crate has to go through these helpers.
+//
+// On Windows a file is exclusive unless the share mode says otherwise, on Unix concurrent
+// readers and writers are always allowed, so there is nothing to do there.
+// FILE_SHARE_DELETE lets finalization rename a new file over a recording someone is still reading.
+#[cfg(windows)]
+mod platform {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    use winapi::um::winnt::{FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE};
+
+    pub(super) fn share(options: &mut std::fs::OpenOptions) -> &mut std::fs::OpenOptions {
+        options.share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE)
+    }
+
+    // The volume serial number and the NTFS file index. Not the creation time: file system tunneling
+    // gives a file renamed over a name that was just replaced the creation time of the file it replaces
+    pub(super) fn identity(file: &std::fs::File) -> std::io::Result<(u64, u64)> {
+        use std::os::windows::io::AsRawHandle;
+
+        use winapi::um::fileapi::{GetFileInformationByHandle, BY_HANDLE_FILE_INFORMATION};
+
+        // SAFETY: a plain C struct, all zeroes is a valid value
+        let mut info: BY_HANDLE_FILE_INFORMATION = unsafe { std::mem::zeroed() };
+        // SAFETY: the handle stays open while `file` is borrowed, `info` is only written to
+        if unsafe { GetFileInformationByHandle(file.as_raw_handle() as _, &mut info) } == 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let file_index = (u64::from(info.nFileIndexHigh) << 32) | u64::from(info.nFileIndexLow);
+        Ok((u64::from(info.dwVolumeSerialNumber), file_index))
+    }
+}
+
+#[cfg(not(windows))]
+mod platform {
+    pub(super) fn share(options: &mut std::fs::OpenOptions) -> &mut std::fs::OpenOptions {
+        options
+    }
+
+    pub(super) fn identity(file: &std::fs::File) -> std::io::Result<(u64, u64)> {
+        use std::os::unix::fs::MetadataExt;
+
+        let metadata = file.metadata()?;
+        Ok((metadata.dev(), metadata.ino()))
+    }
+}
+
+/// [`std::fs::OpenOptions`] that allow other handles to read and write the same file.
+pub fn shared_open_options() -> std::fs::OpenOptions {
+    let mut options = std::fs::OpenOptions::new();
+    platform::share(&mut options);
+    options
+}
+
+pub fn std_open_read(path: &Path) -> io::Result<std::fs::File> {
+    shared_open_options().read(true).open(path)
+}
+
+/// Opens an existing file for writing, without truncating it.
+pub fn std_open_write(path: &Path) -> io::Result<std::fs::File> {
+    shared_open_options().write(true).open(path)
+}
 
 pub async fn open_read(path: &Path) -> io::Result<tokio::fs::File> {
-    let file = tokio::fs::OpenOptions::new()
-        .read(true)
-        .share_mode(FILE_SHARE_WRITE | FILE_SHARE_READ)
-        .open(&path)
-        .await?;
+    let mut options = shared_open_options();
+    options.read(true);
 
-    Ok(file)
+    tokio::fs::OpenOptions::from(options).open(path).await
 }
 
 pub async fn open_write(path: &Path) -> io::Result<tokio::fs::File> {
-    let file = OpenOptions::new()
-        .read(false)
-        .write(true)
-        .truncate(true)
-        .create(true)
-        .share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE)
-        .open(path)
-        .await?;
+    let mut options = shared_open_options();
+    options.read(false).write(true).truncate(true).create(true);
 
-    Ok(file)
+    tokio::fs::OpenOptions::from(options).open(path).await
 }
 
-pub async fn create_time(file: &tokio::fs::File) -> io::Result<std::time::SystemTime> {
+pub async fn create_time(file: &tokio::fs::File) -> io::Result<SystemTime> {
     let metadata = file.metadata().await?;
-    let created = metadata.created()?;
-    Ok(created)
+    created_or_modified(&metadata)
+}
+
+/// Tells a file from the one that was at the same path before, when finalization renamed a new file over it.
+pub fn identity(file: &std::fs::File) -> io::Result<(u64, u64)> {
+    platform::identity(file)
+}
+
+/// Birth time is not available on every Linux filesystem, fall back to the modification time there.
+pub fn created_or_modified(metadata: &Metadata) -> io::Result<SystemTime> {
+    metadata.created().or_else(|_| metadata.modified())
 }
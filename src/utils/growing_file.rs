@@ -0,0 +1,343 @@
+use std::path::PathBuf;
+
+use bytes::{Bytes, BytesMut};
+use futures::Stream;
+use prometheus_client::metrics::counter::Counter;
+use tokio::{io::AsyncReadExt, sync::watch};
+
+use crate::jrec::{
+    metadata::RecordingMetadata,
+    webm::splitter::{ClusterSplitter, LiveChunk},
+};
+
+use super::file::open_read;
+
+/// Most bytes [`tail_segments`] reads in one go.
+const TAIL_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// How far the push session has written its recording.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WriteProgress {
+    pub bytes_written: u64,
+    /// Bytes of every segment, unlike `bytes_written` it keeps counting once rotation finished the first one
+    pub total_bytes: u64,
+    /// No more bytes will be appended to the first segment
+    pub finished: bool,
+    /// Segments completed by rotation, the one written is `rotations` counting from zero
+    pub rotations: usize,
+    /// The push session is over, no segment grows anymore
+    pub ended: bool,
+}
+
+/// Creates the notifier owned by the writer task and the first handle readers subscribe from.
+pub fn channel() -> (WriteNotifier, GrowingFile) {
+    let (sender, receiver) = watch::channel(WriteProgress::default());
+    (WriteNotifier { sender }, GrowingFile { receiver })
+}
+
+/// Writer side, announces every chunk once it has reached the file.
+#[derive(Debug)]
+pub struct WriteNotifier {
+    sender: watch::Sender<WriteProgress>,
+}
+
+impl WriteNotifier {
+    pub fn wrote(&self, bytes_written: u64) {
+        self.sender.send_modify(|progress| {
+            progress.bytes_written = bytes_written;
+            progress.total_bytes = bytes_written;
+        });
+    }
+
+    /// Written to a later segment, readers of the file see nothing new.
+    pub fn wrote_elsewhere(&self, total_bytes: u64) {
+        self.sender
+            .send_modify(|progress| progress.total_bytes = total_bytes);
+    }
+
+    /// The segment written so far is complete, what follows goes to the next one.
+    pub fn rotated(&self) {
+        self.sender.send_modify(|progress| {
+            progress.finished = true;
+            progress.rotations += 1;
+        });
+    }
+
+    pub fn finish(&self) {
+        self.sender.send_modify(|progress| {
+            progress.finished = true;
+            progress.ended = true;
+        });
+    }
+}
+
+impl Drop for WriteNotifier {
+    // Readers must not wait forever on a writer task that died
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// Reader side, wakes up as soon as new bytes are on disk instead of polling the file.
+#[derive(Debug, Clone)]
+pub struct GrowingFile {
+    receiver: watch::Receiver<WriteProgress>,
+}
+
+impl GrowingFile {
+    pub fn progress(&self) -> WriteProgress {
+        *self.receiver.borrow()
+    }
+
+    /// Waits for the next write after the last one this handle was woken up for.
+    /// Returns immediately once the recording is finished.
+    pub async fn changed(&mut self) -> WriteProgress {
+        if self.receiver.changed().await.is_err() {
+            // The notifier marks the progress finished before going away
+            return self.progress();
+        }
+
+        *self.receiver.borrow_and_update()
+    }
+
+    /// [`Self::changed`] for the parsers running in `spawn_blocking`.
+    pub fn blocking_changed(&mut self) -> WriteProgress {
+        futures::executor::block_on(self.changed())
+    }
+
+    /// Waits until there are bytes past `position`, or the recording is finished.
+    pub async fn wait_for_data(&mut self, position: u64) -> WriteProgress {
+        let waited = self
+            .receiver
+            .wait_for(|progress| progress.finished || progress.bytes_written > position)
+            .await
+            .map(|progress| *progress);
+        waited.unwrap_or_else(|_| self.progress())
+    }
+
+    /// [`Self::wait_for_data`] across segments: waits until the recording holds more than `total_position`
+    /// bytes in all, `segment` was completed by rotation, or the push session ended.
+    pub async fn wait_for_segment_data(
+        &mut self,
+        segment: usize,
+        total_position: u64,
+    ) -> WriteProgress {
+        let waited = self
+            .receiver
+            .wait_for(|progress| {
+                progress.ended
+                    || progress.rotations > segment
+                    || progress.total_bytes > total_position
+            })
+            .await
+            .map(|progress| *progress);
+        waited.unwrap_or_else(|_| self.progress())
+    }
+}
+
+/// The content of a recording from its start, following the push session until it ends.
+///
+/// `file` must be open at its start, nothing past what the writer announced is read,
+/// so a reader never sees a half-written chunk. The clusters of the segments the recording is rotated into
+/// follow those of the first one, back at their timestamps in the push and without the header every
+/// segment repeats. `max_element_bytes` bounds what is buffered to find them.
+pub fn tail_segments(
+    recording_path: PathBuf,
+    file: tokio::fs::File,
+    progress: GrowingFile,
+    max_element_bytes: usize,
+) -> impl Stream<Item = std::io::Result<Bytes>> {
+    let tail = SegmentTail {
+        recording_path,
+        progress,
+        segment: 0,
+        file,
+        position: 0,
+        earlier_bytes: 0,
+        rewrite: None,
+        max_element_bytes,
+    };
+
+    futures::stream::try_unfold(tail, |mut tail| async move {
+        let chunk = tail.next_chunk().await?;
+        Ok(chunk.map(|chunk| (chunk, tail)))
+    })
+}
+
+struct SegmentTail {
+    recording_path: PathBuf,
+    progress: GrowingFile,
+    /// Index of the segment read
+    segment: usize,
+    file: tokio::fs::File,
+    /// Bytes read from the segment
+    position: u64,
+    /// Bytes of the segments before it
+    earlier_bytes: u64,
+    /// Where the clusters of a later segment are cut, with the timestamp the segment starts at
+    rewrite: Option<(ClusterSplitter, u64)>,
+    max_element_bytes: usize,
+}
+
+impl SegmentTail {
+    async fn next_chunk(&mut self) -> std::io::Result<Option<Bytes>> {
+        loop {
+            let progress = self
+                .progress
+                .wait_for_segment_data(self.segment, self.earlier_bytes + self.position)
+                .await;
+
+            if progress.rotations > self.segment {
+                // The writer closed the segment before announcing the next one, all of it is on disk
+                let mut rest = vec![];
+                self.file.read_to_end(&mut rest).await?;
+                self.position += rest.len() as u64;
+                let mut chunk = self.rewrite(&rest);
+                chunk.extend_from_slice(&self.finish_segment());
+                self.next_segment().await?;
+
+                if !chunk.is_empty() {
+                    return Ok(Some(chunk.freeze()));
+                }
+                continue;
+            }
+
+            let available = progress
+                .total_bytes
+                .saturating_sub(self.earlier_bytes + self.position);
+            if available == 0 {
+                // Ended, and everything has been read
+                let chunk = self.finish_segment();
+                return Ok((!chunk.is_empty()).then(|| chunk.freeze()));
+            }
+
+            let mut data = vec![0; available.min(TAIL_CHUNK_SIZE) as usize];
+            self.file.read_exact(&mut data).await?;
+            self.position += data.len() as u64;
+            let chunk = self.rewrite(&data);
+            if !chunk.is_empty() {
+                return Ok(Some(chunk.freeze()));
+            }
+        }
+    }
+
+    /// The first segment goes out as it is.
+    fn rewrite(&mut self, data: &[u8]) -> BytesMut {
+        let Some((splitter, start_timestamp)) = &mut self.rewrite else {
+            return BytesMut::from(data);
+        };
+
+        let mut chunk = BytesMut::new();
+        for live_chunk in splitter.push(data) {
+            put_rewritten(&mut chunk, live_chunk, *start_timestamp);
+        }
+        chunk
+    }
+
+    /// What the splitter of a later segment still holds once nothing more is written to it.
+    fn finish_segment(&mut self) -> BytesMut {
+        let mut chunk = BytesMut::new();
+        if let Some((splitter, start_timestamp)) = self.rewrite.take() {
+            if let Some(live_chunk) = splitter.finish() {
+                put_rewritten(&mut chunk, live_chunk, start_timestamp);
+            }
+        }
+        chunk
+    }
+
+    async fn next_segment(&mut self) -> std::io::Result<()> {
+        self.earlier_bytes += self.position;
+        self.segment += 1;
+        self.position = 0;
+
+        // Listed in the sidecar before its file was created
+        let segment = RecordingMetadata::load(&self.recording_path)
+            .await
+            .map_err(std::io::Error::other)?
+            .and_then(|metadata| metadata.segments.get(self.segment).cloned())
+            .ok_or_else(|| std::io::Error::other("rotated segment is not in the sidecar"))?;
+        self.file = open_read(&self.recording_path.with_file_name(&segment.file_name)).await?;
+        // Written from clusters the push session split already, it counted its own parser errors
+        let splitter = ClusterSplitter::new(self.max_element_bytes, Counter::default());
+        self.rewrite = Some((splitter, segment.start_timestamp));
+
+        Ok(())
+    }
+}
+
+fn put_rewritten(chunk: &mut BytesMut, live_chunk: LiveChunk, start_timestamp: u64) {
+    match live_chunk {
+        // The stream already started with the same header
+        LiveChunk::Header(_) => {}
+        LiveChunk::Cluster(cluster) => chunk.extend_from_slice(
+            &cluster
+                .with_timestamp(cluster.timestamp + start_timestamp)
+                .data,
+        ),
+        LiveChunk::Raw(data) => chunk.extend_from_slice(&data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_readers_wake_on_write_and_finish() {
+        let (notifier, mut growing_file) = channel();
+
+        let waiter = tokio::spawn(async move { growing_file.wait_for_data(10).await });
+        notifier.wrote(5);
+        notifier.wrote(12);
+        assert_eq!(12, waiter.await.unwrap().bytes_written);
+
+        let mut growing_file = GrowingFile {
+            receiver: notifier.sender.subscribe(),
+        };
+        drop(notifier);
+        assert!(growing_file.changed().await.finished);
+    }
+
+    #[test]
+    fn test_total_bytes_go_on_after_the_file_is_finished() {
+        let (notifier, growing_file) = channel();
+        notifier.wrote(12);
+        notifier.rotated();
+        notifier.wrote_elsewhere(30);
+
+        let progress = growing_file.progress();
+        assert_eq!(12, progress.bytes_written);
+        assert_eq!(30, progress.total_bytes);
+        assert!(progress.finished);
+        assert_eq!(1, progress.rotations);
+        assert!(!progress.ended);
+    }
+
+    #[tokio::test]
+    async fn test_tail_follows_the_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("recording.webm");
+        let mut writer = tokio::fs::File::create(&path).await.unwrap();
+        let (notifier, growing_file) = channel();
+
+        let reader = tokio::fs::File::open(&path).await.unwrap();
+        let tailed = tokio::spawn(
+            tail_segments(path.clone(), reader, growing_file, usize::MAX)
+                .map_ok(|chunk| chunk.to_vec())
+                .try_concat(),
+        );
+
+        writer.write_all(b"first ").await.unwrap();
+        writer.flush().await.unwrap();
+        notifier.wrote(6);
+        writer.write_all(b"second").await.unwrap();
+        writer.flush().await.unwrap();
+        notifier.wrote(12);
+        notifier.finish();
+
+        assert_eq!(b"first second".to_vec(), tailed.await.unwrap().unwrap());
+    }
+}
@@ -0,0 +1,304 @@
+//! Prometheus metrics of the server, owned by the recording manager and scraped from `/metrics`.
+//!
+//! Counters are bumped where things happen, gauges describing the recordings are set when scraped.
+
+use std::{
+    fmt::{self, Write},
+    path::Path,
+    pin::Pin,
+    sync::{atomic::AtomicU64, Arc},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::Response,
+};
+use bytes::Bytes;
+use http_body::{Frame, SizeHint};
+use pin_project_lite::pin_project;
+use prometheus_client::{
+    encoding::{EncodeLabelSet, EncodeLabelValue, LabelValueEncoder},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::Registry,
+};
+
+use super::{recording_manager::RecordingManager, retention};
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct RecordingLabels {
+    pub recording: String,
+}
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub enum Transport {
+    Http,
+    Websocket,
+}
+
+impl EncodeLabelValue for Transport {
+    fn encode(&self, encoder: &mut LabelValueEncoder) -> fmt::Result {
+        encoder.write_str(match self {
+            Transport::Http => "http",
+            Transport::Websocket => "websocket",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct TransportLabels {
+    pub transport: Transport,
+}
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct StatusLabels {
+    pub status: u16,
+}
+
+type U64Gauge = Gauge<u64, AtomicU64>;
+
+#[derive(Debug)]
+pub struct Metrics {
+    registry: Registry,
+    pub active_recordings: U64Gauge,
+    /// Live viewers of each active recording
+    pub recording_viewers: Family<RecordingLabels, U64Gauge>,
+    /// Stays flat while a push session is stuck
+    pub recording_bytes_written: Family<RecordingLabels, U64Gauge>,
+    pub ingested_bytes: Counter,
+    pub served_bytes: Family<TransportLabels, Counter>,
+    /// Push sessions whose data the cluster splitter could not parse, their live viewers got no more clusters
+    pub stream_parser_errors: Counter,
+    pub range_requests: Family<StatusLabels, Counter>,
+    pub finalize_duration_seconds: Histogram,
+    pub recording_dir_bytes: U64Gauge,
+    pub recording_dir_free_bytes: U64Gauge,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let mut metrics = Self {
+            registry: Registry::with_prefix("webm_streamer"),
+            active_recordings: Default::default(),
+            recording_viewers: Default::default(),
+            recording_bytes_written: Default::default(),
+            ingested_bytes: Default::default(),
+            served_bytes: Default::default(),
+            stream_parser_errors: Default::default(),
+            range_requests: Default::default(),
+            // 50ms up to about 3 minutes
+            finalize_duration_seconds: Histogram::new(exponential_buckets(0.05, 2.0, 12)),
+            recording_dir_bytes: Default::default(),
+            recording_dir_free_bytes: Default::default(),
+        };
+
+        let registry = &mut metrics.registry;
+        registry.register(
+            "active_recordings",
+            "Push sessions being recorded",
+            metrics.active_recordings.clone(),
+        );
+        registry.register(
+            "recording_viewers",
+            "Live viewers of each active recording",
+            metrics.recording_viewers.clone(),
+        );
+        registry.register(
+            "recording_bytes_written",
+            "Bytes written so far to each active recording",
+            metrics.recording_bytes_written.clone(),
+        );
+        registry.register(
+            "ingested_bytes",
+            "Bytes received from push clients",
+            metrics.ingested_bytes.clone(),
+        );
+        registry.register(
+            "served_bytes",
+            "Bytes of recordings sent to viewers",
+            metrics.served_bytes.clone(),
+        );
+        registry.register(
+            "stream_parser_errors",
+            "Pushed streams whose unreadable data ended their live view",
+            metrics.stream_parser_errors.clone(),
+        );
+        registry.register(
+            "range_requests",
+            "Range responses by status code",
+            metrics.range_requests.clone(),
+        );
+        registry.register(
+            "finalize_duration_seconds",
+            "Time taken to rewrite a finished recording",
+            metrics.finalize_duration_seconds.clone(),
+        );
+        registry.register(
+            "recording_dir_bytes",
+            "Size of every file in the recording directory",
+            metrics.recording_dir_bytes.clone(),
+        );
+        registry.register(
+            "recording_dir_free_bytes",
+            "Space left on the disk of the recording directory",
+            metrics.recording_dir_free_bytes.clone(),
+        );
+
+        metrics
+    }
+
+    pub fn served(&self, transport: Transport, bytes: usize) {
+        self.served_counter(transport).inc_by(bytes as u64);
+    }
+
+    /// The counter [`Self::served`] bumps, for the senders that count every chunk.
+    pub fn served_counter(&self, transport: Transport) -> Counter {
+        self.served_bytes
+            .get_or_create(&TransportLabels { transport })
+            .clone()
+    }
+
+    pub fn range_responded(&self, status: StatusCode) {
+        self.range_requests
+            .get_or_create(&StatusLabels {
+                status: status.as_u16(),
+            })
+            .inc();
+    }
+
+    pub fn finalized(&self, duration: Duration) {
+        self.finalize_duration_seconds
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Sets the gauges from the recordings as they are now and encodes everything in the text format.
+    pub async fn scrape(&self, recording_manager: &RecordingManager) -> anyhow::Result<String> {
+        let active_recordings = recording_manager.active_recordings().await;
+        self.active_recordings.set(active_recordings.len() as u64);
+        // Finished recordings drop out instead of staying at their last value
+        self.recording_viewers.clear();
+        self.recording_bytes_written.clear();
+        for recording in active_recordings {
+            let labels = RecordingLabels {
+                recording: recording.file_name,
+            };
+            self.recording_viewers
+                .get_or_create(&labels)
+                .set(recording.viewer_count as u64);
+            self.recording_bytes_written
+                .get_or_create(&labels)
+                .set(recording.bytes_written);
+        }
+
+        let recording_dir = recording_manager.recording_dir();
+        self.recording_dir_bytes
+            .set(directory_size(recording_dir).await?);
+        self.recording_dir_free_bytes
+            .set(retention::available_space(recording_dir)?);
+
+        let mut encoded = String::new();
+        prometheus_client::encoding::text::encode(&mut encoded, &self.registry)?;
+        Ok(encoded)
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Route middleware counting the response bytes as they are sent.
+pub async fn count_served(
+    State(metrics): State<Arc<Metrics>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let served = metrics.served_counter(Transport::Http);
+    next.run(request).await.map(|body| {
+        Body::new(ServedBody {
+            inner: body,
+            served,
+        })
+    })
+}
+
+pin_project! {
+    struct ServedBody {
+        #[pin]
+        inner: Body,
+        served: Counter,
+    }
+}
+
+impl http_body::Body for ServedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        let frame = ready!(this.inner.poll_frame(cx));
+        if let Some(data) = frame
+            .as_ref()
+            .and_then(|frame| frame.as_ref().ok())
+            .and_then(Frame::data_ref)
+        {
+            this.served.inc_by(data.len() as u64);
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+async fn directory_size(dir: &Path) -> anyhow::Result<u64> {
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    let mut size = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encodes_labelled_metrics() {
+        let metrics = Metrics::new();
+        metrics.served(Transport::Websocket, 100);
+        metrics.served(Transport::Websocket, 20);
+        metrics
+            .range_requests
+            .get_or_create(&StatusLabels { status: 206 })
+            .inc();
+
+        let mut encoded = String::new();
+        prometheus_client::encoding::text::encode(&mut encoded, &metrics.registry).unwrap();
+        assert!(encoded.contains("webm_streamer_served_bytes_total{transport=\"websocket\"} 120"));
+        assert!(encoded.contains("webm_streamer_range_requests_total{status=\"206\"} 1"));
+    }
+}
@@ -6,9 +6,14 @@ use tokio::{
 };
 
 pub mod file;
+pub mod growing_file;
 pub mod mastroka;
+pub mod metrics;
 pub mod recording_manager;
+pub mod retention;
+pub mod segment_writer;
 pub mod state;
+pub mod webhooks;
 
 pub struct FileWithLoggin {
     file: File,
@@ -1,28 +1,99 @@
 use std::{
     collections::HashMap,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
+use chrono::{DateTime, Utc};
 use futures::lock::Mutex;
 use tokio::{
-    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufWriter},
-    sync::mpsc::{Receiver, Sender},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{
+        broadcast,
+        mpsc::{self, error::TrySendError, Receiver, Sender},
+        Notify,
+    },
     task::JoinHandle,
 };
-use tracing::info;
+use tracing::{error, info, warn};
+use typed_builder::TypedBuilder;
+use uuid::Uuid;
 
 use crate::{
-    jrec::{streaming::std_stream::{AsyncBufferReader, StdStream}, webm::stream_parser::StreamParser},
+    config::{RotationConfig, ServerConfig},
+    jrec::{
+        events::{EventKind, EventSenders, RecordingEvent, RecordingEvents},
+        live::{self, LiveFeed, ViewerSnapshot},
+        metadata::{file_name, read_tracks, RecordingMetadata, RecordingSegment, RecordingState},
+        streaming::std_stream::AsyncBufferReader,
+        webm::{
+            finalize::{finalize_recording, FinalizeSummary},
+            index::{IndexCache, IndexedRecording},
+            repair,
+            splitter::{ClusterSplitter, LiveChunk},
+        },
+    },
     utils::FileWithLoggin,
 };
 
-use super::file::{open_read, open_write};
+use super::{
+    file::{open_read, open_write},
+    growing_file::{self, GrowingFile},
+    metrics::Metrics,
+    segment_writer::SegmentWriter,
+};
+
+/// A client connection carrying a push session, boxed so a resumed session can go on with another one.
+pub trait PushStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S> PushStream for S where S: AsyncRead + AsyncWrite + Unpin + Send {}
 
 struct RecordingControl {
     termination_sender: Sender<()>,
-    streamer: Option<StreamParser>,
+    /// Hands a reconnected client to the session
+    resume_sender: Sender<Box<dyn PushStream>>,
+    /// Secret the client reconnects with, unlike `session_id` it is never listed
+    session_token: String,
+    live_feed: Arc<LiveFeed>,
+    progress: GrowingFile,
+    session_id: Option<Uuid>,
+    started_at: DateTime<Utc>,
+}
+
+/// Sent to the client when its push starts or resumes.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+pub struct PushReply {
+    pub recording: String,
+    /// Reconnect with `/push?resume=<session_token>` to go on with the same recording
+    pub session_token: String,
+}
+
+/// How a push session is recorded.
+#[derive(Debug, Clone, Default, TypedBuilder)]
+pub struct RecordingOptions {
+    /// Stored in the sidecar, retention counts recordings per group
+    #[builder(default)]
+    pub group: Option<String>,
+    #[builder(default)]
+    pub rotation: RotationConfig,
+}
+
+/// What operators see of a push session in progress.
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct ActiveRecording {
+    pub file_name: String,
+    pub session_id: Option<Uuid>,
+    pub started_at: DateTime<Utc>,
+    /// Of every segment so far
+    pub bytes_written: u64,
+    /// Bits per second received over the last few seconds
+    pub bitrate: u64,
+    pub viewer_count: usize,
 }
 
 impl RecordingControl {
@@ -34,92 +105,852 @@ impl RecordingControl {
 
 #[derive(Debug)]
 pub struct RecordingManager {
+    config: Arc<ServerConfig>,
     recording_map: Mutex<HashMap<PathBuf, RecordingControl>>,
+    /// When each recording, by file name, was last fetched by a viewer
+    last_viewed: std::sync::Mutex<HashMap<String, Instant>>,
+    /// Viewer sessions still open on each recording, by file name
+    open_views: std::sync::Mutex<HashMap<String, usize>>,
+    /// Cleared once shutting down, no push session starts after that
+    accepting: AtomicBool,
+    /// Woken up every time a recording leaves `recording_map`
+    recording_removed: Notify,
+    /// Lifecycle events of every recording, dropped on shutdown to end the subscriptions
+    events: std::sync::Mutex<Option<EventSenders>>,
+    indexes: Arc<IndexCache>,
+    metrics: Arc<Metrics>,
 }
 
 impl RecordingManager {
-    pub fn new() -> Arc<Self> {
+    pub fn new(config: Arc<ServerConfig>) -> Arc<Self> {
         Arc::new(Self {
             recording_map: Mutex::new(HashMap::new()),
+            last_viewed: std::sync::Mutex::new(HashMap::new()),
+            open_views: std::sync::Mutex::new(HashMap::new()),
+            accepting: AtomicBool::new(true),
+            recording_removed: Notify::new(),
+            events: std::sync::Mutex::new(Some(EventSenders::new(config.events.capacity))),
+            indexes: Arc::default(),
+            metrics: Arc::default(),
+            config,
         })
     }
 
+    pub fn recording_dir(&self) -> &Path {
+        &self.config.recording_dir
+    }
+
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// The events of every recording from now on, the subscription ends once the manager is shut down.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<RecordingEvent> {
+        match &*self.events.lock().expect("events lock") {
+            Some(senders) => senders.broadcast.subscribe(),
+            // Already closed
+            None => broadcast::channel(1).1,
+        }
+    }
+
+    /// Like [`Self::subscribe_events`] but never missing an event, however far behind the subscriber is.
+    /// Only the recordings started after subscribing are covered, subscribe before [`Self::recover`].
+    pub fn subscribe_events_lossless(&self) -> mpsc::UnboundedReceiver<RecordingEvent> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        if let Some(senders) = &mut *self.events.lock().expect("events lock") {
+            senders.lossless.push(sender);
+        }
+        receiver
+    }
+
+    fn recording_events(&self, recording_path: &Path) -> RecordingEvents {
+        let senders = self
+            .events
+            .lock()
+            .expect("events lock")
+            .clone()
+            .unwrap_or_else(|| EventSenders::new(1));
+
+        RecordingEvents::new(
+            file_name(recording_path),
+            senders,
+            self.config.events.bytes_milestone,
+        )
+    }
+
+    /// The recording opened along with its index, scanned only as far as it grew since it was last asked for.
+    pub async fn open_indexed(&self, recording_path: &Path) -> anyhow::Result<IndexedRecording> {
+        let indexes = self.indexes.clone();
+        let recording_path = recording_path.to_path_buf();
+
+        tokio::task::spawn_blocking(move || indexes.open(&recording_path)).await?
+    }
+
+    /// [`Self::open_indexed`] for a file a viewer already has open.
+    pub async fn index_file(
+        &self,
+        recording_path: &Path,
+        file: std::fs::File,
+    ) -> anyhow::Result<IndexedRecording> {
+        let indexes = self.indexes.clone();
+        let recording_path = recording_path.to_path_buf();
+
+        tokio::task::spawn_blocking(move || indexes.index(&recording_path, file)).await?
+    }
+
+    /// Players keep fetching what they watch, each fetch keeps the recording from being pruned for a while.
+    pub fn mark_viewed(&self, recording_path: &Path) {
+        if let Some(file_name) = recording_path.file_name() {
+            self.last_viewed
+                .lock()
+                .expect("last viewed lock")
+                .insert(file_name.to_string_lossy().to_string(), Instant::now());
+        }
+    }
+
+    /// Whether the recording was fetched within `grace`, forgetting the views older than that.
+    pub fn recently_viewed(&self, file_name: &str, grace: Duration) -> bool {
+        let mut last_viewed = self.last_viewed.lock().expect("last viewed lock");
+        last_viewed.retain(|_, viewed_at| viewed_at.elapsed() < grace);
+        last_viewed.contains_key(file_name)
+    }
+
+    /// Keeps the recording from being pruned for as long as the viewer session holding the guard lasts,
+    /// however longer than the grace of [`Self::mark_viewed`] that is.
+    pub fn open_view(self: &Arc<Self>, recording_path: &Path) -> ViewGuard {
+        let file_name = file_name(recording_path);
+        *self
+            .open_views
+            .lock()
+            .expect("open views lock")
+            .entry(file_name.clone())
+            .or_default() += 1;
+
+        ViewGuard {
+            recording_manager: self.clone(),
+            file_name,
+        }
+    }
+
+    /// Whether a viewer session is open on the recording.
+    pub fn is_viewed(&self, file_name: &str) -> bool {
+        self.open_views
+            .lock()
+            .expect("open views lock")
+            .contains_key(file_name)
+    }
+
+    /// Whether another push session fits under `limits.max_active_recordings`, never while shutting down.
+    /// Only a hint to turn clients away early, [`Self::start_recording`] checks again.
+    pub async fn can_start_recording(&self) -> bool {
+        if !self.accepting.load(Ordering::SeqCst) {
+            return false;
+        }
+        let Some(max_active_recordings) = self.config.limits.max_active_recordings else {
+            return true;
+        };
+
+        self.recording_map.lock().await.len() < max_active_recordings
+    }
+
     pub async fn start_recording<S>(
         self: Arc<Self>,
         recording_path: PathBuf,
-        mut client_stream: S,
+        options: RecordingOptions,
+        client_stream: S,
     ) -> anyhow::Result<JoinHandle<anyhow::Result<()>>>
     where
-        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        S: PushStream + 'static,
     {
-        let file = open_write(&recording_path).await?;
-        info!(?recording_path, "Recording started");
-        // Debug purposes, I can one click to open the file for streaming
-        client_stream
-            .write(
-                recording_path
-                    .file_name()
-                    .expect("file_name")
-                    .to_str()
-                    .expect("to_str")
-                    .as_bytes(),
+        let mut metadata = RecordingMetadata::new(&recording_path);
+        metadata.group = options.group;
+        let events = self.recording_events(&recording_path);
+        let session_token = Uuid::new_v4().simple().to_string();
+        let (notifier, progress) = growing_file::channel();
+        let live_feed = Arc::new(
+            LiveFeed::new(
+                self.config.live.feed_capacity,
+                self.config.limits.max_group_of_pictures_bytes,
             )
-            .await
-            .inspect_err(|e| info!(?e, "Failed to write file name"))?;
-        client_stream.flush().await?;
+            .with_events(events.clone()),
+        );
+        // Taken before anything is written, concurrent pushes cannot go over the limit
+        let mut recording_handle = RecordingHandle::new(
+            &recording_path,
+            self.clone(),
+            progress,
+            live_feed.clone(),
+            &metadata,
+            session_token.clone(),
+        )
+        .await?;
+
+        // The sidecar goes first, a listing never mistakes the new file for an old recording
+        metadata.save(&recording_path).await?;
+        let file = open_write(&recording_path).await?;
+        info!(?recording_path, session_id = ?metadata.session_id, "Recording started");
+        events.emit(EventKind::Started {
+            session_id: metadata.session_id,
+            group: metadata.group.clone(),
+        });
+        let mut client_stream: Box<dyn PushStream> = Box::new(client_stream);
+        // Also lets the client open the recording for streaming in one click
+        if let Err(e) =
+            send_push_reply(&mut client_stream, &metadata.file_name, &session_token).await
+        {
+            // Nothing was received, no active recording is left behind for the next start to recover
+            drop(file);
+            if let Err(e) = tokio::fs::remove_file(&recording_path).await {
+                warn!(?recording_path, ?e, "Failed to remove the empty recording");
+            }
+            metadata.state = RecordingState::Corrupt;
+            metadata.ended_at = Some(Utc::now());
+            if let Err(e) = metadata.save(&recording_path).await {
+                error!(?recording_path, ?e, "Failed to write recording sidecar");
+            }
+            events.emit(EventKind::Failed {
+                error: format!("{:#}", e),
+            });
+            return Err(e);
+        }
 
-        let mut recording_handle = RecordingHandle::new(&recording_path, self.clone()).await;
+        // Unlike the live feed it never drops a chunk, a slow disk slows down the push instead
+        let (file_sender, file_receiver) = mpsc::channel(self.config.live.feed_capacity);
+        let resume_window = self.config.resume_window();
+        let max_element_bytes = self.config.limits.max_push_element_bytes;
+        let metrics = self.metrics.clone();
 
         let handle = tokio::task::spawn(async move {
-            let file = FileWithLoggin::new(file);
-            let mut file = BufWriter::new(file);
+            let segment_writer = SegmentWriter::new(
+                recording_path.clone(),
+                FileWithLoggin::new(file),
+                notifier,
+                options.rotation,
+                metadata.clone(),
+            );
+            let file_writer = tokio::spawn(write_chunks(file_receiver, segment_writer));
 
-            let result = tokio::select! {
-                res = tokio::io::copy(&mut client_stream, &mut file) => {
-                    res.context("JREC streaming to file").map(|_| ())
-                }
-                _ = recording_handle.wait_for_stop() => {
-                    Ok(())
-                }
-            };
+            let result = receive_push(
+                client_stream,
+                &live_feed,
+                file_sender,
+                &mut recording_handle,
+                resume_window,
+                max_element_bytes,
+                &metrics,
+            )
+            .await
+            .context("JREC streaming to file");
 
             info!("Recording finished");
+            events.emit(EventKind::Stopped);
+            metadata.ended_at = Some(Utc::now());
+
+            match file_writer.await {
+                Ok((segments, written)) => {
+                    match written {
+                        Ok(()) => info!(segments = segments.len(), "Recording written"),
+                        Err(e) => error!(?e, "Failed to write recording"),
+                    }
+                    metadata.segments = segments;
+                }
+                Err(e) => error!(?e, "Recording writer task failed"),
+            }
+
+            // Keep the recording marked as active while the file is being rewritten
+            match Self::finalize(&metrics, recording_path, metadata, finalize_recording).await {
+                Ok(metadata) => events.emit(EventKind::Finalized {
+                    duration_ms: metadata.duration_ms,
+                    byte_size: metadata.byte_size,
+                }),
+                Err(e) => events.emit(EventKind::Failed {
+                    error: format!("{:#}", e),
+                }),
+            }
+            drop(recording_handle);
+
             result
         });
 
         Ok(handle)
     }
 
+    /// Whether a push session with this token can be resumed.
+    pub async fn can_resume(&self, session_token: &str) -> bool {
+        self.accepting.load(Ordering::SeqCst)
+            && self
+                .recording_map
+                .lock()
+                .await
+                .values()
+                .any(|control| control.session_token == session_token)
+    }
+
+    /// Continues the push session of `session_token` on a new client connection, after the previous one dropped.
+    /// The client starts over with a new EBML header, which the session discards.
+    pub async fn resume_recording<S>(
+        &self,
+        session_token: &str,
+        mut client_stream: S,
+    ) -> anyhow::Result<PathBuf>
+    where
+        S: PushStream + 'static,
+    {
+        anyhow::ensure!(self.accepting.load(Ordering::SeqCst), "shutting down");
+        // Not held while talking to the client, every other session would wait on it
+        let (recording_id, resume_sender) = {
+            let recording_map = self.recording_map.lock().await;
+            let (recording_id, control) = recording_map
+                .iter()
+                .find(|(_, control)| control.session_token == session_token)
+                .context("no push session to resume with this token")?;
+            (recording_id.clone(), control.resume_sender.clone())
+        };
+
+        let file_name = file_name(&recording_id);
+        send_push_reply(&mut client_stream, &file_name, session_token).await?;
+        resume_sender
+            .try_send(Box::new(client_stream))
+            .map_err(|e| match e {
+                TrySendError::Full(_) => anyhow::anyhow!("push session is already resuming"),
+                TrySendError::Closed(_) => anyhow::anyhow!("push session ended while resuming"),
+            })?;
+        info!(?recording_id, "Push session resumed");
+
+        Ok(recording_id)
+    }
+
+    /// Repairs the recordings whose sidecar is still active: the server died before their push ended.
+    /// Only meant to run on startup, before any push session begins.
+    pub async fn recover(&self) -> anyhow::Result<usize> {
+        let recording_dir = self.recording_dir();
+        let mut entries = tokio::fs::read_dir(recording_dir)
+            .await
+            .with_context(|| format!("reading recording directory {:?}", recording_dir))?;
+
+        let mut recovered = 0;
+        while let Some(entry) = entries.next_entry().await? {
+            let Some(recording_path) = RecordingMetadata::recording_path(&entry.path()) else {
+                continue;
+            };
+            let mut metadata = match RecordingMetadata::load(&recording_path).await {
+                Ok(Some(metadata)) if metadata.state == RecordingState::Active => metadata,
+                Ok(_) => continue,
+                Err(e) => {
+                    warn!(?recording_path, ?e, "Skipping unreadable sidecar");
+                    continue;
+                }
+            };
+
+            info!(?recording_path, session_id = ?metadata.session_id, "Recovering recording");
+            let file_metadata = tokio::fs::metadata(&recording_path).await;
+            metadata.ended_at = file_metadata
+                .as_ref()
+                .ok()
+                .and_then(|file_metadata| file_metadata.modified().ok())
+                .map(Into::into);
+            metadata.recovered_at = Some(Utc::now());
+            // The last segment may have been listed without its file being created
+            metadata.segments.retain(|segment| {
+                let written = recording_path.with_file_name(&segment.file_name).exists();
+                if !written {
+                    warn!(file_name = segment.file_name, "Segment was never written");
+                }
+                written
+            });
+            // A segment rotated into right before the crash may hold nothing but its header,
+            // repairing it would fail and the whole recording with it
+            let segments = std::mem::take(&mut metadata.segments);
+            metadata.segments = without_empty_segments(&recording_path, segments).await;
+            let events = self.recording_events(&recording_path);
+            if file_metadata.is_err() {
+                // The session died before its first byte was written
+                metadata.state = RecordingState::Corrupt;
+                metadata.save(&recording_path).await?;
+                events.emit(EventKind::Failed {
+                    error: "nothing was written before the server stopped".to_string(),
+                });
+            } else {
+                match Self::finalize(&self.metrics, recording_path, metadata, |path| {
+                    repair::repair(path, None).map(|summary| summary.finalized)
+                })
+                .await
+                {
+                    Ok(metadata) => events.emit(EventKind::Finalized {
+                        duration_ms: metadata.duration_ms,
+                        byte_size: metadata.byte_size,
+                    }),
+                    Err(e) => events.emit(EventKind::Failed {
+                        error: format!("{:#}", e),
+                    }),
+                }
+            }
+            recovered += 1;
+        }
+
+        Ok(recovered)
+    }
+
+    /// Rewrites the recording with `rewrite` off the runtime and records the outcome in its sidecar,
+    /// which is returned unless the rewrite failed.
+    async fn finalize(
+        metrics: &Metrics,
+        recording_path: PathBuf,
+        mut metadata: RecordingMetadata,
+        rewrite: fn(&Path) -> anyhow::Result<FinalizeSummary>,
+    ) -> anyhow::Result<RecordingMetadata> {
+        let segment_paths = metadata.segment_paths(&recording_path);
+        let paths = segment_paths.clone();
+        let started = Instant::now();
+        let result = tokio::task::spawn_blocking(move || {
+            let summaries = paths
+                .iter()
+                .map(|path| rewrite(path).with_context(|| format!("finalizing {:?}", path)))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            anyhow::Ok((summaries, read_tracks(&paths[0])?))
+        })
+        .await;
+        metrics.finalized(started.elapsed());
+
+        let outcome = match result {
+            Ok(Ok((summaries, tracks))) => {
+                info!(?recording_path, ?summaries, "Recording finalized");
+                metadata.state = RecordingState::Finished;
+                metadata.duration_ms =
+                    Some(summaries.iter().map(|summary| summary.duration_ms).sum());
+                for (segment, summary) in metadata.segments.iter_mut().zip(&summaries) {
+                    segment.duration_ms = Some(summary.duration_ms);
+                }
+                metadata.tracks = tracks;
+                Ok(())
+            }
+            Ok(Err(e)) => {
+                error!(?recording_path, ?e, "Failed to finalize recording");
+                metadata.state = RecordingState::Corrupt;
+                Err(e)
+            }
+            Err(e) => {
+                error!(?recording_path, ?e, "Finalization task failed");
+                metadata.state = RecordingState::Corrupt;
+                Err(e.into())
+            }
+        };
+
+        metadata.byte_size = 0;
+        for (index, path) in segment_paths.iter().enumerate() {
+            match tokio::fs::metadata(path).await {
+                Ok(file_metadata) => {
+                    metadata.byte_size += file_metadata.len();
+                    if let Some(segment) = metadata.segments.get_mut(index) {
+                        segment.byte_size = file_metadata.len();
+                    }
+                }
+                Err(e) => error!(?path, ?e, "Failed to read recording size"),
+            }
+        }
+        if let Err(e) = metadata.save(&recording_path).await {
+            error!(?recording_path, ?e, "Failed to write recording sidecar");
+        }
+
+        outcome.map(|()| metadata)
+    }
+
     pub async fn is_recording(&self, recording_id: &Path) -> bool {
+        let Ok(recording_id) = recording_key(recording_id).await else {
+            return false;
+        };
+
+        self.recording_map.lock().await.contains_key(&recording_id)
+    }
+
+    /// Write notifications of an active recording, `None` once it is finished.
+    pub async fn subscribe(&self, recording_id: &Path) -> Option<GrowingFile> {
+        let recording_id = recording_key(recording_id).await.ok()?;
+        let recording_map = self.recording_map.lock().await;
+
+        recording_map
+            .get(&recording_id)
+            .map(|control| control.progress.clone())
+    }
+
+    /// Live viewers of an active recording and how well they keep up.
+    pub async fn viewers(&self, recording_id: &Path) -> Option<Vec<ViewerSnapshot>> {
+        let recording_id = recording_key(recording_id).await.ok()?;
+        let recording_map = self.recording_map.lock().await;
+
+        recording_map
+            .get(&recording_id)
+            .map(|control| control.live_feed.viewers())
+    }
+
+    /// Every push session in progress, oldest first.
+    pub async fn active_recordings(&self) -> Vec<ActiveRecording> {
         let recording_map = self.recording_map.lock().await;
-        let recording_id = tokio::fs::canonicalize(recording_id)
+
+        let mut recordings: Vec<_> = recording_map
+            .iter()
+            .map(|(recording_id, control)| ActiveRecording {
+                file_name: recording_id
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+                session_id: control.session_id,
+                started_at: control.started_at,
+                bytes_written: control.progress.progress().total_bytes,
+                bitrate: control.live_feed.bitrate(),
+                viewer_count: control.live_feed.viewers().len(),
+            })
+            .collect();
+        recordings.sort_by_key(|recording| recording.started_at);
+        recordings
+    }
+
+    /// Ends the push session of an active recording as if the client had disconnected,
+    /// what was received is finalized as usual. `false` when it is not being recorded.
+    pub async fn stop_recording(&self, recording_id: &Path) -> anyhow::Result<bool> {
+        let Ok(recording_id) = recording_key(recording_id).await else {
+            return Ok(false);
+        };
+        let termination_sender = self
+            .recording_map
+            .lock()
             .await
-            .expect("recording_id");
+            .get(&recording_id)
+            .map(|control| control.termination_sender.clone());
 
-        recording_map.contains_key(&recording_id)
+        match termination_sender {
+            Some(termination_sender) => {
+                termination_sender.send(()).await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
     }
 
-    pub async fn start_streaming(&self, recording_path: &Path) -> anyhow::Result<AsyncBufferReader> {
-        let recording_path = tokio::fs::canonicalize(recording_path).await?;
+    /// Stops accepting pushes, ends every push session and waits until their recordings are finalized.
+    /// Live viewers get the end of their stream as the sessions end.
+    pub async fn shutdown(&self) {
+        self.accepting.store(false, Ordering::SeqCst);
 
-        let mut recording_map = self.recording_map.lock().await;
-        let Some(control) = recording_map.get_mut(&recording_path) else {
-            // Not being recorded, just stream the file
-            let file = open_read(&recording_path).await?;
-            return Ok(AsyncBufferReader::from_file(file).await?);
+        loop {
+            let removed = self.recording_removed.notified();
+            let recording_map = self.recording_map.lock().await;
+            if recording_map.is_empty() {
+                // Subscribers get the events already sent, then the end of their stream
+                self.events.lock().expect("events lock").take();
+                return;
+            }
+            info!(
+                active_recordings = recording_map.len(),
+                "Waiting for recordings to finish"
+            );
+            for control in recording_map.values() {
+                // A session that already got the signal is on its way out
+                control.termination_sender.try_send(()).ok();
+            }
+            drop(recording_map);
+            removed.await;
+        }
+    }
+
+    /// A viewer joining an active recording at its last keyframe, `None` once it is finished.
+    pub async fn live_viewer(&self, recording_id: &Path) -> Option<AsyncBufferReader> {
+        let recording_id = recording_key(recording_id).await.ok()?;
+        let recording_map = self.recording_map.lock().await;
+
+        recording_map
+            .get(&recording_id)
+            .map(|control| live::spawn_viewer(&control.live_feed, &self.config.live))
+    }
+
+    pub async fn start_streaming(
+        &self,
+        recording_path: &Path,
+    ) -> anyhow::Result<AsyncBufferReader> {
+        if let Some(viewer) = self.live_viewer(recording_path).await {
+            return Ok(viewer);
+        }
+
+        // Not being recorded, just stream the file
+        let file = open_read(recording_path).await?;
+        Ok(AsyncBufferReader::from_file(file).await?)
+    }
+}
+
+/// The segments after the first one that have no complete cluster left out, their file removed.
+/// The first one is kept whatever it holds, it is the file the recording is known by.
+async fn without_empty_segments(
+    recording_path: &Path,
+    segments: Vec<RecordingSegment>,
+) -> Vec<RecordingSegment> {
+    let mut kept = Vec::with_capacity(segments.len());
+    for (index, segment) in segments.into_iter().enumerate() {
+        let path = recording_path.with_file_name(&segment.file_name);
+        let validated = {
+            let path = path.clone();
+            tokio::task::spawn_blocking(move || repair::validate(&path)).await
+        };
+        let empty = matches!(&validated, Ok(Ok(report)) if report.complete_end.is_none());
+        if index == 0 || !empty {
+            kept.push(segment);
+            continue;
+        }
+
+        warn!(?path, "Dropping segment without a complete cluster");
+        if let Err(e) = tokio::fs::remove_file(&path).await {
+            warn!(?path, ?e, "Failed to remove empty segment");
+        }
+    }
+    kept
+}
+
+/// What a recording is known by in the recording map: its file name in the canonical recording directory.
+/// Unlike the canonical file path it does not need the file to exist, which it does not before
+/// the session starts, nor once it was moved away.
+async fn recording_key(recording_path: &Path) -> std::io::Result<PathBuf> {
+    let file_name = recording_path.file_name().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a recording path")
+    })?;
+    let dir = match recording_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    Ok(tokio::fs::canonicalize(dir).await?.join(file_name))
+}
+
+async fn send_push_reply<S>(
+    client_stream: &mut S,
+    recording: &str,
+    session_token: &str,
+) -> anyhow::Result<()>
+where
+    S: AsyncWrite + Unpin + ?Sized,
+{
+    let reply = PushReply {
+        recording: recording.to_string(),
+        session_token: session_token.to_string(),
+    };
+    client_stream
+        .write_all(&serde_json::to_vec(&reply)?)
+        .await
+        .inspect_err(|e| info!(?e, "Failed to send push reply"))?;
+    client_stream.flush().await?;
+
+    Ok(())
+}
+
+/// Keeps cluster timestamps going forward across the connections of a resumed push session.
+struct PushTimeline {
+    connections: usize,
+    /// Added to the timestamps of the current connection, whose clusters start over from zero
+    offset: u64,
+    /// Timestamp of the last frame published, and when it was
+    last_frame: Option<(u64, Instant)>,
+}
+
+impl PushTimeline {
+    fn new() -> Self {
+        Self {
+            connections: 1,
+            offset: 0,
+            last_frame: None,
+        }
+    }
+
+    fn place(&mut self, chunk: LiveChunk) -> Option<LiveChunk> {
+        match chunk {
+            // Every connection starts with its own header, the recording keeps the first one
+            LiveChunk::Header(_) if self.connections > 1 => None,
+            LiveChunk::Cluster(cluster) => {
+                let cluster = match self.offset {
+                    0 => cluster,
+                    offset => cluster.with_timestamp(cluster.timestamp + offset),
+                };
+                self.last_frame = Some((
+                    cluster.timestamp + cluster.last_frame_offset,
+                    Instant::now(),
+                ));
+                Some(LiveChunk::Cluster(cluster))
+            }
+            chunk => Some(chunk),
+        }
+    }
+
+    /// The new connection goes on from where the wall clock says the session is,
+    /// TimestampScale being a millisecond in what browsers record.
+    fn resume(&mut self) {
+        self.connections += 1;
+        if let Some((timestamp, published_at)) = self.last_frame {
+            self.offset = timestamp + (published_at.elapsed().as_millis() as u64).max(1);
+        }
+    }
+}
+
+enum ConnectionEnd {
+    Stopped,
+    Disconnected(std::io::Result<()>),
+    Resumed(Box<dyn PushStream>),
+    /// Nothing is written anymore, the client is not left pushing into the void
+    WriterFailed,
+}
+
+/// Where the chunks of a push session go: the file first, then the live viewers.
+struct PushOutput<'a> {
+    live_feed: &'a LiveFeed,
+    file: mpsc::Sender<LiveChunk>,
+    timeline: PushTimeline,
+}
+
+impl PushOutput<'_> {
+    /// Fails once the recording writer is gone.
+    async fn publish(&mut self, chunk: LiveChunk) -> Result<(), ()> {
+        let Some(chunk) = self.timeline.place(chunk) else {
+            return Ok(());
         };
+        self.file.send(chunk.clone()).await.map_err(|_| ())?;
+        self.live_feed.publish(chunk);
+
+        Ok(())
+    }
+}
+
+/// Splits the pushed bytes into clusters and publishes them until the recording is stopped,
+/// or the client is gone and did not come back within `resume_window`.
+async fn receive_push(
+    mut client_stream: Box<dyn PushStream>,
+    live_feed: &LiveFeed,
+    file: mpsc::Sender<LiveChunk>,
+    recording_handle: &mut RecordingHandle,
+    resume_window: Duration,
+    max_element_bytes: usize,
+    metrics: &Metrics,
+) -> std::io::Result<()> {
+    let mut output = PushOutput {
+        live_feed,
+        file,
+        timeline: PushTimeline::new(),
+    };
 
-        if control.streamer.is_none() {
-            let stream_parser = StreamParser::new(&recording_path).await?;
-            control.streamer = Some(stream_parser);
+    let result = loop {
+        let mut splitter =
+            ClusterSplitter::new(max_element_bytes, metrics.stream_parser_errors.clone());
+        let end = receive_connection(
+            &mut client_stream,
+            &mut splitter,
+            &mut output,
+            recording_handle,
+            metrics,
+        )
+        .await;
+
+        let resumed = match end {
+            ConnectionEnd::Stopped => Err(Ok(())),
+            ConnectionEnd::WriterFailed => {
+                Err(Err(std::io::Error::other("recording writer stopped")))
+            }
+            ConnectionEnd::Resumed(resumed) => Ok(resumed),
+            ConnectionEnd::Disconnected(result) => {
+                if let Err(e) = &result {
+                    info!(?e, "Push connection lost");
+                }
+                tokio::select! {
+                    signal = recording_handle.next_signal() => match signal {
+                        SessionSignal::Resume(resumed) => Ok(resumed),
+                        SessionSignal::Stop => Err(result),
+                    },
+                    _ = tokio::time::sleep(resume_window) => Err(result),
+                }
+            }
         };
 
-        let streamer = control.streamer.as_ref().unwrap();
+        match resumed {
+            Ok(resumed) => {
+                // The cluster cut off by the disconnect would sit in the middle of the recording
+                let published = match splitter.finish() {
+                    Some(LiveChunk::Raw(data)) => {
+                        warn!(
+                            bytes = data.len(),
+                            "Dropping the cut off end of the push connection"
+                        );
+                        Ok(())
+                    }
+                    Some(chunk) => output.publish(chunk).await,
+                    None => Ok(()),
+                };
+                if published.is_err() {
+                    break Err(std::io::Error::other("recording writer stopped"));
+                }
+                client_stream = resumed;
+                output.timeline.resume();
+            }
+            Err(result) => {
+                // Whatever was received still goes to the file, a cut off cluster is dropped by finalization
+                if let Some(chunk) = splitter.finish() {
+                    output.publish(chunk).await.ok();
+                }
+                break result;
+            }
+        }
+    };
+    live_feed.finish();
+
+    result
+}
+
+async fn receive_connection(
+    client_stream: &mut Box<dyn PushStream>,
+    splitter: &mut ClusterSplitter,
+    output: &mut PushOutput<'_>,
+    recording_handle: &mut RecordingHandle,
+    metrics: &Metrics,
+) -> ConnectionEnd {
+    let mut buffer = vec![0; 64 * 1024];
+
+    loop {
+        let n = tokio::select! {
+            read = client_stream.read(&mut buffer) => match read {
+                Ok(n) => n,
+                Err(e) => return ConnectionEnd::Disconnected(Err(e)),
+            },
+            // The client may come back before the server notices the old connection is dead
+            signal = recording_handle.next_signal() => return match signal {
+                SessionSignal::Resume(resumed) => ConnectionEnd::Resumed(resumed),
+                SessionSignal::Stop => ConnectionEnd::Stopped,
+            },
+        };
+        if n == 0 {
+            return ConnectionEnd::Disconnected(Ok(()));
+        }
+        metrics.ingested_bytes.inc_by(n as u64);
 
-        let stream = streamer.spawn().await?;
+        for chunk in splitter.push(&buffer[..n]) {
+            if output.publish(chunk).await.is_err() {
+                return ConnectionEnd::WriterFailed;
+            }
+        }
+    }
+}
 
-        Ok(stream)
+/// Writes every chunk of the push session, each one is announced once it is on disk.
+/// The segments written are returned even when writing failed, what made it to disk is still finalized.
+async fn write_chunks(
+    mut receiver: mpsc::Receiver<LiveChunk>,
+    mut writer: SegmentWriter,
+) -> (Vec<RecordingSegment>, anyhow::Result<()>) {
+    let result = async {
+        while let Some(chunk) = receiver.recv().await {
+            writer.write(&chunk).await?;
+        }
+        anyhow::Ok(())
     }
+    .await;
+
+    (writer.finish().await, result)
 }
 
 impl RecordingManager {
@@ -128,56 +959,134 @@ impl RecordingManager {
     }
 
     async fn stop_recording_inner(self: Arc<Self>, recording_id: PathBuf) {
-        let recording_id = tokio::fs::canonicalize(recording_id)
-            .await
-            .expect("recording_id");
-
-        let mut recording_map = self.recording_map.lock().await;
-        let tx = recording_map.remove(&recording_id);
+        let tx = self.recording_map.lock().await.remove(&recording_id);
+        self.recording_removed.notify_waiters();
         if let Some(handle) = tx {
             handle.terminate().await.ok();
         }
     }
 
-    async fn start_recording_inner(&self, recording_id: PathBuf) -> Receiver<()> {
+    /// Registers the push session under `recording_id`, the key it is removed by,
+    /// unless it would go over `limits.max_active_recordings` or the manager is shutting down.
+    async fn start_recording_inner(
+        &self,
+        recording_id: PathBuf,
+        progress: GrowingFile,
+        live_feed: Arc<LiveFeed>,
+        metadata: &RecordingMetadata,
+        session_token: String,
+    ) -> anyhow::Result<(Receiver<()>, Receiver<Box<dyn PushStream>>)> {
+        // Checked under the same lock as the insert, `shutdown` and concurrent pushes see either both or neither
         let mut recording_map = self.recording_map.lock().await;
+        anyhow::ensure!(self.accepting.load(Ordering::SeqCst), "shutting down");
+        if let Some(max_active_recordings) = self.config.limits.max_active_recordings {
+            anyhow::ensure!(
+                recording_map.len() < max_active_recordings,
+                "too many active recordings"
+            );
+        }
+        anyhow::ensure!(
+            !recording_map.contains_key(&recording_id),
+            "{:?} is already being recorded",
+            recording_id
+        );
         let (sender, receiver) = tokio::sync::mpsc::channel(1);
-
-        let recording_id = tokio::fs::canonicalize(recording_id)
-            .await
-            .expect("recording_id");
+        let (resume_sender, resume_receiver) = tokio::sync::mpsc::channel(1);
 
         recording_map.insert(
             recording_id,
             RecordingControl {
                 termination_sender: sender,
-                streamer: None,
+                resume_sender,
+                session_token,
+                live_feed,
+                progress,
+                session_id: metadata.session_id,
+                started_at: metadata.started_at,
             },
         );
-        receiver
+        Ok((receiver, resume_receiver))
     }
 }
 
+/// A viewer session open on a recording, see [`RecordingManager::open_view`].
+#[derive(Debug)]
+pub struct ViewGuard {
+    recording_manager: Arc<RecordingManager>,
+    file_name: String,
+}
+
+impl Drop for ViewGuard {
+    fn drop(&mut self) {
+        let mut open_views = self
+            .recording_manager
+            .open_views
+            .lock()
+            .expect("open views lock");
+        if let Some(count) = open_views.get_mut(&self.file_name) {
+            *count -= 1;
+            if *count == 0 {
+                open_views.remove(&self.file_name);
+            }
+        }
+        drop(open_views);
+
+        // The grace starts over when the session ends, a player may reconnect
+        self.recording_manager
+            .last_viewed
+            .lock()
+            .expect("last viewed lock")
+            .insert(self.file_name.clone(), Instant::now());
+    }
+}
+
+enum SessionSignal {
+    Stop,
+    Resume(Box<dyn PushStream>),
+}
+
 pub struct RecordingHandle {
     recording_id: PathBuf,
     recording_manager: Arc<RecordingManager>,
     recording_signal: Receiver<()>,
+    resume_receiver: Receiver<Box<dyn PushStream>>,
 }
 
 impl RecordingHandle {
-    async fn new(recording_id: &Path, recording_manager: Arc<RecordingManager>) -> Self {
-        let recording_signal = recording_manager
-            .start_recording_inner(recording_id.to_path_buf())
-            .await;
-        Self {
-            recording_id: recording_id.to_path_buf(),
+    async fn new(
+        recording_id: &Path,
+        recording_manager: Arc<RecordingManager>,
+        progress: GrowingFile,
+        live_feed: Arc<LiveFeed>,
+        metadata: &RecordingMetadata,
+        session_token: String,
+    ) -> anyhow::Result<Self> {
+        let recording_id = recording_key(recording_id)
+            .await
+            .with_context(|| format!("locating recording {:?}", recording_id))?;
+        let (recording_signal, resume_receiver) = recording_manager
+            .start_recording_inner(
+                recording_id.clone(),
+                progress,
+                live_feed,
+                metadata,
+                session_token,
+            )
+            .await?;
+        Ok(Self {
+            recording_id,
             recording_signal,
+            resume_receiver,
             recording_manager,
-        }
+        })
     }
 
-    pub async fn wait_for_stop(&mut self) {
-        self.recording_signal.recv().await;
+    /// Waits for the recording to be stopped, or for the client to resume it on a new connection.
+    async fn next_signal(&mut self) -> SessionSignal {
+        tokio::select! {
+            _ = self.recording_signal.recv() => SessionSignal::Stop,
+            Some(resumed) = self.resume_receiver.recv() => SessionSignal::Resume(resumed),
+        }
     }
 }
 
@@ -188,3 +1097,504 @@ impl Drop for RecordingHandle {
             .try_stop_recording(self.recording_id.clone());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::TryStreamExt;
+    use tokio::io::DuplexStream;
+
+    use crate::jrec::webm::{fixture, index::RecordingIndex};
+
+    use super::*;
+
+    /// A manager recording into a directory of its own, pushes go to `01_10_00_00.webm`.
+    struct Setup {
+        dir: tempfile::TempDir,
+        manager: Arc<RecordingManager>,
+        recording_path: PathBuf,
+    }
+
+    impl Setup {
+        fn new(configure: impl FnOnce(&mut ServerConfig)) -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let mut config = ServerConfig {
+                recording_dir: dir.path().to_path_buf(),
+                ..Default::default()
+            };
+            configure(&mut config);
+
+            Self {
+                manager: RecordingManager::new(Arc::new(config)),
+                recording_path: dir.path().join("01_10_00_00.webm"),
+                dir,
+            }
+        }
+    }
+
+    /// The client end of a push session just started.
+    async fn push(
+        manager: &Arc<RecordingManager>,
+        recording_path: &Path,
+        options: RecordingOptions,
+    ) -> (DuplexStream, JoinHandle<anyhow::Result<()>>) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let session = manager
+            .clone()
+            .start_recording(recording_path.to_path_buf(), options, server)
+            .await
+            .unwrap();
+
+        (client, session)
+    }
+
+    #[tokio::test]
+    async fn test_list_and_stop_active_recording() {
+        let Setup {
+            dir: _dir,
+            manager,
+            recording_path,
+        } = Setup::new(|_| {});
+
+        let (mut client, session) =
+            push(&manager, &recording_path, RecordingOptions::default()).await;
+        let recording = fixture::recording(2, 4);
+        client.write_all(&recording).await.unwrap();
+        let mut progress = manager.subscribe(&recording_path).await.unwrap();
+        // The last cluster is only complete once the session ends
+        progress.wait_for_data(0).await;
+
+        let active = manager.active_recordings().await;
+        assert_eq!(1, active.len());
+        assert_eq!("01_10_00_00.webm", active[0].file_name);
+        assert!(active[0].bytes_written > 0);
+        assert!(active[0].bitrate > 0);
+        assert_eq!(0, active[0].viewer_count);
+
+        // The client is still connected, only the stop ends the session
+        assert!(manager.stop_recording(&recording_path).await.unwrap());
+        session.await.unwrap().unwrap();
+
+        let metadata = RecordingMetadata::load(&recording_path)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(RecordingState::Finished, metadata.state);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_pushes_stay_under_the_limit() {
+        let Setup { dir, manager, .. } = Setup::new(|config| {
+            config.limits.max_active_recordings = Some(1);
+        });
+
+        let (_first_client, first_server) = tokio::io::duplex(1024);
+        let (_second_client, second_server) = tokio::io::duplex(1024);
+        // Both see room for one more before either is registered
+        assert!(manager.can_start_recording().await);
+        let (first, second) = tokio::join!(
+            manager.clone().start_recording(
+                dir.path().join("01_10_00_00.webm"),
+                RecordingOptions::default(),
+                first_server,
+            ),
+            manager.clone().start_recording(
+                dir.path().join("01_10_00_01.webm"),
+                RecordingOptions::default(),
+                second_server,
+            ),
+        );
+
+        assert!(first.is_ok() != second.is_ok());
+        assert_eq!(1, manager.active_recordings().await.len());
+    }
+
+    #[tokio::test]
+    async fn test_stop_recording_whose_file_was_removed() {
+        let Setup {
+            dir: _dir,
+            manager,
+            recording_path,
+        } = Setup::new(|config| {
+            config.resume_window_secs = 0;
+        });
+
+        let (mut client, session) =
+            push(&manager, &recording_path, RecordingOptions::default()).await;
+        client.write_all(&fixture::recording(2, 4)).await.unwrap();
+        let mut progress = manager.subscribe(&recording_path).await.unwrap();
+        progress.wait_for_data(0).await;
+        std::fs::remove_file(&recording_path).unwrap();
+
+        assert!(manager.is_recording(&recording_path).await);
+        assert!(manager.stop_recording(&recording_path).await.unwrap());
+        // Finalizing the missing file fails, the session still leaves the map
+        session.await.unwrap().unwrap();
+        manager.shutdown().await;
+        assert!(manager.active_recordings().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_finalizes_active_recordings() {
+        let Setup {
+            dir: _dir,
+            manager,
+            recording_path,
+        } = Setup::new(|_| {});
+
+        let (mut client, session) =
+            push(&manager, &recording_path, RecordingOptions::default()).await;
+        client.write_all(&fixture::recording(2, 4)).await.unwrap();
+        let mut progress = manager.subscribe(&recording_path).await.unwrap();
+        progress.wait_for_data(0).await;
+
+        manager.shutdown().await;
+        assert!(!manager.can_start_recording().await);
+        assert!(manager.active_recordings().await.is_empty());
+        session.await.unwrap().unwrap();
+
+        let metadata = RecordingMetadata::load(&recording_path)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(RecordingState::Finished, metadata.state);
+    }
+
+    #[tokio::test]
+    async fn test_events_of_a_push_session() {
+        let Setup {
+            dir: _dir,
+            manager,
+            recording_path,
+        } = Setup::new(|config| {
+            config.resume_window_secs = 0;
+            config.events.bytes_milestone = 1024;
+        });
+        let mut events = manager.subscribe_events();
+
+        let (mut client, session) =
+            push(&manager, &recording_path, RecordingOptions::default()).await;
+        let recording = fixture::recording(2, 4);
+        client.write_all(&recording).await.unwrap();
+        let mut progress = manager.subscribe(&recording_path).await.unwrap();
+        progress.wait_for_data(fixture::header().len() as u64).await;
+        let viewer = manager.live_viewer(&recording_path).await.unwrap();
+        drop(client);
+        session.await.unwrap().unwrap();
+        drop(viewer);
+
+        let mut kinds = vec![];
+        loop {
+            let event = events.recv().await.unwrap();
+            assert_eq!("01_10_00_00.webm", event.recording);
+            let finalized = matches!(event.kind, EventKind::Finalized { .. });
+            kinds.push(event.kind);
+            if finalized {
+                break;
+            }
+        }
+        let milestones = kinds
+            .iter()
+            .filter(|kind| matches!(kind, EventKind::BytesReceived { .. }))
+            .count();
+        assert_eq!(recording.len() / 1024, milestones);
+        // The viewer leaves when the feed ends, racing with the end of the session
+        kinds.retain(|kind| {
+            !matches!(
+                kind,
+                EventKind::BytesReceived { .. } | EventKind::ViewerLeft { .. }
+            )
+        });
+        assert!(matches!(
+            kinds[0],
+            EventKind::Started {
+                session_id: Some(_),
+                group: None
+            }
+        ));
+        assert_eq!(
+            vec![
+                EventKind::FirstKeyframe,
+                EventKind::ViewerJoined { viewers: 1 },
+                EventKind::Stopped,
+            ],
+            kinds[1..4]
+        );
+        assert!(matches!(
+            kinds[4],
+            EventKind::Finalized {
+                duration_ms: Some(_),
+                ..
+            }
+        ));
+
+        manager.shutdown().await;
+        assert!(events.recv().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rotate_segments_at_keyframes() {
+        let Setup {
+            dir: _dir,
+            manager,
+            recording_path,
+        } = Setup::new(|config| {
+            // The session ends as soon as the client hangs up
+            config.resume_window_secs = 0;
+        });
+        let options = RecordingOptions::builder()
+            .rotation(RotationConfig {
+                max_segment_secs: None,
+                max_segment_bytes: Some(1),
+            })
+            .build();
+
+        let (mut client, session) = push(&manager, &recording_path, options).await;
+        client.write_all(&fixture::recording(3, 4)).await.unwrap();
+        drop(client);
+        session.await.unwrap().unwrap();
+
+        let metadata = RecordingMetadata::load(&recording_path)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(RecordingState::Finished, metadata.state);
+        let segments: Vec<_> = metadata
+            .segments
+            .iter()
+            .map(|segment| (segment.file_name.as_str(), segment.start_timestamp))
+            .collect();
+        let cluster_duration = 4 * fixture::FRAME_DURATION_MS;
+        assert_eq!(
+            vec![
+                ("01_10_00_00.webm", 0),
+                ("01_10_00_00.002.webm", cluster_duration),
+                ("01_10_00_00.003.webm", 2 * cluster_duration),
+            ],
+            segments
+        );
+        for path in metadata.segment_paths(&recording_path) {
+            let report = repair::validate(&path).unwrap();
+            assert!(report.issues.is_empty(), "{:?}: {:?}", path, report.issues);
+            assert_eq!(1, report.cluster_count);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tail_follows_the_push_across_segments() {
+        let Setup {
+            dir,
+            manager,
+            recording_path,
+        } = Setup::new(|config| {
+            config.resume_window_secs = 0;
+        });
+        let options = RecordingOptions::builder()
+            .rotation(RotationConfig {
+                max_segment_secs: None,
+                max_segment_bytes: Some(1),
+            })
+            .build();
+
+        let (mut client, session) = push(&manager, &recording_path, options).await;
+        let progress = manager.subscribe(&recording_path).await.unwrap();
+        let file = open_read(&recording_path).await.unwrap();
+        let tailed = tokio::spawn(
+            growing_file::tail_segments(recording_path.clone(), file, progress, usize::MAX)
+                .map_ok(|chunk| chunk.to_vec())
+                .try_concat(),
+        );
+        client.write_all(&fixture::recording(3, 4)).await.unwrap();
+        drop(client);
+        session.await.unwrap().unwrap();
+
+        // One header, then the clusters of the three segments at their timestamps in the push
+        let tailed_path = dir.path().join("tailed.webm");
+        std::fs::write(&tailed_path, tailed.await.unwrap().unwrap()).unwrap();
+        let index = RecordingIndex::build(&tailed_path).unwrap();
+        assert_eq!(fixture::header().len() as u64, index.header_end);
+        let cluster_duration = 4 * fixture::FRAME_DURATION_MS;
+        let timestamps: Vec<_> = index
+            .clusters
+            .iter()
+            .map(|cluster| cluster.timestamp_ms)
+            .collect();
+        assert_eq!(vec![0, cluster_duration, 2 * cluster_duration], timestamps);
+    }
+
+    #[tokio::test]
+    async fn test_slow_file_writer_gets_every_cluster() {
+        let Setup {
+            dir: _dir,
+            manager,
+            recording_path,
+        } = Setup::new(|config| {
+            config.resume_window_secs = 0;
+            // Every read from the client publishes far more chunks than this
+            config.live.feed_capacity = 1;
+        });
+
+        let (mut client, session) =
+            push(&manager, &recording_path, RecordingOptions::default()).await;
+        client.write_all(&fixture::recording(20, 4)).await.unwrap();
+        drop(client);
+        session.await.unwrap().unwrap();
+
+        let report = repair::validate(&recording_path).unwrap();
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+        assert_eq!(20, report.cluster_count);
+    }
+
+    #[tokio::test]
+    async fn test_push_reply_failure_leaves_no_active_recording() {
+        let Setup {
+            dir: _dir,
+            manager,
+            recording_path,
+        } = Setup::new(|_| {});
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        // The client is gone before the reply is sent
+        drop(client);
+        assert!(manager
+            .clone()
+            .start_recording(recording_path.clone(), RecordingOptions::default(), server)
+            .await
+            .is_err());
+
+        assert!(!recording_path.exists());
+        let metadata = RecordingMetadata::load(&recording_path)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(RecordingState::Corrupt, metadata.state);
+        manager.shutdown().await;
+        assert!(manager.active_recordings().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_resume_push_into_the_same_recording() {
+        let Setup {
+            dir: _dir,
+            manager,
+            recording_path,
+        } = Setup::new(|config| {
+            config.resume_window_secs = 0;
+        });
+
+        let (mut client, session) =
+            push(&manager, &recording_path, RecordingOptions::default()).await;
+        let mut reply = vec![0; 1024];
+        let n = client.read(&mut reply).await.unwrap();
+        let reply: PushReply = serde_json::from_slice(&reply[..n]).unwrap();
+        assert_eq!("01_10_00_00.webm", reply.recording);
+        client.write_all(&fixture::recording(2, 4)).await.unwrap();
+        let mut progress = manager.subscribe(&recording_path).await.unwrap();
+        // The first cluster is written once the second one started, both were read
+        progress.wait_for_data(fixture::header().len() as u64).await;
+
+        // The client comes back before the old connection is noticed dead
+        assert!(manager.can_resume(&reply.session_token).await);
+        assert!(!manager.can_resume("unknown").await);
+        let (mut resumed_client, resumed_server) = tokio::io::duplex(64 * 1024);
+        let resumed = manager
+            .resume_recording(&reply.session_token, resumed_server)
+            .await
+            .unwrap();
+        assert_eq!(file_name(&recording_path), file_name(&resumed));
+        // A new MediaRecorder starts with a header of its own and timestamps from 0
+        resumed_client
+            .write_all(&fixture::recording(2, 4))
+            .await
+            .unwrap();
+        drop(resumed_client);
+        session.await.unwrap().unwrap();
+        drop(client);
+
+        let metadata = RecordingMetadata::load(&recording_path)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(RecordingState::Finished, metadata.state);
+        let report = repair::validate(&recording_path).unwrap();
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+        assert_eq!(4, report.cluster_count);
+    }
+
+    #[tokio::test]
+    async fn test_recover_interrupted_recordings() {
+        let Setup {
+            dir,
+            manager,
+            recording_path: interrupted,
+        } = Setup::new(|_| {});
+
+        let recording = fixture::recording(3, 4);
+        std::fs::write(&interrupted, &recording[..recording.len() - 5]).unwrap();
+        RecordingMetadata::new(&interrupted)
+            .save(&interrupted)
+            .await
+            .unwrap();
+        // Died between writing the sidecar and creating the file
+        let never_written = dir.path().join("01_11_00_00.webm");
+        RecordingMetadata::new(&never_written)
+            .save(&never_written)
+            .await
+            .unwrap();
+
+        assert_eq!(2, manager.recover().await.unwrap());
+
+        let metadata = RecordingMetadata::load(&interrupted)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(RecordingState::Finished, metadata.state);
+        assert!(metadata.recovered_at.is_some());
+        assert_eq!(2, metadata.tracks.len());
+        assert!(repair::validate(&interrupted).unwrap().issues.is_empty());
+
+        let metadata = RecordingMetadata::load(&never_written)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(RecordingState::Corrupt, metadata.state);
+
+        // Nothing is left to recover on the next start
+        assert_eq!(0, manager.recover().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_recover_drops_segment_without_cluster() {
+        let Setup {
+            dir,
+            manager,
+            recording_path,
+        } = Setup::new(|_| {});
+
+        std::fs::write(&recording_path, fixture::recording(2, 4)).unwrap();
+        // Rotated into just before the crash
+        let empty_segment = dir.path().join("01_10_00_00.002.webm");
+        std::fs::write(&empty_segment, fixture::header()).unwrap();
+        let mut metadata = RecordingMetadata::new(&recording_path);
+        metadata.segments = ["01_10_00_00.webm", "01_10_00_00.002.webm"]
+            .into_iter()
+            .map(|file_name| RecordingSegment {
+                file_name: file_name.to_string(),
+                start_timestamp: 0,
+                byte_size: 0,
+                duration_ms: None,
+            })
+            .collect();
+        metadata.save(&recording_path).await.unwrap();
+
+        assert_eq!(1, manager.recover().await.unwrap());
+
+        let metadata = RecordingMetadata::load(&recording_path)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(RecordingState::Finished, metadata.state);
+        assert_eq!(1, metadata.segments.len());
+        assert!(!empty_segment.exists());
+        assert!(repair::validate(&recording_path).unwrap().issues.is_empty());
+    }
+}
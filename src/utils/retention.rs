@@ -0,0 +1,305 @@
+//! Pruning of finished recordings by age, total size, count per group and free disk space.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
+
+use crate::{
+    config::RetentionConfig,
+    jrec::{
+        metadata::{RecordingMetadata, RecordingState},
+        utils::list_recordings,
+    },
+};
+
+use super::recording_manager::RecordingManager;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PruneSummary {
+    pub deleted: usize,
+    pub freed_bytes: u64,
+}
+
+/// Recordings to delete to get under every limit of `config`, by file name, oldest first.
+///
+/// Only recordings in `candidates` are picked, the others still count toward the limits.
+/// `missing_free_bytes` is how much has to go for the disk to have `min_free_bytes` left.
+pub fn select_expired(
+    recordings: &[RecordingMetadata],
+    candidates: &HashSet<String>,
+    config: &RetentionConfig,
+    missing_free_bytes: u64,
+    now: DateTime<Utc>,
+) -> Vec<String> {
+    let mut oldest_first: Vec<_> = recordings.iter().collect();
+    oldest_first.sort_by_key(|recording| recording.started_at);
+
+    let mut group_counts = HashMap::<_, usize>::new();
+    for recording in &oldest_first {
+        *group_counts.entry(recording.group.as_deref()).or_default() += 1;
+    }
+    let mut total_bytes: u64 = oldest_first
+        .iter()
+        .map(|recording| recording.byte_size)
+        .sum();
+    let mut freed_bytes = 0;
+
+    let mut expired = vec![];
+    for recording in oldest_first {
+        if !candidates.contains(&recording.file_name) {
+            continue;
+        }
+
+        let too_old = config.max_age_secs.is_some_and(|max_age_secs| {
+            (now - recording.started_at).num_seconds() > max_age_secs as i64
+        });
+        let group_count = group_counts
+            .get_mut(&recording.group.as_deref())
+            .expect("every group is counted");
+        let group_full = config
+            .max_recordings_per_group
+            .is_some_and(|max_recordings| *group_count > max_recordings);
+        let too_large = config
+            .max_total_bytes
+            .is_some_and(|max_total_bytes| total_bytes > max_total_bytes);
+        let disk_full = freed_bytes < missing_free_bytes;
+
+        if too_old || group_full || too_large || disk_full {
+            *group_count -= 1;
+            total_bytes -= recording.byte_size;
+            freed_bytes += recording.byte_size;
+            expired.push(recording.file_name.clone());
+        }
+    }
+
+    expired
+}
+
+/// One pass over the recording directory. Active recordings, recordings being viewed and
+/// recordings viewed lately are kept.
+pub async fn prune(recording_manager: &RecordingManager) -> anyhow::Result<PruneSummary> {
+    let config = &recording_manager.config().retention;
+    let recording_dir = recording_manager.recording_dir();
+    let recordings = list_recordings(recording_dir)
+        .await
+        .map_err(|status| anyhow::anyhow!("listing recordings failed with {}", status))?;
+
+    let grace = Duration::from_secs(config.view_grace_secs);
+    let mut candidates = HashSet::new();
+    for recording in &recordings {
+        let path = recording_dir.join(&recording.file_name);
+        // The sidecar stays active while the recording is finalized
+        if recording.state == RecordingState::Active
+            || recording_manager.subscribe(&path).await.is_some()
+            || recording_manager.is_viewed(&recording.file_name)
+            || recording_manager.recently_viewed(&recording.file_name, grace)
+        {
+            continue;
+        }
+        candidates.insert(recording.file_name.clone());
+    }
+
+    let missing_free_bytes = match config.min_free_bytes {
+        Some(min_free_bytes) => min_free_bytes.saturating_sub(available_space(recording_dir)?),
+        None => 0,
+    };
+    let expired = select_expired(
+        &recordings,
+        &candidates,
+        config,
+        missing_free_bytes,
+        Utc::now(),
+    );
+
+    let mut summary = PruneSummary::default();
+    for file_name in expired {
+        let path = recording_dir.join(&file_name);
+        match delete_recording(&path).await {
+            Ok(freed_bytes) => {
+                info!(?path, freed_bytes, "Pruned recording");
+                summary.deleted += 1;
+                summary.freed_bytes += freed_bytes;
+            }
+            Err(e) => error!(?path, ?e, "Failed to prune recording"),
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Whether a push may start under `min_free_bytes`, pruning first when the disk is short.
+pub async fn ensure_free_space(recording_manager: &RecordingManager) -> anyhow::Result<bool> {
+    let Some(min_free_bytes) = recording_manager.config().retention.min_free_bytes else {
+        return Ok(true);
+    };
+    let recording_dir = recording_manager.recording_dir();
+    if available_space(recording_dir)? >= min_free_bytes {
+        return Ok(true);
+    }
+
+    let summary = prune(recording_manager).await?;
+    let available = available_space(recording_dir)?;
+    if available < min_free_bytes {
+        warn!(
+            available,
+            min_free_bytes,
+            pruned = summary.deleted,
+            "Not enough free space for a recording"
+        );
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+/// Prunes every `retention.interval_secs`.
+pub fn spawn(recording_manager: Arc<RecordingManager>) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let period = Duration::from_secs(recording_manager.config().retention.interval_secs);
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            match prune(&recording_manager).await {
+                Ok(summary) if summary.deleted > 0 => info!(?summary, "Retention pass done"),
+                Ok(_) => {}
+                Err(e) => error!(?e, "Retention pass failed"),
+            }
+        }
+    })
+}
+
+pub(crate) fn available_space(recording_dir: &Path) -> anyhow::Result<u64> {
+    fs2::available_space(recording_dir)
+        .with_context(|| format!("reading free space of {:?}", recording_dir))
+}
+
+/// Deletes the recording with its segments and sidecar, the sidecar last so a listing never sees
+/// a recording without one.
+async fn delete_recording(path: &Path) -> anyhow::Result<u64> {
+    let segment_paths = match RecordingMetadata::load(path).await? {
+        Some(metadata) => metadata.segment_paths(path),
+        None => vec![path.to_path_buf()],
+    };
+
+    let mut freed_bytes = 0;
+    for segment_path in segment_paths {
+        match tokio::fs::metadata(&segment_path).await {
+            Ok(file_metadata) => freed_bytes += file_metadata.len(),
+            // Already gone, or a segment listed when the server died before writing it
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        }
+        tokio::fs::remove_file(&segment_path).await?;
+    }
+    match tokio::fs::remove_file(RecordingMetadata::sidecar_path(path)).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    Ok(freed_bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use crate::{config::ServerConfig, jrec::webm::fixture};
+
+    use super::*;
+
+    fn recording(name: &str, hour: u32, group: Option<&str>, byte_size: u64) -> RecordingMetadata {
+        let mut recording = RecordingMetadata::new(Path::new(name));
+        recording.started_at = Utc.with_ymd_and_hms(2024, 10, 1, hour, 0, 0).unwrap();
+        recording.state = RecordingState::Finished;
+        recording.group = group.map(str::to_string);
+        recording.byte_size = byte_size;
+        recording
+    }
+
+    #[test]
+    fn test_select_oldest_recordings_over_the_limits() {
+        let recordings = vec![
+            recording("a.webm", 8, Some("lobby"), 100),
+            recording("b.webm", 9, None, 100),
+            recording("c.webm", 10, Some("lobby"), 100),
+            recording("d.webm", 11, Some("lobby"), 100),
+            recording("e.webm", 12, None, 100),
+        ];
+        let all: HashSet<_> = recordings
+            .iter()
+            .map(|recording| recording.file_name.clone())
+            .collect();
+        let now = Utc.with_ymd_and_hms(2024, 10, 1, 12, 30, 0).unwrap();
+
+        let config = RetentionConfig {
+            max_recordings_per_group: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            vec!["a.webm"],
+            select_expired(&recordings, &all, &config, 0, now)
+        );
+
+        let config = RetentionConfig {
+            max_age_secs: Some(3 * 3600),
+            max_total_bytes: Some(250),
+            ..Default::default()
+        };
+        assert_eq!(
+            vec!["a.webm", "b.webm", "c.webm"],
+            select_expired(&recordings, &all, &config, 0, now)
+        );
+
+        // A recording being watched is kept, the next one goes in its place
+        let mut candidates = all.clone();
+        candidates.remove("a.webm");
+        assert_eq!(
+            vec!["b.webm", "c.webm"],
+            select_expired(
+                &recordings,
+                &candidates,
+                &RetentionConfig::default(),
+                150,
+                now
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_keep_recording_while_a_viewer_session_is_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = ServerConfig {
+            recording_dir: dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        config.retention.max_age_secs = Some(60);
+        // Longer sessions than that are still kept
+        config.retention.view_grace_secs = 0;
+        let manager = RecordingManager::new(Arc::new(config));
+
+        let path = dir.path().join("01_10_00_00.webm");
+        std::fs::write(&path, fixture::recording(1, 4)).unwrap();
+        recording("01_10_00_00.webm", 8, None, 100)
+            .save(&path)
+            .await
+            .unwrap();
+
+        let view = manager.open_view(&path);
+        let other_view = manager.open_view(&path);
+        assert_eq!(0, prune(&manager).await.unwrap().deleted);
+        drop(view);
+        assert_eq!(0, prune(&manager).await.unwrap().deleted);
+        drop(other_view);
+        assert_eq!(1, prune(&manager).await.unwrap().deleted);
+        assert!(!path.exists());
+    }
+}
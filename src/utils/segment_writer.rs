@@ -0,0 +1,159 @@
+//! Writes the chunks of a push session to its recording, moving on to a new file at a keyframe cluster
+//! once the current one is long or large enough.
+
+use std::{path::PathBuf, time::Duration};
+
+use bytes::Bytes;
+use tokio::{
+    io::{AsyncWriteExt, BufWriter},
+    time::Instant,
+};
+use tracing::{error, info};
+
+use crate::{
+    config::RotationConfig,
+    jrec::{
+        metadata::{file_name, segment_path, RecordingMetadata, RecordingSegment},
+        webm::splitter::LiveChunk,
+    },
+};
+
+use super::{file::open_write, growing_file::WriteNotifier, FileWithLoggin};
+
+pub struct SegmentWriter {
+    recording_path: PathBuf,
+    rotation: RotationConfig,
+    file: BufWriter<FileWithLoggin>,
+    /// Written again at the start of every new segment
+    header: Option<Bytes>,
+    segments: Vec<RecordingSegment>,
+    segment_started: Instant,
+    /// Whether the current segment holds a cluster yet
+    has_cluster: bool,
+    /// Readers of the first segment get its end on rotation, readers following every segment move on to the next one
+    notifier: WriteNotifier,
+    /// Saved with the segments so far on every rotation, so recovery finds them
+    sidecar: RecordingMetadata,
+}
+
+impl SegmentWriter {
+    pub fn new(
+        recording_path: PathBuf,
+        file: FileWithLoggin,
+        notifier: WriteNotifier,
+        rotation: RotationConfig,
+        sidecar: RecordingMetadata,
+    ) -> Self {
+        Self {
+            segments: vec![RecordingSegment {
+                file_name: file_name(&recording_path),
+                start_timestamp: 0,
+                byte_size: 0,
+                duration_ms: None,
+            }],
+            recording_path,
+            rotation,
+            file: BufWriter::new(file),
+            header: None,
+            segment_started: Instant::now(),
+            has_cluster: false,
+            notifier,
+            sidecar,
+        }
+    }
+
+    pub async fn write(&mut self, chunk: &LiveChunk) -> anyhow::Result<()> {
+        let data = match chunk {
+            LiveChunk::Header(header) => {
+                self.header = Some(header.clone());
+                header.clone()
+            }
+            LiveChunk::Cluster(cluster) => {
+                if cluster.keyframe && self.has_cluster && self.should_rotate() {
+                    self.rotate(cluster.timestamp).await?;
+                }
+                self.has_cluster = true;
+                cluster.rebased(self.current().start_timestamp)
+            }
+            LiveChunk::Raw(data) => data.clone(),
+        };
+
+        self.file.write_all(&data).await?;
+        self.file.flush().await?;
+        self.segments
+            .last_mut()
+            .expect("one segment at least")
+            .byte_size += data.len() as u64;
+        if let [segment] = self.segments.as_slice() {
+            self.notifier.wrote(segment.byte_size);
+        } else {
+            self.notifier.wrote_elsewhere(self.total_bytes());
+        }
+
+        Ok(())
+    }
+
+    /// Flushes the last segment, the segments are listed in order.
+    pub async fn finish(mut self) -> Vec<RecordingSegment> {
+        if let Err(e) = self.file.shutdown().await {
+            error!(?e, "Failed to flush recording");
+        }
+        self.notifier.finish();
+
+        self.segments
+    }
+
+    fn total_bytes(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.byte_size).sum()
+    }
+
+    fn current(&self) -> &RecordingSegment {
+        self.segments.last().expect("one segment at least")
+    }
+
+    fn should_rotate(&self) -> bool {
+        // Without a header the next file could not be played on its own
+        self.header.is_some()
+            && (self
+                .rotation
+                .max_segment_bytes
+                .is_some_and(|max_segment_bytes| self.current().byte_size >= max_segment_bytes)
+                || self
+                    .rotation
+                    .max_segment_secs
+                    .is_some_and(|max_segment_secs| {
+                        self.segment_started.elapsed() >= Duration::from_secs(max_segment_secs)
+                    }))
+    }
+
+    async fn rotate(&mut self, start_timestamp: u64) -> anyhow::Result<()> {
+        let path = segment_path(&self.recording_path, self.segments.len());
+        self.segments.push(RecordingSegment {
+            file_name: file_name(&path),
+            start_timestamp,
+            byte_size: 0,
+            duration_ms: None,
+        });
+        // Listed before the file exists, like the recording itself
+        self.sidecar.segments = self.segments.clone();
+        if let Err(e) = self.sidecar.save(&self.recording_path).await {
+            error!(?e, "Failed to list the new segment in the sidecar");
+        }
+
+        let file = open_write(&path).await?;
+        let mut previous =
+            std::mem::replace(&mut self.file, BufWriter::new(FileWithLoggin::new(file)));
+        previous.shutdown().await?;
+        self.notifier.rotated();
+        info!(?path, start_timestamp, "Recording rotated to a new segment");
+
+        self.segment_started = Instant::now();
+        self.has_cluster = false;
+        let header = self.header.clone().expect("rotating after the header");
+        self.file.write_all(&header).await?;
+        self.segments.last_mut().expect("just pushed").byte_size += header.len() as u64;
+        self.notifier.wrote_elsewhere(self.total_bytes());
+
+        Ok(())
+    }
+}
@@ -1,26 +1,36 @@
 use std::sync::Arc;
 
+use crate::{config::ServerConfig, jrec::auth::TokenVerifier};
+
 use super::recording_manager::RecordingManager;
 
 #[derive(Debug, Clone)]
 pub struct AppState {
+    config: Arc<ServerConfig>,
     recording_manager: Arc<RecordingManager>,
-}
-
-impl Default for AppState {
-    fn default() -> Self {
-        Self::new()
-    }
+    /// Every route is open without one
+    token_verifier: Option<Arc<dyn TokenVerifier>>,
 }
 
 impl AppState {
-    pub fn new() -> Self {
+    pub fn new(config: ServerConfig, token_verifier: Option<Arc<dyn TokenVerifier>>) -> Self {
+        let config = Arc::new(config);
         Self {
-            recording_manager: RecordingManager::new(),
+            recording_manager: RecordingManager::new(config.clone()),
+            config,
+            token_verifier,
         }
     }
 
+    pub fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
     pub fn recording_manager(&self) -> Arc<RecordingManager> {
         self.recording_manager.clone()
     }
+
+    pub fn token_verifier(&self) -> Option<&dyn TokenVerifier> {
+        self.token_verifier.as_deref()
+    }
 }
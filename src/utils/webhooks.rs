@@ -0,0 +1,540 @@
+//! Notifications of recordings starting, stopping, being finalized or failing, POSTed to the configured
+//! endpoints. They go through an outbox on disk first, so a restart does not lose them.
+
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use reqwest::header;
+use ring::hmac;
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    config::WebhooksConfig,
+    jrec::events::{EventKind, RecordingEvent},
+};
+
+use super::recording_manager::RecordingManager;
+
+/// A notification on its way to one endpoint, as stored in the outbox.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+struct Notification {
+    /// Sent as `X-Webhook-Id`, receivers can tell a retry from a new notification
+    id: Uuid,
+    url: String,
+    event: RecordingEvent,
+    attempts: u32,
+    next_attempt_at: DateTime<Utc>,
+}
+
+struct Endpoint {
+    url: String,
+    key: Option<hmac::Key>,
+}
+
+struct Outbox {
+    dir: PathBuf,
+}
+
+impl Outbox {
+    fn path(&self, id: Uuid) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    /// The notifications left over by the last run.
+    async fn load(&self) -> anyhow::Result<Vec<Notification>> {
+        let mut entries = tokio::fs::read_dir(&self.dir)
+            .await
+            .with_context(|| format!("reading webhook outbox {:?}", self.dir))?;
+
+        let mut notifications = vec![];
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let parsed = tokio::fs::read(&path)
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|content| Ok(serde_json::from_slice(&content)?));
+            match parsed {
+                Ok(notification) => notifications.push(notification),
+                Err(e) => warn!(?path, ?e, "Skipping unreadable webhook notification"),
+            }
+        }
+        notifications.sort_by_key(|notification: &Notification| notification.event.at);
+
+        Ok(notifications)
+    }
+
+    async fn save(&self, notification: &Notification) -> anyhow::Result<()> {
+        let path = self.path(notification.id);
+        // Written aside and renamed, a crash never leaves half a notification behind
+        let temp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&temp_path, serde_json::to_vec_pretty(notification)?).await?;
+        tokio::fs::rename(&temp_path, &path)
+            .await
+            .with_context(|| format!("writing webhook notification {:?}", path))
+    }
+
+    async fn remove(&self, id: Uuid) -> anyhow::Result<()> {
+        Ok(tokio::fs::remove_file(self.path(id)).await?)
+    }
+
+    /// Kept for an operator to look at, but never sent again.
+    async fn give_up(&self, id: Uuid) -> anyhow::Result<()> {
+        let path = self.path(id);
+        Ok(tokio::fs::rename(&path, path.with_extension("failed")).await?)
+    }
+}
+
+/// Starts sending notifications, `None` when no endpoint is configured. The handle finishes once every
+/// event published before the recording manager shut down is in the outbox.
+/// Only the recordings started or recovered from now on are notified about.
+pub async fn spawn(recording_manager: &RecordingManager) -> anyhow::Result<Option<JoinHandle<()>>> {
+    let config = recording_manager.config().webhooks.clone();
+    if config.endpoints.is_empty() {
+        return Ok(None);
+    }
+
+    let endpoints = config
+        .endpoints
+        .iter()
+        .map(|endpoint| {
+            let key = match &endpoint.secret_file {
+                Some(secret_file) => {
+                    let secret = std::fs::read(secret_file).with_context(|| {
+                        format!("reading webhook secret file {:?}", secret_file)
+                    })?;
+                    // Secret files usually end with a newline the receiver does not use
+                    Some(hmac::Key::new(hmac::HMAC_SHA256, secret.trim_ascii()))
+                }
+                None => None,
+            };
+            Ok(Endpoint {
+                url: endpoint.url.clone(),
+                key,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let outbox = Outbox {
+        dir: recording_manager.config().webhook_outbox_dir(),
+    };
+    tokio::fs::create_dir_all(&outbox.dir)
+        .await
+        .with_context(|| format!("creating webhook outbox {:?}", outbox.dir))?;
+    let pending = outbox.load().await?;
+    if !pending.is_empty() {
+        info!(
+            pending = pending.len(),
+            "Resuming webhook notifications of the last run"
+        );
+    }
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(config.timeout_secs))
+        .build()?;
+
+    let outbox = Arc::new(outbox);
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let events = recording_manager.subscribe_events_lossless();
+    let urls = endpoints
+        .iter()
+        .map(|endpoint| endpoint.url.clone())
+        .collect();
+    let delivery = Delivery {
+        config,
+        endpoints,
+        outbox: outbox.clone(),
+        client,
+    };
+    tokio::spawn(delivery.run(pending, receiver));
+
+    Ok(Some(tokio::spawn(enqueue(events, urls, outbox, sender))))
+}
+
+/// Stores a notification for every endpoint as events come in, and hands it to the delivery.
+async fn enqueue(
+    mut events: mpsc::UnboundedReceiver<RecordingEvent>,
+    urls: Vec<String>,
+    outbox: Arc<Outbox>,
+    sender: mpsc::UnboundedSender<Notification>,
+) {
+    while let Some(event) = events.recv().await {
+        if !matches!(
+            event.kind,
+            EventKind::Started { .. }
+                | EventKind::Stopped
+                | EventKind::Finalized { .. }
+                | EventKind::Failed { .. }
+        ) {
+            continue;
+        }
+
+        for url in &urls {
+            let notification = Notification {
+                id: Uuid::new_v4(),
+                url: url.clone(),
+                event: event.clone(),
+                attempts: 0,
+                next_attempt_at: Utc::now(),
+            };
+            if let Err(e) = outbox.save(&notification).await {
+                error!(
+                    ?e,
+                    "Failed to store webhook notification, sending it anyway"
+                );
+            }
+            // The delivery stopped along with the runtime, what is in the outbox goes out next time
+            sender.send(notification).ok();
+        }
+    }
+}
+
+struct Delivery {
+    config: WebhooksConfig,
+    endpoints: Vec<Endpoint>,
+    outbox: Arc<Outbox>,
+    client: reqwest::Client,
+}
+
+impl Delivery {
+    async fn run(
+        self,
+        mut pending: Vec<Notification>,
+        mut receiver: mpsc::UnboundedReceiver<Notification>,
+    ) {
+        let mut receiving = true;
+        loop {
+            let next_attempt_at = pending
+                .iter()
+                .map(|notification| notification.next_attempt_at)
+                .min();
+            let wait = next_attempt_at.map(|at| (at - Utc::now()).to_std().unwrap_or_default());
+            if wait.is_none() && !receiving {
+                return;
+            }
+
+            tokio::select! {
+                received = receiver.recv(), if receiving => match received {
+                    Some(notification) => pending.push(notification),
+                    None => receiving = false,
+                },
+                () = sleep(wait) => {
+                    let now = Utc::now();
+                    let (due, later) = pending
+                        .into_iter()
+                        .partition(|notification| notification.next_attempt_at <= now);
+                    pending = later;
+                    for notification in due {
+                        if let Some(retry) = self.attempt(notification).await {
+                            pending.push(retry);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends the notification once, returns it when it is to be tried again.
+    async fn attempt(&self, mut notification: Notification) -> Option<Notification> {
+        let Some(endpoint) = self
+            .endpoints
+            .iter()
+            .find(|endpoint| endpoint.url == notification.url)
+        else {
+            warn!(
+                url = notification.url,
+                "Dropping notification for an endpoint no longer configured"
+            );
+            self.outbox.remove(notification.id).await.ok();
+            return None;
+        };
+
+        notification.attempts += 1;
+        match self.send(endpoint, &notification).await {
+            Ok(()) => {
+                debug!(id = %notification.id, url = endpoint.url, "Webhook notification delivered");
+                if let Err(e) = self.outbox.remove(notification.id).await {
+                    error!(?e, "Failed to remove delivered webhook notification");
+                }
+                return None;
+            }
+            Err(e) if notification.attempts >= self.config.max_attempts => {
+                error!(
+                    ?e,
+                    id = %notification.id,
+                    url = endpoint.url,
+                    attempts = notification.attempts,
+                    "Giving up on webhook notification"
+                );
+                if let Err(e) = self.outbox.give_up(notification.id).await {
+                    error!(?e, "Failed to set aside webhook notification");
+                }
+                return None;
+            }
+            Err(e) => warn!(
+                ?e,
+                id = %notification.id,
+                url = endpoint.url,
+                attempts = notification.attempts,
+                "Webhook notification failed, retrying"
+            ),
+        }
+
+        notification.next_attempt_at = Utc::now() + self.backoff(notification.attempts);
+        if let Err(e) = self.outbox.save(&notification).await {
+            error!(?e, "Failed to store webhook notification");
+        }
+        Some(notification)
+    }
+
+    async fn send(&self, endpoint: &Endpoint, notification: &Notification) -> anyhow::Result<()> {
+        let body = serde_json::to_vec(&notification.event)?;
+        let mut request = self
+            .client
+            .post(&endpoint.url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("X-Webhook-Id", notification.id.to_string());
+        if let Some(key) = &endpoint.key {
+            request = request.header("X-Webhook-Signature", signature(key, &body));
+        }
+
+        request.body(body).send().await?.error_for_status()?;
+        Ok(())
+    }
+
+    /// Wait after the `attempts`th failure.
+    fn backoff(&self, attempts: u32) -> Duration {
+        let factor = 1u64 << attempts.saturating_sub(1).min(32);
+        Duration::from_secs(
+            self.config
+                .initial_backoff_secs
+                .saturating_mul(factor)
+                .min(self.config.max_backoff_secs),
+        )
+    }
+}
+
+/// `sha256=` and the hex HMAC-SHA256 of `body`.
+fn signature(key: &hmac::Key, body: &[u8]) -> String {
+    let tag = hmac::sign(key, body);
+    let hex: String = tag
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!("sha256={}", hex)
+}
+
+async fn sleep(wait: Option<Duration>) {
+    match wait {
+        Some(wait) => tokio::time::sleep(wait).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use axum::{
+        extract::State,
+        http::{HeaderMap, StatusCode},
+        routing::post,
+        Router,
+    };
+    use bytes::Bytes;
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    use crate::{
+        config::{ServerConfig, WebhookEndpoint},
+        jrec::{metadata::RecordingMetadata, webm::fixture},
+        utils::recording_manager::RecordingOptions,
+    };
+
+    use super::*;
+
+    type Received = Arc<Mutex<Vec<(HeaderMap, Bytes)>>>;
+
+    /// Fails the first request, accepts every one after that.
+    async fn receive(
+        State(received): State<Received>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> StatusCode {
+        let mut received = received.lock().unwrap();
+        received.push((headers, body));
+        if received.len() == 1 {
+            StatusCode::INTERNAL_SERVER_ERROR
+        } else {
+            StatusCode::OK
+        }
+    }
+
+    /// The URL of an endpoint recording what it receives with [`receive`].
+    async fn serve_hook() -> (String, Received) {
+        let received = Received::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new()
+            .route("/hook", post(receive))
+            .with_state(received.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        (format!("http://{}/hook", addr), received)
+    }
+
+    async fn wait_for_empty_outbox(outbox: &Outbox) {
+        let started = std::time::Instant::now();
+        while std::fs::read_dir(&outbox.dir).unwrap().next().is_some() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "notifications not delivered"
+            );
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    /// Recording and event kind of every notification delivered, retries counted once.
+    fn delivered(received: &Received) -> Vec<(String, String)> {
+        let mut delivered = std::collections::BTreeMap::new();
+        for (headers, body) in received.lock().unwrap().iter() {
+            let event: RecordingEvent = serde_json::from_slice(body).unwrap();
+            let kind = serde_json::to_value(&event.kind).unwrap()["event"].clone();
+            delivered.insert(
+                headers["x-webhook-id"].to_str().unwrap().to_string(),
+                (event.recording, kind.as_str().unwrap().to_string()),
+            );
+        }
+        let mut delivered: Vec<_> = delivered.into_values().collect();
+        delivered.sort();
+        delivered
+    }
+
+    #[tokio::test]
+    async fn test_delivers_signed_notifications_through_the_outbox() {
+        let (url, received) = serve_hook().await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let secret_file = dir.path().join("secret");
+        std::fs::write(&secret_file, "s3cret\n").unwrap();
+        let mut config = ServerConfig {
+            recording_dir: dir.path().to_path_buf(),
+            resume_window_secs: 0,
+            ..Default::default()
+        };
+        config.webhooks.endpoints = vec![WebhookEndpoint {
+            url: url.clone(),
+            secret_file: Some(secret_file),
+        }];
+        config.webhooks.initial_backoff_secs = 0;
+
+        // Left over by the last run
+        let outbox = Outbox {
+            dir: config.webhook_outbox_dir(),
+        };
+        std::fs::create_dir_all(&outbox.dir).unwrap();
+        outbox
+            .save(&Notification {
+                id: Uuid::new_v4(),
+                url,
+                event: RecordingEvent {
+                    recording: "old.webm".to_string(),
+                    at: Utc::now(),
+                    kind: EventKind::Stopped,
+                },
+                attempts: 3,
+                next_attempt_at: Utc::now(),
+            })
+            .await
+            .unwrap();
+
+        let manager = RecordingManager::new(Arc::new(config));
+        let intake = spawn(&manager).await.unwrap().unwrap();
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let session = manager
+            .clone()
+            .start_recording(
+                dir.path().join("01_10_00_00.webm"),
+                RecordingOptions::default(),
+                server,
+            )
+            .await
+            .unwrap();
+        client.write_all(&fixture::recording(2, 4)).await.unwrap();
+        drop(client);
+        session.await.unwrap().unwrap();
+        manager.shutdown().await;
+        intake.await.unwrap();
+
+        wait_for_empty_outbox(&outbox).await;
+
+        let key = hmac::Key::new(hmac::HMAC_SHA256, b"s3cret");
+        {
+            let received = received.lock().unwrap();
+            // The first attempt was refused and sent again, under the same id
+            assert_eq!(5, received.len());
+            for (headers, body) in received.iter() {
+                let sent = headers["x-webhook-signature"].to_str().unwrap();
+                assert_eq!(signature(&key, body), sent);
+            }
+        }
+        let expected: Vec<_> = [
+            ("01_10_00_00.webm", "finalized"),
+            ("01_10_00_00.webm", "started"),
+            ("01_10_00_00.webm", "stopped"),
+            ("old.webm", "stopped"),
+        ]
+        .into_iter()
+        .map(|(recording, kind)| (recording.to_string(), kind.to_string()))
+        .collect();
+        assert_eq!(expected, delivered(&received));
+    }
+
+    #[tokio::test]
+    async fn test_notifies_recovered_recordings() {
+        let (url, received) = serve_hook().await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = ServerConfig {
+            recording_dir: dir.path().to_path_buf(),
+            ..Default::default()
+        };
+        config.webhooks.endpoints = vec![WebhookEndpoint {
+            url,
+            secret_file: None,
+        }];
+        config.webhooks.initial_backoff_secs = 0;
+        // Every recovery outcome is emitted before the webhooks get to run
+        config.events.capacity = 1;
+        let outbox = Outbox {
+            dir: config.webhook_outbox_dir(),
+        };
+
+        let interrupted = dir.path().join("01_10_00_00.webm");
+        std::fs::write(&interrupted, fixture::recording(2, 4)).unwrap();
+        let never_written = dir.path().join("01_11_00_00.webm");
+        for path in [&interrupted, &never_written] {
+            RecordingMetadata::new(path).save(path).await.unwrap();
+        }
+
+        let manager = RecordingManager::new(Arc::new(config));
+        let intake = spawn(&manager).await.unwrap().unwrap();
+        assert_eq!(2, manager.recover().await.unwrap());
+        manager.shutdown().await;
+        intake.await.unwrap();
+        wait_for_empty_outbox(&outbox).await;
+
+        let expected: Vec<_> = [
+            ("01_10_00_00.webm", "finalized"),
+            ("01_11_00_00.webm", "failed"),
+        ]
+        .into_iter()
+        .map(|(recording, kind)| (recording.to_string(), kind.to_string()))
+        .collect();
+        assert_eq!(expected, delivered(&received));
+    }
+}
@@ -9,12 +9,27 @@ const API_LIST_RECORDINGS = `${HOST}/jet/jrec/list-recording`;
 const API_PULL_RECORDING = `${HOST}/jet/jrec/pull`;
 const API_TEST_PULL_RECORDING = `${HOST}/jet/jrec/test`;
 const API_STREAMING_PULL_RECORDING = `${HOST}/jet/jrec/stream-realtime`;
+const API_EVENTS = `${HOST}/jet/jrec/events`;
+// Events that change what the recording list shows
+const LIST_EVENTS = ["started", "stopped", "finalized", "failed", "lagged"];
 // const API_LIST_RECORDINGS = "http://localhost:3000/jet/jrec/list-recording";
 // const API_PULL_RECORDING = "http://localhost:3000/jet/jrec/pull";
 // const API_TEST_PULL_RECORDING = "http://localhost:3000/jet/jrec/test";
 
-// Define the type for a recording tuple
-type Recording = [filename: string, date: string];
+// A recording as described by its sidecar
+interface Recording {
+	file_name: string;
+	state: "active" | "finished" | "corrupt";
+	started_at: string;
+	duration_ms: number | null;
+	byte_size: number;
+}
+
+interface RecordingPage {
+	total: number;
+	offset: number;
+	items: Recording[];
+}
 
 // RecordingsList Component Props Interface
 interface RecordingsListProps {
@@ -31,9 +46,9 @@ function RecordingsList({
 		<div className="recordings-list">
 			<h2>Available Recordings</h2>
 			<ul>
-				{recordings.map(([filename, date]) => (
+				{recordings.map(({ file_name: filename, started_at, state }) => (
 					<li key={filename}>
-						{date} - {filename}
+						{new Date(started_at).toLocaleString()} - {filename} ({state})
 						<button onClick={() => openRecordingInPopup(filename, "play")}>
 							Play
 						</button>
@@ -82,8 +97,8 @@ function VideoApp() {
 	useEffect(() => {
 		const fetchRecordings = async () => {
 			const response = await fetch(API_LIST_RECORDINGS);
-			const data: Recording[] = await response.json();
-			setRecordings(data);
+			const data: RecordingPage = await response.json();
+			setRecordings(data.items);
 		};
 		const urlParams = new URLSearchParams(window.location.search);
 		const recording = urlParams.get("recording");
@@ -102,9 +117,18 @@ function VideoApp() {
 		if (recording) {
 			setRecordingToPlay(`${api}?recording=${encodeURIComponent(recording)}`);
 			setCurrentMode(mode);
-		} else {
-			fetchRecordings();
+			return;
 		}
+
+		fetchRecordings();
+		const events = new EventSource(API_EVENTS);
+		events.onmessage = (message) => {
+			const { event } = JSON.parse(message.data);
+			if (LIST_EVENTS.includes(event)) {
+				fetchRecordings();
+			}
+		};
+		return () => events.close();
 	}, []);
 
 	return (
@@ -1,5 +1,12 @@
 const MediaRecorderRecordInterval = 10;
-const recordingUrl = "ws://localhost:3000/jet/jrec/push";
+// wss:// when the page is served over https://, browsers block plain WebSockets from secure pages
+const recordingUrl = `${location.protocol === "https:" ? "wss" : "ws"}://${location.hostname}:3000/jet/jrec/push`;
+const ResumeDelay = 1000;
+
+interface PushReply {
+	recording: string;
+	session_token: string;
+}
 
 export interface TerminalHandle {
 	stop: () => void;
@@ -39,58 +46,94 @@ export async function startRecorder(
 	}
 
 	let onErrorCb = () => {};
+	let stopped = false;
+	let sessionToken: string | undefined;
+	let mediaRecorder: MediaRecorder | undefined;
+	let ws: WebSocket;
+	let resolveFilename: (filename: string) => void = () => {};
+	const filename = new Promise<string>((resolve) => {
+		resolveFilename = resolve;
+	});
 
 	const stream = canvas.captureStream();
-	const mediaRecorder = new MediaRecorder(stream, { mimeType: "video/webm" });
-
-	const ws = new WebSocket(recordingUrl);
-	ws.onopen = () => {
-		mediaRecorder.start(MediaRecorderRecordInterval);
-		mediaRecorder.ondataavailable = (e) => {
-			// console log the data in hex dump, for debugging
-			// debugPrintHexDump(e);
-			ws.send(e.data);
+
+	// A resumed session needs a new MediaRecorder, the server drops the second header
+	const connect = (resume: string | undefined) => {
+		const url = resume
+			? `${recordingUrl}?resume=${encodeURIComponent(resume)}`
+			: recordingUrl;
+		ws = new WebSocket(url);
+		const socket = ws;
+		const recorder = new MediaRecorder(stream, { mimeType: "video/webm" });
+		mediaRecorder = recorder;
+
+		socket.onopen = () => {
+			recorder.start(MediaRecorderRecordInterval);
+			recorder.ondataavailable = (e) => {
+				// console log the data in hex dump, for debugging
+				// debugPrintHexDump(e);
+				if (socket.readyState === socket.OPEN) {
+					socket.send(e.data);
+				}
+			};
+			recorder.onstop = () => {
+				if (socket.readyState === socket.OPEN) {
+					socket.close();
+				}
+			};
 		};
-		mediaRecorder.onstop = () => {
-			if (ws.readyState === ws.OPEN) {
-				ws.close();
-			}
+
+		socket.onerror = (e) => {
+			console.error("Error in recording websocket", e);
 		};
-	};
 
-	ws.onerror = (e) => {
-		console.error("Error in recording websocket", e);
-		mediaRecorder.stop();
-		onErrorCb();
-	};
+		socket.onclose = () => {
+			if (recorder.state !== "inactive") {
+				recorder.stop();
+			}
+			console.log("Recording websocket closed");
+			if (stopped) {
+				return;
+			}
+			if (sessionToken === undefined) {
+				onErrorCb();
+				return;
+			}
+			console.log("Resuming recording session");
+			setTimeout(() => {
+				if (!stopped) {
+					connect(sessionToken);
+				}
+			}, ResumeDelay);
+		};
 
-	ws.onclose = () => {
-		mediaRecorder.stop();
-		console.log("Recording websocket closed");
+		socket.onmessage = (e) => {
+			const blob = e.data as Blob;
+			blob.text().then((text) => {
+				const reply = JSON.parse(text) as PushReply;
+				console.log("Recording filename:", reply.recording);
+				sessionToken = reply.session_token;
+				resolveFilename(reply.recording);
+			});
+		};
 	};
+	connect(undefined);
 
 	const closeWindow = await new Promise<() => void>((resolve) => {
 		if (!openStreamer) {
 			resolve(() => {});
 			return;
 		}
-		ws.onmessage = (e) => {
-			const blob = e.data as Blob;
-			const stream = blob.stream();
-			const reader = stream.getReader();
-			reader.read().then((result) => {
-				const filename = new TextDecoder().decode(result.value!);
-				console.log("Recording filename:", filename);
-				const url = `http://localhost:5174/?recording=${filename}&mode=stream`;
-				const closeWindow = openStreamingInPopup(url);
-				resolve(closeWindow);
-			});
-		};
+		filename.then((filename) => {
+			const url = `http://localhost:5174/?recording=${filename}&mode=stream`;
+			resolve(openStreamingInPopup(url));
+		});
 	});
 
 	return {
 		stop: () => {
-			mediaRecorder.stop();
+			stopped = true;
+			mediaRecorder?.stop();
 			ws.close();
 			closeWindow();
 		},